@@ -37,6 +37,7 @@ if [ "$(whoami)" = "root" ]; then
   exit 1
 fi
 if [ ! -e build/pam_wsl_hello.so ] || \
+  [ ! -e build/wsl_hello_admin ] || \
   [ ! -e build/WindowsHelloBridge.exe ]; then
     echo "No built binary was found. Build first before installing."
     exit 1
@@ -124,6 +125,10 @@ set -x
 sudo cp build/pam_wsl_hello.so "${SECURITY_PATH}/"
 sudo chown root:root "${SECURITY_PATH}/pam_wsl_hello.so"
 sudo chmod 644 "${SECURITY_PATH}/pam_wsl_hello.so"
+ADMIN_PATH="/usr/local/sbin/wsl_hello_admin"
+sudo cp build/wsl_hello_admin "${ADMIN_PATH}"
+sudo chown root:root "${ADMIN_PATH}"
+sudo chmod 755 "${ADMIN_PATH}"
 
 set +x
 echo_stage "Creating pam-config..."
@@ -156,6 +161,9 @@ if [ ! -e "$PAM_CONFIG_FILENAME" ] || prompt_yn "'$PAM_CONFIG_FILENAME' already
   sudo touch /etc/pam_wsl_hello/config
   sudo echo "$AUTHENTICATOR_PATH" | sudo tee "$PAM_CONFIG_FILENAME"
   sudo echo "win_mnt = \"$MNT\"" | sudo tee -a /etc/pam_wsl_hello/config
+  if [ -n "$WSL_DISTRO_NAME" ]; then
+    sudo echo "distro_name = \"$WSL_DISTRO_NAME\"" | sudo tee -a /etc/pam_wsl_hello/config
+  fi
 elif grep -q "WindowsHelloAuthenticator" "$PAM_CONFIG_FILENAME" ; then
   echo "Migrating PAM config to v2.0"
   sudo sed -i "1s;authenticator_path.*;$AUTHENTICATOR_PATH;" "$PAM_CONFIG_FILENAME"
@@ -170,7 +178,27 @@ pushd "$PAM_WSL_HELLO_WINPATH"
 ./WindowsHelloBridge.exe creator "pam_wsl_hello_$USER" || test $? = $KEY_ALREADY_EXIST_ERR
 sudo mkdir -p /etc/pam_wsl_hello/public_keys
 popd
+INSTALLED_KEY_PATH="/etc/pam_wsl_hello/public_keys/pam_wsl_hello_$USER.pem"
+KEY_METADATA_PATH="/etc/pam_wsl_hello/public_keys/pam_wsl_hello_$USER.meta"
+if ! sudo cmp -s "$KEY_PATH" "$INSTALLED_KEY_PATH" || ! sudo test -f "$KEY_METADATA_PATH"; then
+  echo "created = \"$(date -u +%F)\"" | sudo tee "$KEY_METADATA_PATH" > /dev/null
+fi
 sudo cp "$KEY_PATH" /etc/pam_wsl_hello/public_keys/
+ATTESTATION_PATH="$PAM_WSL_HELLO_WINPATH/pam_wsl_hello_$USER.attestation"
+CHAIN_PATH="$PAM_WSL_HELLO_WINPATH/pam_wsl_hello_$USER.chain"
+if [ -f "$ATTESTATION_PATH" ] && [ -f "$CHAIN_PATH" ]; then
+  sudo cp "$ATTESTATION_PATH" "$CHAIN_PATH" /etc/pam_wsl_hello/public_keys/
+fi
+# The PAM module refuses files that anyone but root can modify
+sudo chown -R root:root /etc/pam_wsl_hello
+sudo chmod -R go-w /etc/pam_wsl_hello
+sudo "${ADMIN_PATH}" verify-attestation "$USER"
+if [ -n "$WSL_DISTRO_NAME" ]; then
+  # The bridge prompts only for requests that a registered distro has signed
+  sudo "${ADMIN_PATH}" host-key | (cd "$PAM_WSL_HELLO_WINPATH" && ./WindowsHelloBridge.exe register-host "$WSL_DISTRO_NAME")
+else
+  echo "WSL_DISTRO_NAME is not set, so this distro is not registered with the bridge, which refuses its requests until it is."
+fi
 
 set +x
 echo_stage "Creating uninstall.sh..."
@@ -178,8 +206,12 @@ if [ ! -e "uninstall.sh" ] || prompt_yn "'uninstall.sh' already exists. Overwrit
   cat > uninstall.sh << EOS
   echo -e "\e[31mNote: Please ensure that config files in /etc/pam.d/ are restored to as they were before WSL-Hello-sudo was installed\e[m"
   set -x
+  # Other distros may share the credential, so deleting it is left to the user to confirm. It is
+  # deleted first, since the name of a rotated one is only kept in /etc/pam_wsl_hello.
+  sudo "${ADMIN_PATH}" delete-credential "$USER" || echo "Keeping the Windows Hello credential of '$USER'"
   sudo rm -rf /etc/pam_wsl_hello
   sudo rm "${SECURITY_PATH}/pam_wsl_hello.so"
+  sudo rm "${ADMIN_PATH}"
   if [ -e "${PAM_CONFIG}" ]; then
     sudo pam-auth-update --remove "${PAM_CONFIG_NAME}"
     sudo rm "${PAM_CONFIG}"
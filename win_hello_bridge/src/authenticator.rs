@@ -1,21 +1,50 @@
 use crate::FailureReason;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::time::Duration;
 use windows::{
-    core::Handle,
+    core::{Handle, RuntimeType},
+    Foundation::{AsyncStatus, IAsyncOperation},
     Security::{Credentials::KeyCredentialManager, Cryptography::CryptographicBuffer},
+    Storage::Streams::IBuffer,
     Win32::{
-        Foundation::PWSTR,
-        UI::WindowsAndMessaging::{FindWindowW, SetForegroundWindow},
+        Foundation::{BOOL, HWND, LPARAM, PWSTR, WPARAM},
+        System::Console::SetConsoleCtrlHandler,
+        UI::WindowsAndMessaging::{
+            FindWindowW, MessageBoxW, PostMessageW, SetForegroundWindow, IDOK, MB_ICONINFORMATION,
+            MB_OKCANCEL, MB_SETFOREGROUND, MB_TOPMOST, WM_CLOSE,
+        },
     },
     UI::Popups::MessageDialog,
 };
 
-pub(crate) fn verify_user(key_name: &str, data_to_sign: &[u8]) -> Result<Vec<u8>, FailureReason> {
+/// The title of the dialog that shows the message of the caller before Windows Hello
+const CONFIRMATION_TITLE: &str = "WSL Hello sudo";
+
+/// Set once the caller is gone, which cancels the pending Windows Hello request
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// The result of a successful Windows Hello signing
+pub(crate) struct HelloSignature {
+    pub(crate) signature: IBuffer,
+    /// The public key of the credential that made `signature`, so that the PAM module can tell
+    /// a re-created credential from a plain verification failure
+    pub(crate) public_key: IBuffer,
+}
+
+pub(crate) fn verify_user(
+    key_name: &str,
+    data_to_sign: &[u8],
+    interactive: bool,
+    message: Option<&str>,
+) -> Result<HelloSignature, FailureReason> {
     if !KeyCredentialManager::IsSupportedAsync()?.get()? {
-        let _ = MessageDialog::Create("Windows Hello not supported")?
-            .ShowAsync()?
-            .get();
+        if interactive {
+            let _ = MessageDialog::Create("Windows Hello not supported")?
+                .ShowAsync()?
+                .get();
+        }
 
         return Err(FailureReason::WindowsHelloNotSupported);
     }
@@ -28,19 +57,111 @@ pub(crate) fn verify_user(key_name: &str, data_to_sign: &[u8]) -> Result<Vec<u8>
 
     let data = CryptographicBuffer::CreateFromByteArray(data_to_sign)?;
 
+    // Windows Hello cannot show a message of its own, so the user is asked before it
+    if let Some(message) = message {
+        confirm(message)?;
+    }
+
     let hello_focus = focus_hello_window();
 
-    let result = key.RequestSignAsync(&data)?.get()?;
+    let result = wait_cancellably(key.RequestSignAsync(&data)?)?;
 
     drop(hello_focus);
 
     FailureReason::from_credential_status(result.Status()?, key_name)?;
 
-    let buffer = result.Result()?;
-    let mut out = windows::core::Array::<u8>::with_len(buffer.Length().unwrap() as usize);
-    CryptographicBuffer::CopyToByteArray(buffer, &mut out)?;
+    Ok(HelloSignature {
+        signature: result.Result()?,
+        public_key: key.RetrievePublicKeyWithDefaultBlobType()?,
+    })
+}
+
+/// Returns the certificate issued for `key_name` by an enterprise CA, which is installed as
+/// `<key_name>.crt` next to the bridge executable
+pub(crate) fn find_certificate(key_name: &str) -> Option<Vec<u8>> {
+    let exe = std::env::current_exe().ok()?;
+    std::fs::read(exe.with_file_name(format!("{}.crt", key_name))).ok()
+}
+
+/// Shows `message` and asks whether to go on with Windows Hello
+fn confirm(message: &str) -> Result<(), FailureReason> {
+    let dialog_closer = close_confirmation_on_cancel();
+    let answer = unsafe {
+        MessageBoxW(
+            HWND::default(),
+            format!("{}\n\nContinue with Windows Hello?", message),
+            CONFIRMATION_TITLE,
+            MB_OKCANCEL | MB_ICONINFORMATION | MB_SETFOREGROUND | MB_TOPMOST,
+        )
+    };
+    drop(dialog_closer);
+
+    if CANCELLED.load(Ordering::SeqCst) {
+        Err(FailureReason::Aborted)
+    } else if answer == IDOK {
+        Ok(())
+    } else {
+        Err(FailureReason::UserCancelled)
+    }
+}
+
+/// Closes the confirmation dialog once the caller is gone, like cancelling Windows Hello does
+fn close_confirmation_on_cancel() -> mpsc::SyncSender<()> {
+    let (send_shutdown, wait_for_shutdown) = mpsc::sync_channel(0);
+
+    std::thread::spawn(move || loop {
+        match wait_for_shutdown.recv_timeout(Duration::from_millis(50)) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) | Ok(()) => return,
+        }
+        if CANCELLED.load(Ordering::SeqCst) {
+            unsafe {
+                let hwnd = FindWindowW(PWSTR(core::ptr::null_mut()), CONFIRMATION_TITLE);
+                PostMessageW(hwnd, WM_CLOSE, WPARAM(0), LPARAM(0));
+            }
+            return;
+        }
+    });
+
+    send_shutdown
+}
+
+/// Cancels Windows Hello on Ctrl-C, Ctrl-Break and when the console closes, which is how WSL
+/// passes on the termination of the bridge
+pub(crate) fn cancel_on_termination() {
+    unsafe { SetConsoleCtrlHandler(Some(on_console_event), true) };
+}
 
-    Ok(out.to_vec())
+unsafe extern "system" fn on_console_event(_ctrl_type: u32) -> BOOL {
+    CANCELLED.store(true, Ordering::SeqCst);
+    // The main thread exits once the request is cancelled
+    true.into()
+}
+
+/// Cancels Windows Hello once `input` reaches its end, i.e. once the caller closes it
+pub(crate) fn cancel_on_eof(mut input: impl Read + Send + 'static) {
+    std::thread::spawn(move || {
+        let mut buffer = [0; 256];
+        while let Ok(1..) = input.read(&mut buffer) {}
+        CANCELLED.store(true, Ordering::SeqCst);
+    });
+}
+
+/// Waits for `operation` like `get` does, but cancels it once the caller is gone
+fn wait_cancellably<T: RuntimeType + 'static>(
+    operation: IAsyncOperation<T>,
+) -> Result<T, FailureReason> {
+    loop {
+        match operation.Status()? {
+            AsyncStatus::Started => {}
+            AsyncStatus::Canceled => return Err(FailureReason::Aborted),
+            _ => return Ok(operation.GetResults()?),
+        }
+        if CANCELLED.load(Ordering::SeqCst) {
+            operation.Cancel()?;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
 }
 
 fn focus_hello_window() -> mpsc::SyncSender<()> {
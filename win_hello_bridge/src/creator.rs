@@ -1,11 +1,26 @@
 use crate::FailureReason;
 use windows::Security::{
-    Credentials::{KeyCredentialCreationOption, KeyCredentialManager},
+    Credentials::{
+        KeyCredential, KeyCredentialAttestationStatus, KeyCredentialCreationOption,
+        KeyCredentialManager,
+    },
     Cryptography::CryptographicBuffer,
 };
+use windows::Storage::Streams::IBuffer;
 
-pub(crate) fn create_public_key(key_name: &str) -> Result<String, FailureReason> {
-    let public_key = {
+pub(crate) struct CreatedKey {
+    pub(crate) pem: String,
+    /// The TPM attestation of the key, if the device supports it
+    pub(crate) attestation: Option<KeyAttestation>,
+}
+
+pub(crate) struct KeyAttestation {
+    pub(crate) statement: Vec<u8>,
+    pub(crate) certificate_chain: Vec<u8>,
+}
+
+pub(crate) fn create_public_key(key_name: &str) -> Result<CreatedKey, FailureReason> {
+    let credential = {
         let result = KeyCredentialManager::RequestCreateAsync(
             key_name,
             KeyCredentialCreationOption::FailIfExists,
@@ -13,22 +28,45 @@ pub(crate) fn create_public_key(key_name: &str) -> Result<String, FailureReason>
         .get()?;
 
         match FailureReason::from_credential_status(result.Status()?, key_name) {
-            Ok(()) => result
-                .Credential()?
-                .RetrievePublicKeyWithDefaultBlobType()?,
+            Ok(()) => result.Credential()?,
             Err(FailureReason::CredentialExists) => {
                 let result = KeyCredentialManager::OpenAsync(key_name)?.get()?;
                 FailureReason::from_credential_status(result.Status()?, key_name)?;
-                result
-                    .Credential()?
-                    .RetrievePublicKeyWithDefaultBlobType()?
+                result.Credential()?
             }
             Err(e) => return Err(e),
         }
     };
 
-    Ok(format!(
+    let pem = format!(
         "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
-        CryptographicBuffer::EncodeToBase64String(public_key)?
-    ))
+        CryptographicBuffer::EncodeToBase64String(
+            credential.RetrievePublicKeyWithDefaultBlobType()?
+        )?
+    );
+
+    Ok(CreatedKey {
+        pem,
+        attestation: retrieve_attestation(&credential)?,
+    })
+}
+
+fn retrieve_attestation(
+    credential: &KeyCredential,
+) -> Result<Option<KeyAttestation>, FailureReason> {
+    let result = credential.GetAttestationAsync()?.get()?;
+    if result.Status()? != KeyCredentialAttestationStatus::Success {
+        return Ok(None);
+    }
+
+    Ok(Some(KeyAttestation {
+        statement: to_bytes(result.AttestationBuffer()?)?,
+        certificate_chain: to_bytes(result.CertificateChainBuffer()?)?,
+    }))
+}
+
+pub(crate) fn to_bytes(buffer: IBuffer) -> Result<Vec<u8>, FailureReason> {
+    let mut out = windows::core::Array::<u8>::with_len(buffer.Length()? as usize);
+    CryptographicBuffer::CopyToByteArray(buffer, &mut out)?;
+    Ok(out.to_vec())
 }
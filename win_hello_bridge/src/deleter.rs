@@ -0,0 +1,30 @@
+use crate::FailureReason;
+use windows::Security::Credentials::KeyCredentialManager;
+
+/// Deletes the credential `key_name` from Windows Hello, after asking on the console unless
+/// `confirmed`
+pub(crate) fn delete_key(key_name: &str, confirmed: bool) -> Result<(), FailureReason> {
+    // DeleteAsync fails alike for every reason, so a missing credential is told apart first
+    let result = KeyCredentialManager::OpenAsync(key_name)?.get()?;
+    FailureReason::from_credential_status(result.Status()?, key_name)?;
+
+    if !confirmed && !confirm_deletion(key_name) {
+        return Err(FailureReason::UserCancelled);
+    }
+
+    KeyCredentialManager::DeleteAsync(key_name)?.get()?;
+    Ok(())
+}
+
+fn confirm_deletion(key_name: &str) -> bool {
+    use std::io::Write;
+
+    print!(
+        "Delete the Windows Hello credential '{}'? It cannot be restored. [y/N]: ",
+        key_name
+    );
+    std::io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
@@ -10,6 +10,12 @@ pub(crate) enum FailureReason {
     Unknown,
     UserCancelled,
     PrefsPasswd,
+    /// The caller closed stdin or terminated the bridge while Windows Hello was pending
+    Aborted,
+    /// The data to sign is not a challenge envelope, or the key may not sign it
+    RejectedChallenge(String),
+    /// The distro or its host key is invalid, or the key cannot be written
+    HostRegistrationFailed(String),
     Other(windows::core::Error),
 }
 
@@ -30,6 +36,9 @@ impl FailureReason {
             FailureReason::UserCancelled => 176,
             FailureReason::PrefsPasswd => 177,
             FailureReason::Other(_) => 178,
+            FailureReason::Aborted => 179,
+            FailureReason::RejectedChallenge(_) => 180,
+            FailureReason::HostRegistrationFailed(_) => 181,
         }
     }
 
@@ -64,6 +73,13 @@ impl Display for FailureReason {
             FailureReason::Unknown => "Unknown error.",
             FailureReason::UserCancelled => "The user cancelled.",
             FailureReason::PrefsPasswd => "The user prefers to enter a password. Aborted.",
+            FailureReason::Aborted => "The request was cancelled by the caller.",
+            FailureReason::RejectedChallenge(reason) => {
+                return write!(f, "The request is rejected; {}.", reason)
+            }
+            FailureReason::HostRegistrationFailed(reason) => {
+                return write!(f, "The host cannot be registered; {}.", reason)
+            }
             FailureReason::Other(e) => return Display::fmt(e, f),
         })
     }
@@ -0,0 +1,150 @@
+use crate::{authenticator, creator, FailureReason};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use windows::Security::{
+    Credentials::{
+        KeyCredential, KeyCredentialCreationOption, KeyCredentialManager, KeyCredentialStatus,
+    },
+    Cryptography::{
+        Core::{
+            AsymmetricAlgorithmNames, AsymmetricKeyAlgorithmProvider, CryptographicEngine,
+            CryptographicPublicKeyBlobType,
+        },
+        CryptographicBuffer,
+    },
+};
+use wsl_hello_protocol::{Envelope, HostError, HostKeys, SignatureVerifier};
+
+/// The directory next to this executable that holds the list of registered distros
+const HOSTS_DIR: &str = "hosts";
+
+/// The list of registered distros with their public keys, in the format of `HostKeys::to_list`
+const HOST_LIST: &str = "hosts.txt";
+
+/// The signature of `HOST_LIST` by the `HOST_LIST_KEY_NAME` credential
+const HOST_LIST_SIGNATURE: &str = "hosts.sig";
+
+/// The Windows Hello credential that signs the list of registered distros. Only the user can have
+/// it sign, so programs that can write the `hosts` directory cannot register a distro, and
+/// removing the list or the credential leaves the bridge refusing every request.
+const HOST_LIST_KEY_NAME: &str = "wsl_hello_hosts";
+
+/// Verifies the signatures of hosts with the cryptography of Windows
+struct CngVerifier;
+
+impl SignatureVerifier for CngVerifier {
+    fn verify(&self, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+        let verify = || -> windows::core::Result<bool> {
+            let provider = AsymmetricKeyAlgorithmProvider::OpenAlgorithm(
+                AsymmetricAlgorithmNames::RsaSignPkcs1Sha256()?,
+            )?;
+            let key = provider.ImportPublicKeyWithBlobType(
+                CryptographicBuffer::CreateFromByteArray(public_key)?,
+                CryptographicPublicKeyBlobType::X509SubjectPublicKeyInfo,
+            )?;
+            CryptographicEngine::VerifySignature(
+                key,
+                CryptographicBuffer::CreateFromByteArray(data)?,
+                CryptographicBuffer::CreateFromByteArray(signature)?,
+            )
+        };
+        verify().unwrap_or(false)
+    }
+}
+
+fn hosts_dir() -> std::io::Result<PathBuf> {
+    Ok(std::env::current_exe()?.with_file_name(HOSTS_DIR))
+}
+
+/// Opens the credential that signs the list of registered hosts, if it exists
+fn open_list_key() -> Result<Option<KeyCredential>, FailureReason> {
+    let result = KeyCredentialManager::OpenAsync(HOST_LIST_KEY_NAME)?.get()?;
+    if result.Status()? == KeyCredentialStatus::NotFound {
+        return Ok(None);
+    }
+    FailureReason::from_credential_status(result.Status()?, HOST_LIST_KEY_NAME)?;
+    Ok(Some(result.Credential()?))
+}
+
+/// Loads the registered hosts, failing unless the list is signed by `list_key`. There are none if
+/// the list does not exist.
+fn load_hosts(list_key: &KeyCredential) -> Result<HostKeys, String> {
+    let dir = hosts_dir().map_err(|e| e.to_string())?;
+    let read = |name: &str| match std::fs::read(dir.join(name)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("cannot read '{}'; {}", dir.join(name).display(), e)),
+    };
+    let (list, signature) = match (read(HOST_LIST)?, read(HOST_LIST_SIGNATURE)?) {
+        (Some(list), Some(signature)) => (list, signature),
+        (None, None) => return Ok(HostKeys::new()),
+        _ => return Err(HostError::UntrustedList.to_string()),
+    };
+    let public_key = list_key
+        .RetrievePublicKeyWithDefaultBlobType()
+        .map_err(|e| e.to_string())
+        .and_then(|key| creator::to_bytes(key).map_err(|e| e.to_string()))?;
+    HostKeys::from_signed_list(&list, &signature, &public_key, &CngVerifier)
+        .map_err(|e| e.to_string())
+}
+
+/// Rejects the request of `envelope`, parsed from `data`, unless a registered host signed it.
+/// Every request is rejected until a host is registered.
+pub(crate) fn verify_request(
+    envelope: &Envelope,
+    data: &[u8],
+    host_signature: Option<&str>,
+) -> Result<(), FailureReason> {
+    let hosts = match open_list_key()? {
+        Some(list_key) => load_hosts(&list_key).map_err(FailureReason::RejectedChallenge)?,
+        None => HostKeys::new(),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    hosts
+        .verify(envelope, data, host_signature, now, &CngVerifier)
+        .map_err(|e| FailureReason::RejectedChallenge(e.to_string()))
+}
+
+/// Registers `pem` as the public key of the distro `host` once the user approves it with Windows
+/// Hello, which signs the new list of hosts, and returns the path of the written list
+pub(crate) fn register(host: &str, pem: &str) -> Result<PathBuf, FailureReason> {
+    let failed = FailureReason::HostRegistrationFailed;
+    let mut hosts = match open_list_key()? {
+        Some(list_key) => load_hosts(&list_key).map_err(failed)?,
+        None => {
+            let result = KeyCredentialManager::RequestCreateAsync(
+                HOST_LIST_KEY_NAME,
+                KeyCredentialCreationOption::FailIfExists,
+            )?
+            .get()?;
+            FailureReason::from_credential_status(result.Status()?, HOST_LIST_KEY_NAME)?;
+            HostKeys::new()
+        }
+    };
+    hosts
+        .insert_pem(host, pem)
+        .map_err(|e| failed(e.to_string()))?;
+    let list = hosts.to_list();
+
+    let message = format!(
+        "Allow the WSL distribution '{}' to ask for Windows Hello for WSL Hello sudo?",
+        host
+    );
+    let signature =
+        authenticator::verify_user(HOST_LIST_KEY_NAME, list.as_bytes(), true, Some(&message))?
+            .signature;
+    let signature = creator::to_bytes(signature)?;
+
+    let io_failed = |e: std::io::Error| failed(e.to_string());
+    let dir = hosts_dir().map_err(io_failed)?;
+    std::fs::create_dir_all(&dir).map_err(io_failed)?;
+    let path = dir.join(HOST_LIST);
+    // The signature is written first, so that an interrupted registration leaves a list that is
+    // rejected rather than one without the new host
+    std::fs::write(dir.join(HOST_LIST_SIGNATURE), signature).map_err(io_failed)?;
+    std::fs::write(&path, list).map_err(io_failed)?;
+    Ok(path)
+}
@@ -2,31 +2,57 @@
 
 mod authenticator;
 mod creator;
+mod deleter;
+mod host;
 
 mod error;
 pub(crate) use error::FailureReason;
 
+use windows::Security::Cryptography::CryptographicBuffer;
+use wsl_hello_protocol::Envelope;
+
 const AUTHENTICATOR: &str = "authenticator";
 const CREATOR: &str = "creator";
-const MODES: [&str; 2] = [AUTHENTICATOR, CREATOR];
+const SIGN_RAW: &str = "sign-raw";
+const REGISTER_HOST: &str = "register-host";
+const DELETE: &str = "delete";
+const MODES: [&str; 5] = [AUTHENTICATOR, CREATOR, SIGN_RAW, REGISTER_HOST, DELETE];
+/// Only keys named with this prefix sign arbitrary data, and they sign nothing else, so that a
+/// key of the PAM module never signs data that means something to another program
+const RAW_KEY_PREFIX: &str = "raw_";
+const CANCEL_ON_EOF: &str = "--cancel-on-eof";
+const NON_INTERACTIVE: &str = "--non-interactive";
+const YES: &str = "--yes";
 
 fn main() {
-    use std::io::{Read, Write};
+    use std::io::Write;
 
-    let mut args = std::env::args();
+    let options: Vec<String> = std::env::args()
+        .filter(|arg| arg.starts_with("--"))
+        .collect();
+    let mut args = std::env::args().filter(|arg| !arg.starts_with("--"));
 
-    if args.len() < 2 || std::env::args().any(|arg| arg == "-h" || arg == "/?") {
+    if std::env::args().len() < 2 || std::env::args().any(|arg| arg == "-h" || arg == "/?") {
         display_help();
         std::process::exit(1)
     }
 
-    let mode = args.nth(1).unwrap();
+    let mode = match args.nth(1) {
+        Some(mode) => mode,
+        None => {
+            display_help();
+            std::process::exit(1)
+        }
+    };
 
     if !MODES.contains(&mode.as_str()) {
         display_help();
         std::process::exit(1);
     }
 
+    let cancel_on_eof = options.iter().any(|option| option == CANCEL_ON_EOF);
+    let interactive = !options.iter().any(|option| option == NON_INTERACTIVE);
+    let confirmed = options.iter().any(|option| option == YES);
     let mut prompt_to_exit = false;
     let key_name = match args.next() {
         Some(name) => name,
@@ -45,28 +71,94 @@ fn main() {
     let result = || -> Result<(), FailureReason> {
         match mode.as_str() {
             AUTHENTICATOR => {
-                let data = {
-                    let mut stdin = std::io::stdin();
-                    let mut buffer = Vec::new();
-                    stdin.read_to_end(&mut buffer).unwrap();
-                    buffer
+                if key_name.starts_with(RAW_KEY_PREFIX) {
+                    return Err(FailureReason::RejectedChallenge(format!(
+                        "'{}' keys only sign in the {} mode",
+                        RAW_KEY_PREFIX, SIGN_RAW
+                    )));
+                }
+                authenticator::cancel_on_termination();
+                let request = read_request(cancel_on_eof)?;
+                let envelope = Envelope::parse(&request.data)
+                    .map_err(|err| FailureReason::RejectedChallenge(err.to_string()))?;
+                host::verify_request(&envelope, &request.data, request.host_signature.as_deref())?;
+                // The user is shown what is signed, and nothing else
+                let message = match request.message {
+                    Some(message) if message != envelope.describe() => {
+                        return Err(FailureReason::RejectedChallenge(
+                            "the message does not describe the challenge envelope".to_owned(),
+                        ))
+                    }
+                    message => message,
                 };
-                let signature = authenticator::verify_user(key_name, &data)?;
-                let mut stdout = std::io::stdout();
-                stdout.write_all(&signature).unwrap();
-                Ok(())
+                let signed = authenticator::verify_user(
+                    key_name,
+                    &request.data,
+                    interactive,
+                    message.as_deref(),
+                )?;
+                print_signature(key_name, signed)
+            }
+            SIGN_RAW => {
+                if !key_name.starts_with(RAW_KEY_PREFIX) {
+                    return Err(FailureReason::RejectedChallenge(format!(
+                        "only '{}' keys sign arbitrary data",
+                        RAW_KEY_PREFIX
+                    )));
+                }
+                authenticator::cancel_on_termination();
+                let request = read_request(cancel_on_eof)?;
+                let signed =
+                    authenticator::verify_user(key_name, &request.data, interactive, None)?;
+                print_signature(key_name, signed)
             }
             CREATOR => {
-                let pem_key = creator::create_public_key(key_name)?;
+                let created = creator::create_public_key(key_name)?;
                 let file_name = format!("./{}.pem", key_name);
                 println!("file name: {}", file_name);
-                std::fs::write(&file_name, &pem_key).unwrap();
+                std::fs::write(&file_name, &created.pem).unwrap();
+                match created.attestation {
+                    Some(attestation) => {
+                        std::fs::write(
+                            format!("./{}.attestation", key_name),
+                            &attestation.statement,
+                        )
+                        .unwrap();
+                        std::fs::write(
+                            format!("./{}.chain", key_name),
+                            &attestation.certificate_chain,
+                        )
+                        .unwrap();
+                        println!("The TPM attestation of the key is written next to it");
+                    }
+                    None => {
+                        println!("This device cannot attest the key, no attestation is written")
+                    }
+                }
                 println!(
                     "Done. The public credential key is written in '{}'",
                     file_name
                 );
                 Ok(())
             }
+            DELETE => {
+                deleter::delete_key(key_name, confirmed)?;
+                println!("Done. The credential '{}' is deleted", key_name);
+                Ok(())
+            }
+            REGISTER_HOST => {
+                use std::io::Read;
+
+                let mut pem = String::new();
+                std::io::stdin().read_to_string(&mut pem).unwrap();
+                let path = host::register(key_name, &pem)?;
+                println!(
+                    "Done. The host key of '{}' is registered in '{}'",
+                    key_name,
+                    path.display()
+                );
+                Ok(())
+            }
             _ => {
                 display_help();
                 std::process::exit(1)
@@ -87,20 +179,152 @@ fn main() {
     }
 }
 
+/// The input of the signing modes
+struct Request {
+    data: Vec<u8>,
+    /// Shown to the user, who is asked to continue
+    message: Option<String>,
+    /// The base64 signature of `data` by the host key of the distro that sends it
+    host_signature: Option<String>,
+}
+
+/// Reads the data to sign from stdin. With `cancel_on_eof`, it is the first line, followed by
+/// 'name: value' lines up to an empty line, and the rest of stdin is watched for its end.
+fn read_request(cancel_on_eof: bool) -> Result<Request, FailureReason> {
+    use std::io::{BufRead, Read};
+
+    let stdin = std::io::stdin();
+    let mut request = Request {
+        data: Vec::new(),
+        message: None,
+        host_signature: None,
+    };
+    if !cancel_on_eof {
+        stdin.lock().read_to_end(&mut request.data).unwrap();
+        return Ok(request);
+    }
+
+    let mut input = stdin.lock();
+    input.read_until(b'\n', &mut request.data).unwrap();
+    if request.data.pop() != Some(b'\n') {
+        return Err(FailureReason::Aborted);
+    }
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).unwrap_or(0) == 0 {
+            return Err(FailureReason::Aborted);
+        }
+        match line.trim_end_matches(&['\r', '\n'][..]) {
+            "" => break,
+            line => {
+                if let Some(message) = line.strip_prefix("message: ") {
+                    request.message = Some(message.to_owned());
+                } else if let Some(signature) = line.strip_prefix("host_signature: ") {
+                    request.host_signature = Some(signature.to_owned());
+                }
+            }
+        }
+    }
+    drop(input);
+    authenticator::cancel_on_eof(stdin);
+    Ok(request)
+}
+
+fn print_signature(
+    key_name: &str,
+    signed: authenticator::HelloSignature,
+) -> Result<(), FailureReason> {
+    print!(
+        "signature: {}\npublic_key: {}\n",
+        CryptographicBuffer::EncodeToBase64String(signed.signature)?,
+        CryptographicBuffer::EncodeToBase64String(signed.public_key)?
+    );
+    if let Some(certificate) = authenticator::find_certificate(key_name) {
+        let certificate = CryptographicBuffer::CreateFromByteArray(&certificate)?;
+        println!(
+            "certificate: {}",
+            CryptographicBuffer::EncodeToBase64String(certificate)?
+        );
+    }
+    Ok(())
+}
+
 fn display_help() {
-    println!("usage: {}.exe <mode> key_name", env!("CARGO_BIN_NAME"));
+    println!(
+        "usage: {}.exe <mode> key_name [{}] [{}] [{}]",
+        env!("CARGO_BIN_NAME"),
+        CANCEL_ON_EOF,
+        NON_INTERACTIVE,
+        YES
+    );
     println!();
 
     println!("mode: ");
     print!("    authenticator: Authenticates the user with Windows Hello, ");
-    println!("and outputs a signature of the input from stdin to stdout.");
+    println!("and outputs a signature of the challenge envelope from stdin to stdout.");
+    println!("        Input that is not a 'wsl-hello-challenge/1 ...' envelope is rejected.");
     println!(
         "        The input will be signed by a private key that is associated with 'key_name'."
     );
+    println!(
+        "        The output consists of 'signature: <base64>' and 'public_key: <base64>' lines."
+    );
+    println!("        If 'key_name.crt' exists next to this executable, the certificate is output");
+    println!("        in a 'certificate: <base64>' line as well.");
     println!("        If key_name is not given, the prompt to ask the name will be shown.");
+    println!(
+        "        With '{}', only the first line of the input is signed, and Windows Hello is",
+        CANCEL_ON_EOF
+    );
+    println!("        cancelled once stdin is closed. Ctrl-C and Ctrl-Break always cancel it.");
+    println!("        The first line may be followed by 'name: value' lines up to an empty line.");
+    println!("        A 'message: <text>' line is shown to the user, who is asked to continue.");
+    println!("        It must be the description of the envelope, e.g. 'sudo in Ubuntu as alice on pts/3'.");
+    println!(
+        "        The envelope must name a distro registered with '{}' and come with a",
+        REGISTER_HOST
+    );
+    println!(
+        "        'host_signature: <base64>' line made by its key. No request is signed until then."
+    );
+    println!(
+        "        With '{}', an unsupported device is reported by the exit code only, without a dialog.",
+        NON_INTERACTIVE
+    );
 
     println!();
 
     println!("    creator: Creates a KeyCredential with Windows Hello, and saves it to a file named 'key_name.pem'.");
+    println!("        If the device supports key attestation, the attestation statement and its certificate chain");
+    println!("        are saved to 'key_name.attestation' and 'key_name.chain'.");
+    println!("        If key_name is not given, the prompt to ask the name will be shown.");
+
+    println!();
+
+    println!(
+        "    {}: Like authenticator, but signs any input. Only keys named '{}*' sign in this mode,",
+        SIGN_RAW, RAW_KEY_PREFIX
+    );
+    println!("        and they sign in no other mode.");
+
+    println!();
+
+    println!(
+        "    {}: Registers the public key PEM from stdin as the host key of the distro named by 'key_name',",
+        REGISTER_HOST
+    );
+    println!("        after the user approves it with Windows Hello. It is added to the list in the 'hosts' directory");
+    println!("        next to this executable, which the Windows Hello credential 'wsl_hello_hosts' signs.");
+
+    println!();
+
+    println!(
+        "    {}: Deletes the KeyCredential 'key_name' from Windows Hello after asking to confirm,",
+        DELETE
+    );
+    println!(
+        "        or right away with '{}'. The exit code tells e.g. a missing credential apart.",
+        YES
+    );
     println!("        If key_name is not given, the prompt to ask the name will be shown.")
 }
@@ -0,0 +1,214 @@
+use bindings::*;
+use config::Config;
+use conversation;
+use date::Date;
+use libc::{c_char, c_int, c_void, LOG_NOTICE, LOG_WARNING};
+use std::ptr;
+use syslog::syslog;
+
+/// The name of the module data that carries the expiry of the key that authenticated the user
+/// from `pam_sm_authenticate` to `pam_sm_acct_mgmt`
+const KEY_EXPIRY_DATA: &[u8] = b"pam_wsl_hello_key_expiry\0";
+
+/// Warns about the expiry of the key that authenticated the user, and requires its rotation
+/// if `rotate_before_expiry` is set. Users who did not authenticate with Windows Hello are
+/// left to the other modules.
+#[no_mangle]
+pub fn pam_sm_acct_mgmt(
+    pamh: *mut pam_handle_t,
+    flags: c_int,
+    _: c_int,
+    _: *mut *const c_char,
+) -> c_int {
+    let expires = match remembered_key_expiry(pamh) {
+        Some(expires) => expires,
+        None => return PAM_IGNORE,
+    };
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            syslog(pamh, LOG_WARNING, &format!("config error; {}", err));
+            return PAM_IGNORE;
+        }
+    };
+
+    let notice = match check_expiry(expires, Date::today(), &config) {
+        Some(notice) => notice,
+        None => return PAM_SUCCESS,
+    };
+    show(pamh, flags, notice.style, &notice.message);
+    if notice.style == PAM_TEXT_INFO {
+        syslog(pamh, LOG_NOTICE, &format!("the key expires on {}", expires));
+    }
+    notice.result
+}
+
+/// What `pam_sm_acct_mgmt` tells the user about the expiry of their key, and returns
+#[derive(Debug, PartialEq, Eq)]
+struct ExpiryNotice {
+    result: c_int,
+    style: c_int,
+    message: String,
+}
+
+/// Decides what to do about a key that expires on `expires`, or `None` if it is not about to
+fn check_expiry(expires: Date, today: Date, config: &Config) -> Option<ExpiryNotice> {
+    let days_left = today.days_until(expires);
+    if days_left <= 0 {
+        return Some(ExpiryNotice {
+            result: PAM_ACCT_EXPIRED,
+            style: PAM_ERROR_MSG,
+            message: format!("Your Windows Hello key expired on {}.", expires),
+        });
+    }
+    if days_left > i64::from(config.expiry_warning_days) {
+        return None;
+    }
+
+    let mut message = format!(
+        "Your Windows Hello key expires on {} (in {} day{}).",
+        expires,
+        days_left,
+        if days_left == 1 { "" } else { "s" }
+    );
+    if config.rotate_before_expiry {
+        message.push_str(" You must rotate it now.");
+    }
+    Some(ExpiryNotice {
+        result: match config.rotate_before_expiry {
+            true => PAM_NEW_AUTHTOK_REQD,
+            false => PAM_SUCCESS,
+        },
+        style: PAM_TEXT_INFO,
+        message,
+    })
+}
+
+fn show(pamh: *mut pam_handle_t, flags: c_int, style: c_int, message: &str) {
+    if (flags & PAM_SILENT) == 0 {
+        let _ = conversation::send_message(pamh, style, message);
+    }
+}
+
+/// Records the expiry of the key that has just authenticated the user for `pam_sm_acct_mgmt`
+pub(crate) fn remember_key_expiry(pamh: *mut pam_handle_t, expires: Date) {
+    unsafe extern "C" fn cleanup(_: *mut pam_handle_t, data: *mut c_void, _: c_int) {
+        drop(Box::from_raw(data as *mut Date));
+    }
+    let data = Box::into_raw(Box::new(expires));
+    let err = unsafe {
+        pam_set_data(
+            pamh,
+            KEY_EXPIRY_DATA.as_ptr() as *const c_char,
+            data as *mut c_void,
+            Some(cleanup),
+        )
+    };
+    if err != PAM_SUCCESS {
+        drop(unsafe { Box::from_raw(data) });
+    }
+}
+
+fn remembered_key_expiry(pamh: *mut pam_handle_t) -> Option<Date> {
+    let mut data: *const c_void = ptr::null();
+    let err = unsafe { pam_get_data(pamh, KEY_EXPIRY_DATA.as_ptr() as *const c_char, &mut data) };
+    if err != PAM_SUCCESS || data.is_null() {
+        return None;
+    }
+    Some(unsafe { *(data as *const Date) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(rotate_before_expiry: bool) -> Config {
+        Config::parse(&format!(
+            "authenticator_path = \"/mnt/c/bridge\"\nwin_mnt = \"/mnt/c\"\n\
+             expiry_warning_days = 14\nrotate_before_expiry = {}\n",
+            rotate_before_expiry
+        ))
+        .unwrap()
+    }
+
+    fn check(expires: &str, rotate_before_expiry: bool) -> Option<ExpiryNotice> {
+        let today = Date::parse("2026-06-15").unwrap();
+        check_expiry(
+            Date::parse(expires).unwrap(),
+            today,
+            &config(rotate_before_expiry),
+        )
+    }
+
+    #[test]
+    fn refuses_expired_keys() {
+        for &expires in &["2026-06-15", "2025-01-01"] {
+            for &rotate_before_expiry in &[false, true] {
+                let notice = check(expires, rotate_before_expiry).unwrap();
+                assert_eq!(notice.result, PAM_ACCT_EXPIRED);
+                assert_eq!(notice.style, PAM_ERROR_MSG);
+                assert_eq!(
+                    notice.message,
+                    format!("Your Windows Hello key expired on {}.", expires)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn warns_about_keys_about_to_expire() {
+        let notice = check("2026-06-16", false).unwrap();
+        assert_eq!(
+            notice,
+            ExpiryNotice {
+                result: PAM_SUCCESS,
+                style: PAM_TEXT_INFO,
+                message: "Your Windows Hello key expires on 2026-06-16 (in 1 day).".to_owned(),
+            }
+        );
+        let notice = check("2026-06-29", true).unwrap();
+        assert_eq!(
+            notice,
+            ExpiryNotice {
+                result: PAM_NEW_AUTHTOK_REQD,
+                style: PAM_TEXT_INFO,
+                message: "Your Windows Hello key expires on 2026-06-29 (in 14 days). \
+                          You must rotate it now."
+                    .to_owned(),
+            }
+        );
+    }
+
+    /// The lines of the `<stack>:` stanza of the pam-auth-update profile
+    fn profile_stanza(stack: &str) -> Vec<&'static str> {
+        include_str!("../../pam-config")
+            .lines()
+            .skip_while(|line| *line != format!("{}:", stack))
+            .skip(1)
+            .take_while(|line| line.starts_with(char::is_whitespace))
+            .collect()
+    }
+
+    #[test]
+    fn profile_rotates_the_keys_that_it_asks_to_rotate() {
+        // PAM_NEW_AUTHTOK_REQD makes applications change the authentication token, which rotates
+        // the key only if the module is in the password stack as well
+        for &stack in &["Account", "Password"] {
+            assert!(
+                profile_stanza(stack)
+                    .iter()
+                    .any(|line| line.trim_end().ends_with("pam_wsl_hello.so")),
+                "{}",
+                stack
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_keys_far_from_expiry() {
+        for &rotate_before_expiry in &[false, true] {
+            assert_eq!(check("2026-06-30", rotate_before_expiry), None);
+            assert_eq!(check("2030-01-01", rotate_before_expiry), None);
+        }
+    }
+}
@@ -0,0 +1,509 @@
+use certificate;
+use keys;
+use openssl::error::ErrorStack;
+use openssl::hash::{self, MessageDigest};
+use openssl::pkey::{PKey, Public};
+use openssl::sign::Verifier;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509StoreContext, X509};
+use permissions::{self, PermissionError};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// `PCP_KEY_ATTESTATION_BLOB` of the Microsoft Platform Crypto Provider, 'SDAK' in little endian
+const KEY_ATTESTATION_MAGIC: &[u8] = b"KADS";
+/// `PCP_KEY_BLOB_WIN8`, the key blob in the attestation, 'MPCP' in little endian
+const KEY_BLOB_MAGIC: &[u8] = b"PCPM";
+const TPM_VERSION_20: u32 = 2;
+
+const TPM_GENERATED_VALUE: u32 = 0xff54_4347;
+const TPM_ST_ATTEST_CERTIFY: u16 = 0x8017;
+const TPM_ALG_RSA: u16 = 0x0001;
+const TPM_ALG_SHA1: u16 = 0x0004;
+const TPM_ALG_SHA256: u16 = 0x000b;
+const TPM_ALG_NULL: u16 = 0x0010;
+const TPM_ALG_RSASSA: u16 = 0x0014;
+/// The object attributes of a key that was generated in the TPM and can never leave it
+const FIXED_TPM: u32 = 1 << 1;
+const SENSITIVE_DATA_ORIGIN: u32 = 1 << 5;
+
+/// tcg-kp-AIKCertificate, 2.23.133.8.3, as the contents of its OID
+const AIK_CERTIFICATE_USAGE: &[u8] = &[0x67, 0x81, 0x05, 0x08, 0x03];
+
+/// The TPM attestation of a Windows Hello key, as exported by `WindowsHelloBridge.exe creator`.
+///
+/// The statement is a `PCP_KEY_ATTESTATION_BLOB`: a header of six little-endian `u32`s (magic,
+/// platform, header size and the sizes of the three parts) followed by the `TPMS_ATTEST` that
+/// `TPM2_Certify` made about the key, its `TPMT_SIGNATURE` by the attestation identity key, and
+/// the Platform Crypto Provider blob of the key, which holds its `TPM2B_PUBLIC`.
+pub struct Attestation {
+    pub statement: Vec<u8>,
+    /// The certificate chain of the attestation identity key, leaf first
+    pub certificate_chain: Vec<X509>,
+}
+
+#[derive(Debug)]
+pub enum AttestationError {
+    Io(io::Error),
+    OpenSsl(ErrorStack),
+    /// The statement, the chain or the roots could have been written by someone else than root
+    UntrustedFile(PermissionError),
+    EmptyChain,
+    Untrusted(String),
+    NotAnAik,
+    Malformed(&'static str),
+    BadSignature,
+    NotInTpm,
+    KeyMismatch,
+}
+
+impl From<io::Error> for AttestationError {
+    fn from(err: io::Error) -> AttestationError {
+        AttestationError::Io(err)
+    }
+}
+
+impl From<PermissionError> for AttestationError {
+    fn from(err: PermissionError) -> AttestationError {
+        match err {
+            PermissionError::Io(err) => AttestationError::Io(err),
+            err => AttestationError::UntrustedFile(err),
+        }
+    }
+}
+
+impl From<ErrorStack> for AttestationError {
+    fn from(err: ErrorStack) -> AttestationError {
+        AttestationError::OpenSsl(err)
+    }
+}
+
+impl fmt::Display for AttestationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AttestationError::Io(ref err) => write!(f, "{}", err),
+            AttestationError::OpenSsl(ref err) => write!(f, "OpenSSL error; {}", err),
+            AttestationError::UntrustedFile(ref err) => write!(f, "{}", err),
+            AttestationError::EmptyChain => write!(f, "the certificate chain is empty"),
+            AttestationError::Untrusted(ref reason) => write!(
+                f,
+                "the certificate chain does not lead to a trusted TPM root; {}",
+                reason
+            ),
+            AttestationError::NotAnAik => write!(
+                f,
+                "the leaf certificate is not for an attestation identity key"
+            ),
+            AttestationError::Malformed(part) => {
+                write!(f, "the attestation statement is malformed; {}", part)
+            }
+            AttestationError::BadSignature => write!(
+                f,
+                "the attestation statement is not signed by the attestation identity key"
+            ),
+            AttestationError::NotInTpm => {
+                write!(
+                    f,
+                    "the attested key is not generated in and bound to the TPM"
+                )
+            }
+            AttestationError::KeyMismatch => {
+                write!(f, "the attestation statement is not about the enrolled key")
+            }
+        }
+    }
+}
+
+impl Attestation {
+    /// Loads `<key_name>.attestation` and `<key_name>.chain` from the public key directory, which
+    /// must be root-owned like the key
+    pub fn load(key_name: &str) -> Result<Attestation, AttestationError> {
+        let statement =
+            permissions::read_root_owned(&keys::key_file_path(key_name, "attestation"))?;
+        let chain = permissions::read_root_owned(&keys::key_file_path(key_name, "chain"))?;
+        Attestation::from_bytes(statement, &chain)
+    }
+
+    /// `chain` is either a PEM bundle or DER certificates concatenated back to back,
+    /// which is how Windows returns `KeyCredentialAttestationResult.CertificateChainBuffer`
+    pub fn from_bytes(statement: Vec<u8>, chain: &[u8]) -> Result<Attestation, AttestationError> {
+        Ok(Attestation {
+            statement,
+            certificate_chain: certificate::parse_certificates(chain)?,
+        })
+    }
+
+    /// Checks that the certificate chain leads to one of `roots`, that its leaf certifies an
+    /// attestation identity key, that this key signed the statement, and that the statement
+    /// certifies `key` as a key that cannot leave the TPM
+    pub fn verify(&self, key: &PKey<Public>, roots: &[X509]) -> Result<(), AttestationError> {
+        let (leaf, intermediates) = self
+            .certificate_chain
+            .split_first()
+            .ok_or(AttestationError::EmptyChain)?;
+
+        let mut store = X509StoreBuilder::new()?;
+        for root in roots {
+            store.add_cert(root.clone())?;
+        }
+        let store = store.build();
+
+        let mut chain = Stack::new()?;
+        for intermediate in intermediates {
+            chain.push(intermediate.clone())?;
+        }
+
+        let mut context = X509StoreContext::new()?;
+        let failure = context.init(&store, leaf, &chain, |context| {
+            Ok(match context.verify_cert()? {
+                true => None,
+                false => Some(context.error().error_string().to_owned()),
+            })
+        })?;
+        if let Some(reason) = failure {
+            return Err(AttestationError::Untrusted(reason));
+        }
+
+        if !certificate::extended_key_usages(leaf)?
+            .iter()
+            .any(|usage| usage == AIK_CERTIFICATE_USAGE)
+        {
+            return Err(AttestationError::NotAnAik);
+        }
+
+        let statement = KeyAttestation::parse(&self.statement)?;
+        statement.verify_signature(&leaf.public_key()?)?;
+        statement.verify_key(key)
+    }
+}
+
+/// The parts of an attestation statement
+struct KeyAttestation<'a> {
+    /// `TPMS_ATTEST`
+    certify_info: &'a [u8],
+    /// `TPMT_SIGNATURE` of `certify_info`
+    signature: &'a [u8],
+    /// `TPMT_PUBLIC` of the attested key
+    public_area: &'a [u8],
+}
+
+impl<'a> KeyAttestation<'a> {
+    fn parse(statement: &'a [u8]) -> Result<KeyAttestation<'a>, AttestationError> {
+        let header = |index: usize| {
+            statement
+                .get(index * 4..index * 4 + 4)
+                .map(|field| u32::from_le_bytes([field[0], field[1], field[2], field[3]]) as usize)
+                .ok_or(AttestationError::Malformed("the header is truncated"))
+        };
+        if !statement.starts_with(KEY_ATTESTATION_MAGIC) {
+            return Err(AttestationError::Malformed("it is not a key attestation"));
+        }
+        if header(1)? != TPM_VERSION_20 as usize {
+            return Err(AttestationError::Malformed("it is not made by a TPM 2.0"));
+        }
+        let mut body = TpmReader::new(
+            statement
+                .get(header(2)?..)
+                .ok_or(AttestationError::Malformed("the header is truncated"))?,
+        );
+        let certify_info = body.bytes(header(3)?)?;
+        let signature = body.bytes(header(4)?)?;
+        let key_blob = body.bytes(header(5)?)?;
+        body.finish()?;
+
+        // PCP_KEY_BLOB_WIN8 is little endian too, and its TPM2B_PUBLIC follows the header
+        let blob_header = |index: usize| {
+            key_blob
+                .get(index * 4..index * 4 + 4)
+                .map(|field| u32::from_le_bytes([field[0], field[1], field[2], field[3]]) as usize)
+                .ok_or(AttestationError::Malformed("the key blob is truncated"))
+        };
+        if !key_blob.starts_with(KEY_BLOB_MAGIC) {
+            return Err(AttestationError::Malformed("the key blob is unknown"));
+        }
+        let mut public = TpmReader::new(
+            key_blob
+                .get(blob_header(1)?..)
+                .ok_or(AttestationError::Malformed("the key blob is truncated"))?,
+        );
+        let mut public = TpmReader::new(public.bytes(blob_header(4)?)?);
+        let public_area = public.sized()?;
+        public.finish()?;
+
+        Ok(KeyAttestation {
+            certify_info,
+            signature,
+            public_area,
+        })
+    }
+
+    fn verify_signature(&self, aik: &PKey<Public>) -> Result<(), AttestationError> {
+        let mut signature = TpmReader::new(self.signature);
+        if signature.u16()? != TPM_ALG_RSASSA {
+            return Err(AttestationError::Malformed("the signature is not RSASSA"));
+        }
+        let digest = digest(signature.u16()?)?;
+        let value = signature.sized()?;
+        signature.finish()?;
+
+        let mut verifier = Verifier::new(digest, aik)?;
+        verifier.update(self.certify_info)?;
+        if !verifier.verify(value).unwrap_or(false) {
+            return Err(AttestationError::BadSignature);
+        }
+        Ok(())
+    }
+
+    /// Checks that the signed `TPMS_ATTEST` certifies the public area, and that it is `key`
+    fn verify_key(&self, key: &PKey<Public>) -> Result<(), AttestationError> {
+        let mut info = TpmReader::new(self.certify_info);
+        if info.u32()? != TPM_GENERATED_VALUE || info.u16()? != TPM_ST_ATTEST_CERTIFY {
+            return Err(AttestationError::Malformed(
+                "it is not a TPM2_Certify result",
+            ));
+        }
+        info.sized()?; // qualifiedSigner
+        info.sized()?; // extraData
+        info.bytes(17)?; // clockInfo
+        info.bytes(8)?; // firmwareVersion
+        let certified_name = info.sized()?;
+        info.sized()?; // qualifiedName
+        info.finish()?;
+
+        // The name of an object is the algorithm and digest of its public area
+        let mut public = TpmReader::new(self.public_area);
+        if public.u16()? != TPM_ALG_RSA {
+            return Err(AttestationError::KeyMismatch);
+        }
+        let name_algorithm = public.u16()?;
+        let mut name = name_algorithm.to_be_bytes().to_vec();
+        name.extend_from_slice(&hash::hash(digest(name_algorithm)?, self.public_area)?);
+        if certified_name != &name[..] {
+            return Err(AttestationError::KeyMismatch);
+        }
+
+        let attributes = public.u32()?;
+        if attributes & (FIXED_TPM | SENSITIVE_DATA_ORIGIN) != FIXED_TPM | SENSITIVE_DATA_ORIGIN {
+            return Err(AttestationError::NotInTpm);
+        }
+        public.sized()?; // authPolicy
+        if public.u16()? != TPM_ALG_NULL {
+            public.bytes(4)?; // keyBits and mode of the symmetric algorithm
+        }
+        if public.u16()? != TPM_ALG_NULL {
+            public.bytes(2)?; // hash algorithm of the scheme
+        }
+        public.u16()?; // keyBits
+        let exponent = match public.u32()? {
+            0 => 65537,
+            exponent => exponent,
+        };
+        let modulus = public.sized()?;
+        public.finish()?;
+
+        let rsa = key.rsa().map_err(|_| AttestationError::KeyMismatch)?;
+        if modulus != &rsa.n().to_vec()[..] || rsa.e().to_vec() != exponent.to_be_bytes()[1..] {
+            return Err(AttestationError::KeyMismatch);
+        }
+        Ok(())
+    }
+}
+
+fn digest(algorithm: u16) -> Result<MessageDigest, AttestationError> {
+    match algorithm {
+        TPM_ALG_SHA1 => Ok(MessageDigest::sha1()),
+        TPM_ALG_SHA256 => Ok(MessageDigest::sha256()),
+        _ => Err(AttestationError::Malformed(
+            "the hash algorithm is unsupported",
+        )),
+    }
+}
+
+/// Reads the big-endian structures of the TPM
+struct TpmReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> TpmReader<'a> {
+    fn new(data: &'a [u8]) -> TpmReader<'a> {
+        TpmReader { data }
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], AttestationError> {
+        if self.data.len() < length {
+            return Err(AttestationError::Malformed("a structure is truncated"));
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, AttestationError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, AttestationError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a `TPM2B_*`, whose contents follow their `u16` size
+    fn sized(&mut self) -> Result<&'a [u8], AttestationError> {
+        let length = self.u16()?;
+        self.bytes(length as usize)
+    }
+
+    fn finish(self) -> Result<(), AttestationError> {
+        match self.data.is_empty() {
+            true => Ok(()),
+            false => Err(AttestationError::Malformed("a structure has trailing data")),
+        }
+    }
+}
+
+/// Loads the PEM bundle of trusted TPM roots configured as `attestation_roots`, which must be
+/// root-owned
+pub fn load_roots(path: &str) -> Result<Vec<X509>, AttestationError> {
+    Ok(X509::stack_from_pem(&permissions::read_root_owned(
+        Path::new(path),
+    )?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &[u8] = include_bytes!("../tests/fixtures/attestation/root.pem");
+    const OTHER_ROOT: &[u8] = include_bytes!("../tests/fixtures/attestation/other_root.pem");
+    const INTERMEDIATE: &[u8] = include_bytes!("../tests/fixtures/attestation/intermediate.pem");
+    const AIK: &[u8] = include_bytes!("../tests/fixtures/attestation/aik.pem");
+    const NOT_AIK: &[u8] = include_bytes!("../tests/fixtures/attestation/not_aik.pem");
+    const HELLO_KEY: &[u8] = include_bytes!("../tests/fixtures/attestation/hello_key.pem");
+    const HELLO_KEY_ATTESTATION: &[u8] =
+        include_bytes!("../tests/fixtures/attestation/hello_key.attestation");
+    const OTHER_KEY_ATTESTATION: &[u8] =
+        include_bytes!("../tests/fixtures/attestation/other_key.attestation");
+    const FORGED_ATTESTATION: &[u8] =
+        include_bytes!("../tests/fixtures/attestation/forged.attestation");
+    const NOT_AIK_ATTESTATION: &[u8] =
+        include_bytes!("../tests/fixtures/attestation/not_aik.attestation");
+    const IMPORTED_KEY_ATTESTATION: &[u8] =
+        include_bytes!("../tests/fixtures/attestation/imported_key.attestation");
+
+    fn der_chain(leaf: &[u8]) -> Vec<u8> {
+        let mut chain = X509::from_pem(leaf).unwrap().to_der().unwrap();
+        chain.extend(X509::from_pem(INTERMEDIATE).unwrap().to_der().unwrap());
+        chain
+    }
+
+    fn verify(statement: &[u8], chain: &[u8], roots: &[u8]) -> Result<(), AttestationError> {
+        let attestation = Attestation::from_bytes(statement.to_vec(), chain).unwrap();
+        let key = PKey::public_key_from_pem(HELLO_KEY).unwrap();
+        attestation.verify(&key, &X509::stack_from_pem(roots).unwrap())
+    }
+
+    #[test]
+    fn verifies_der_chain_leading_to_root() {
+        let attestation =
+            Attestation::from_bytes(HELLO_KEY_ATTESTATION.to_vec(), &der_chain(AIK)).unwrap();
+        assert_eq!(attestation.certificate_chain.len(), 2);
+        verify(HELLO_KEY_ATTESTATION, &der_chain(AIK), ROOT).unwrap();
+    }
+
+    #[test]
+    fn verifies_pem_chain_leading_to_root() {
+        let mut chain = AIK.to_vec();
+        chain.extend(INTERMEDIATE);
+        verify(HELLO_KEY_ATTESTATION, &chain, ROOT).unwrap();
+    }
+
+    #[test]
+    fn rejects_chain_of_other_root() {
+        match verify(HELLO_KEY_ATTESTATION, &der_chain(AIK), OTHER_ROOT) {
+            Err(AttestationError::Untrusted(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_chain_without_intermediate() {
+        let chain = X509::from_pem(AIK).unwrap().to_der().unwrap();
+        match verify(HELLO_KEY_ATTESTATION, &chain, ROOT) {
+            Err(AttestationError::Untrusted(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_leaf_that_is_no_aik() {
+        match verify(NOT_AIK_ATTESTATION, &der_chain(NOT_AIK), ROOT) {
+            Err(AttestationError::NotAnAik) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_statement_signed_by_other_key() {
+        match verify(FORGED_ATTESTATION, &der_chain(AIK), ROOT) {
+            Err(AttestationError::BadSignature) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_statement_about_other_key() {
+        match verify(OTHER_KEY_ATTESTATION, &der_chain(AIK), ROOT) {
+            Err(AttestationError::KeyMismatch) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_key_that_can_leave_the_tpm() {
+        match verify(IMPORTED_KEY_ATTESTATION, &der_chain(AIK), ROOT) {
+            Err(AttestationError::NotInTpm) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_statements() {
+        // The public area is not signed itself, but its digest is part of the certified name
+        let mut tampered = HELLO_KEY_ATTESTATION.to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        match verify(&tampered, &der_chain(AIK), ROOT) {
+            Err(AttestationError::KeyMismatch) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let truncated = &HELLO_KEY_ATTESTATION[..HELLO_KEY_ATTESTATION.len() - 1];
+        match verify(truncated, &der_chain(AIK), ROOT) {
+            Err(AttestationError::Malformed(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match verify(b"\x00\x01TPMT_PUBLIC", &der_chain(AIK), ROOT) {
+            Err(AttestationError::Malformed(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn loads_roots_only_from_root_owned_files() {
+        // The temporary directory is writable by everyone
+        let path = std::env::temp_dir().join("pam_wsl_hello_attestation_roots_test.pem");
+        std::fs::write(&path, ROOT).unwrap();
+        let result = load_roots(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(AttestationError::UntrustedFile(_)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|roots| roots.len())),
+        }
+        match load_roots("/nonexistent/roots.pem") {
+            Err(AttestationError::Io(_)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|roots| roots.len())),
+        }
+    }
+}
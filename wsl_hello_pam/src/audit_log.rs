@@ -0,0 +1,470 @@
+use auth;
+use authorized_keys::{self, AuthorizedKeysError};
+use keys;
+use libc;
+use openssl::base64;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{PKey, Public};
+use permissions::{self, PermissionError};
+use revocation::{RevocationError, RevocationList};
+use serde_json::{self, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Fingerprints of keys that were enrolled once, whose approvals in the log are verified with the
+/// public key in the record. Rotations add the replaced keys, and revoked keys count as well.
+pub const RETIRED_KEYS_PATH: &str = "/etc/pam_wsl_hello/retired_keys";
+
+/// The `prev_hash` of the first record of a log
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// How much of the end of the log is read to find the hash of the last record, which is far
+/// more than a record takes
+const TAIL_SIZE: u64 = 64 * 1024;
+
+/// A successful authentication with Windows Hello, with everything needed to verify it again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Approval {
+    /// Seconds since the UNIX epoch
+    pub timestamp: u64,
+    pub user: String,
+    pub service: Option<String>,
+    pub tty: Option<String>,
+    pub rhost: Option<String>,
+    pub ruser: Option<String>,
+    pub distro: Option<String>,
+    pub key_name: String,
+    pub key_fingerprint: String,
+    /// DER of the public key that verified the signature
+    pub public_key: Vec<u8>,
+    pub challenge: String,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum AuditLogError {
+    Io(io::Error),
+    OpenSsl(ErrorStack),
+    Untrusted(String),
+    UntrustedRetiredKeys(PermissionError),
+    AuthorizedKeysError(AuthorizedKeysError),
+    RevocationError(RevocationError),
+    Broken { line: usize, reason: String },
+}
+
+impl From<io::Error> for AuditLogError {
+    fn from(err: io::Error) -> AuditLogError {
+        AuditLogError::Io(err)
+    }
+}
+
+impl From<ErrorStack> for AuditLogError {
+    fn from(err: ErrorStack) -> AuditLogError {
+        AuditLogError::OpenSsl(err)
+    }
+}
+
+impl fmt::Display for AuditLogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuditLogError::Io(ref err) => write!(f, "{}", err),
+            AuditLogError::OpenSsl(ref err) => write!(f, "OpenSSL error; {}", err),
+            AuditLogError::Untrusted(ref path) => {
+                write!(f, "{} must be owned by root and private to it", path)
+            }
+            AuditLogError::UntrustedRetiredKeys(ref err) => write!(f, "{}", err),
+            AuditLogError::AuthorizedKeysError(ref err) => write!(f, "{}", err),
+            AuditLogError::RevocationError(ref err) => write!(f, "{}", err),
+            AuditLogError::Broken { line, ref reason } => write!(f, "line {}: {}", line, reason),
+        }
+    }
+}
+
+impl Approval {
+    fn to_json(&self, prev_hash: &str) -> Map<String, Value> {
+        let optional = |value: &Option<String>| match *value {
+            Some(ref value) => Value::from(value.as_str()),
+            None => Value::Null,
+        };
+        let mut record = Map::new();
+        record.insert("timestamp".to_owned(), Value::from(self.timestamp));
+        record.insert("user".to_owned(), Value::from(self.user.as_str()));
+        record.insert("service".to_owned(), optional(&self.service));
+        record.insert("tty".to_owned(), optional(&self.tty));
+        record.insert("rhost".to_owned(), optional(&self.rhost));
+        record.insert("ruser".to_owned(), optional(&self.ruser));
+        record.insert("distro".to_owned(), optional(&self.distro));
+        record.insert("key_name".to_owned(), Value::from(self.key_name.as_str()));
+        record.insert(
+            "key_fingerprint".to_owned(),
+            Value::from(self.key_fingerprint.as_str()),
+        );
+        record.insert(
+            "public_key".to_owned(),
+            Value::from(base64::encode_block(&self.public_key)),
+        );
+        record.insert("challenge".to_owned(), Value::from(self.challenge.as_str()));
+        record.insert(
+            "signature".to_owned(),
+            Value::from(base64::encode_block(&self.signature)),
+        );
+        record.insert("prev_hash".to_owned(), Value::from(prev_hash));
+        record
+    }
+}
+
+/// Returns the hash of a record, which is the hex SHA-256 of its JSON without the `hash` field.
+/// serde_json keeps the fields sorted, so the JSON of a record is always the same.
+fn record_hash(record: &Map<String, Value>) -> Result<String, AuditLogError> {
+    let json = serde_json::to_string(record).map_err(|err| AuditLogError::Broken {
+        line: 0,
+        reason: err.to_string(),
+    })?;
+    let digest = hash(MessageDigest::sha256(), json.as_bytes())?;
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Formats `approval` as a line of the log that follows the record with `prev_hash`, and returns
+/// it with its hash
+fn format_record(approval: &Approval, prev_hash: &str) -> Result<(String, String), AuditLogError> {
+    let mut record = approval.to_json(prev_hash);
+    let hash = record_hash(&record)?;
+    record.insert("hash".to_owned(), Value::from(hash.as_str()));
+    Ok((format!("{}\n", Value::Object(record)), hash))
+}
+
+/// Appends `approval` to the log at `path`, which is created private to root. The log is locked
+/// meanwhile so that concurrent authentications do not fork the chain. Returns the hash of the
+/// appended record, the new head of the chain.
+pub fn append(path: &str, approval: &Approval) -> Result<String, AuditLogError> {
+    if let Some(parent) = Path::new(path).parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)?;
+    let metadata = file.metadata()?;
+    if metadata.uid() != 0 || metadata.mode() & 0o077 != 0 {
+        return Err(AuditLogError::Untrusted(path.to_owned()));
+    }
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(AuditLogError::Io(io::Error::last_os_error()));
+    }
+    let prev_hash = last_hash(&mut file, metadata.len())?;
+    let (line, hash) = format_record(approval, &prev_hash)?;
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+    // Closing the file releases the lock
+    Ok(hash)
+}
+
+fn last_hash(file: &mut File, len: u64) -> Result<String, AuditLogError> {
+    let start = len.saturating_sub(TAIL_SIZE);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    // The tail may start in the middle of a character, but the last line is whole
+    let tail = String::from_utf8_lossy(&tail);
+    let last_line = match tail.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => line,
+        None if start == 0 => return Ok(GENESIS_HASH.to_owned()),
+        None => {
+            return Err(AuditLogError::Broken {
+                line: 0,
+                reason: "the end of the log is blank".to_owned(),
+            })
+        }
+    };
+    serde_json::from_str::<Value>(last_line)
+        .ok()
+        .and_then(|record| record.get("hash")?.as_str().map(str::to_owned))
+        .ok_or_else(|| AuditLogError::Broken {
+            line: 0,
+            reason: "the last record has no hash".to_owned(),
+        })
+}
+
+/// What `verify` found in a log that is intact
+#[derive(Debug)]
+pub struct Verification {
+    pub records: usize,
+    /// Lines whose signature was verified with the key in the record because the key is
+    /// retired, e.g. after a rotation
+    pub retired: Vec<usize>,
+    /// The hash of the last record, which must match the last one that was logged to syslog and
+    /// the audit subsystem, or records were cut off the end
+    pub head: String,
+}
+
+/// Checks the hash chain of a log and verifies every signature again with the enrolled key of
+/// the same fingerprint in `enrolled_keys`. A record of a key that is no longer enrolled is only
+/// verified with the key in it if the fingerprint is in `retired_keys`, since anyone who can
+/// rewrite the log could otherwise sign records with a key of their own.
+pub fn verify(
+    contents: &str,
+    enrolled_keys: &HashMap<String, PKey<Public>>,
+    retired_keys: &HashSet<String>,
+) -> Result<Verification, AuditLogError> {
+    let mut verification = Verification {
+        records: 0,
+        retired: Vec::new(),
+        head: GENESIS_HASH.to_owned(),
+    };
+    let mut prev_hash = GENESIS_HASH.to_owned();
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let broken = |reason: &str| AuditLogError::Broken {
+            line: line_number,
+            reason: reason.to_owned(),
+        };
+        let mut record = match serde_json::from_str::<Value>(line) {
+            Ok(Value::Object(record)) => record,
+            _ => return Err(broken("the record is not a JSON object")),
+        };
+        let hash = match record.remove("hash") {
+            Some(Value::String(hash)) => hash,
+            _ => return Err(broken("the record has no hash")),
+        };
+        if record.get("prev_hash").and_then(Value::as_str) != Some(prev_hash.as_str()) {
+            return Err(broken(
+                "the record does not follow the previous one; records were removed or reordered",
+            ));
+        }
+        if record_hash(&record)? != hash {
+            return Err(broken("the record was modified"));
+        }
+
+        let field = |name: &str| {
+            record
+                .get(name)
+                .and_then(Value::as_str)
+                .ok_or_else(|| broken(&format!("the record has no {}", name)))
+        };
+        let decode = |name: &str| {
+            base64::decode_block(field(name)?)
+                .map_err(|_| broken(&format!("the {} is not base64", name)))
+        };
+        let fingerprint = field("key_fingerprint")?;
+        let key = match enrolled_keys.get(fingerprint) {
+            Some(key) => key.clone(),
+            None if !retired_keys.contains(fingerprint) => {
+                return Err(broken("the key is neither enrolled nor retired"))
+            }
+            None => {
+                let key = PKey::public_key_from_der(&decode("public_key")?)
+                    .map_err(|_| broken("the public key is invalid"))?;
+                if keys::fingerprint(&key)? != fingerprint {
+                    return Err(broken("the public key does not match its fingerprint"));
+                }
+                verification.retired.push(line_number);
+                key
+            }
+        };
+        let verified = auth::verify_signature(&key, field("challenge")?, &decode("signature")?)
+            .unwrap_or(false);
+        if !verified {
+            return Err(broken("the signature does not match the challenge"));
+        }
+
+        verification.records += 1;
+        prev_hash = hash;
+    }
+    verification.head = prev_hash;
+    Ok(verification)
+}
+
+/// Returns the keys in `/etc/pam_wsl_hello/public_keys` and `authorized_keys` by fingerprint
+pub fn enrolled_keys() -> Result<HashMap<String, PKey<Public>>, AuditLogError> {
+    let mut enrolled_keys = HashMap::new();
+    match fs::read_dir(keys::PUBLIC_KEYS_DIR) {
+        Ok(entries) => {
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().and_then(|extension| extension.to_str()) != Some("pem") {
+                    continue;
+                }
+                if let Ok(key) = keys::parse_public_key(&fs::read(&path)?) {
+                    enrolled_keys.insert(keys::fingerprint(&key)?, key);
+                }
+            }
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(AuditLogError::Io(err)),
+    }
+    let authorized_keys = authorized_keys::load().map_err(AuditLogError::AuthorizedKeysError)?;
+    for authorized_key in authorized_keys.unwrap_or_default() {
+        enrolled_keys.insert(keys::fingerprint(&authorized_key.key)?, authorized_key.key);
+    }
+    Ok(enrolled_keys)
+}
+
+/// Returns the fingerprints in `/etc/pam_wsl_hello/retired_keys` and `revoked_keys`, which must be
+/// root-owned like the enrolled keys. Each line of `retired_keys` starts with a fingerprint, and
+/// the rest of it is a comment.
+pub fn retired_keys() -> Result<HashSet<String>, AuditLogError> {
+    let mut retired_keys = match permissions::read_root_owned(Path::new(RETIRED_KEYS_PATH)) {
+        Ok(contents) => parse_retired_keys(&String::from_utf8_lossy(&contents)),
+        Err(PermissionError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => {
+            HashSet::new()
+        }
+        Err(err) => return Err(AuditLogError::UntrustedRetiredKeys(err)),
+    };
+    let revoked_keys = RevocationList::load().map_err(AuditLogError::RevocationError)?;
+    retired_keys.extend(revoked_keys.fingerprints().map(str::to_owned));
+    Ok(retired_keys)
+}
+
+fn parse_retired_keys(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|fingerprint| fingerprint.starts_with("SHA256:"))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Adds the key of `fingerprint` to `/etc/pam_wsl_hello/retired_keys`, so that its approvals are
+/// still verified once it is no longer enrolled
+pub fn retire_key(fingerprint: &str, note: &str) -> Result<(), AuditLogError> {
+    let path = Path::new(RETIRED_KEYS_PATH);
+    if path.exists() {
+        permissions::check_root_owned(path).map_err(AuditLogError::UntrustedRetiredKeys)?;
+    }
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o644)
+        .open(path)?;
+    writeln!(file, "{} {}", fingerprint, note)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+
+    fn approval(key: &PKey<openssl::pkey::Private>, challenge: &str) -> Approval {
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(challenge.as_bytes()).unwrap();
+        let public_key = key.public_key_to_der().unwrap();
+        Approval {
+            timestamp: 1_790_000_000,
+            user: "alice".to_owned(),
+            service: Some("sudo".to_owned()),
+            tty: Some("/dev/pts/3".to_owned()),
+            rhost: None,
+            ruser: None,
+            distro: Some("Ubuntu".to_owned()),
+            key_name: "pam_wsl_hello_alice".to_owned(),
+            key_fingerprint: keys::fingerprint(&PKey::public_key_from_der(&public_key).unwrap())
+                .unwrap(),
+            public_key,
+            challenge: challenge.to_owned(),
+            signature: signer.sign_to_vec().unwrap(),
+        }
+    }
+
+    fn chain(approvals: &[Approval]) -> Vec<String> {
+        let mut prev_hash = GENESIS_HASH.to_owned();
+        approvals
+            .iter()
+            .map(|approval| {
+                let (line, hash) = format_record(approval, &prev_hash).unwrap();
+                let record: Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(record["hash"].as_str(), Some(hash.as_str()));
+                prev_hash = hash;
+                line
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verifies_chained_records() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let lines = chain(&[approval(&key, "first"), approval(&key, "second")]);
+        let public_key = PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap();
+        let mut enrolled_keys = HashMap::new();
+        let fingerprint = keys::fingerprint(&public_key).unwrap();
+        enrolled_keys.insert(fingerprint.clone(), public_key);
+
+        let verification = verify(&lines.concat(), &enrolled_keys, &HashSet::new()).unwrap();
+        assert_eq!(verification.records, 2);
+        assert!(verification.retired.is_empty());
+        let last_record: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(
+            Some(verification.head.as_str()),
+            last_record["hash"].as_str()
+        );
+
+        let verification = verify("", &enrolled_keys, &HashSet::new()).unwrap();
+        assert_eq!(verification.head, GENESIS_HASH);
+
+        let retired_keys =
+            parse_retired_keys(&format!("# rotated\n{} 2026-06-01 alice\n", fingerprint));
+        let verification = verify(&lines.concat(), &HashMap::new(), &retired_keys).unwrap();
+        assert_eq!(verification.retired, vec![1, 2]);
+    }
+
+    #[test]
+    fn refuses_keys_that_are_not_retired() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let lines = chain(&[approval(&key, "first")]);
+        // Anyone who can rewrite the log could sign records with a key of their own
+        match verify(&lines.concat(), &HashMap::new(), &HashSet::new()) {
+            Err(AuditLogError::Broken { line: 1, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let lines = chain(&[
+            approval(&key, "first"),
+            approval(&key, "second"),
+            approval(&key, "third"),
+        ]);
+        let public_key = PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap();
+        let retired_keys = [keys::fingerprint(&public_key).unwrap()]
+            .iter()
+            .cloned()
+            .collect();
+        let expect_broken = |contents: String, expected_line: usize| match verify(
+            &contents,
+            &HashMap::new(),
+            &retired_keys,
+        ) {
+            Err(AuditLogError::Broken { line, .. }) if line == expected_line => {}
+            other => panic!("unexpected result: {:?}", other),
+        };
+
+        expect_broken(format!("{}{}", lines[0], lines[2]), 2);
+        expect_broken(format!("{}{}{}", lines[1], lines[0], lines[2]), 1);
+        expect_broken(
+            format!("{}{}", lines[0], lines[1].replace("alice", "mallory")),
+            2,
+        );
+
+        // A record that is consistently rehashed still needs a valid signature
+        let mut forged = approval(&key, "second");
+        forged.challenge = "forged".to_owned();
+        let forged = chain(&[approval(&key, "first"), forged]);
+        expect_broken(forged.concat(), 2);
+    }
+}
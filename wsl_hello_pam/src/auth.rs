@@ -1,20 +1,48 @@
+use account;
+use attestation::{self, Attestation, AttestationError};
+use audit_log::{self, Approval, AuditLogError};
+use authorized_keys::{self, AuthorizedKeysError, RequestContext, Restriction};
 use bindings::*;
-use libc::{c_char, c_int};
+use certificate::{self, CaPolicy, CertificateError};
+use config::{Config, ConfigError, TrustMode};
+use conversation;
+use date::Date;
+use grace::Session;
+use hooks::{self, HookContext, HookEvent};
+use host_key::{self, HostKeyError};
+use interruption::{self, Interruption};
+use key_command::{self, KeyCommandError, KeyCommandMode};
+use key_metadata::{KeyMetadata, KeyMetadataError};
+use keys;
+use libc::{self, c_char, c_int, c_void, LOG_ERR, LOG_NOTICE, LOG_WARNING};
+use linux_audit::{self, AuthEvent};
+use lockout::{self, FailureRecord, LockoutError};
 use openssl;
 use openssl::hash::MessageDigest;
-use openssl::pkey::PKey;
+use openssl::pkey::{PKey, Public};
 use openssl::sign::Verifier;
+use password_race;
+use permissions::{self, PermissionError};
+use prompt_lock::PromptLock;
+use protocol::{AuthenticatorRequest, AuthenticatorResponse, BridgeFailure};
+use revocation::{RevocationError, RevocationList};
 use std::borrow::Cow;
 use std::ffi::{CStr, CString};
 use std::fmt;
-use std::fs::{self, File, OpenOptions};
-use std::io::{self, prelude::*, SeekFrom};
+use std::fs;
+use std::io::{self, prelude::*};
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{Child, ChildStdin, Command, Stdio};
 use std::ptr;
-use toml;
-use toml::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use syslog::syslog;
+use unavailable::{Unavailability, UnavailableCache};
 use uuid::Uuid;
+use wsl_hello_protocol::Envelope;
 
 #[no_mangle]
 pub fn pam_sm_authenticate(
@@ -23,25 +51,65 @@ pub fn pam_sm_authenticate(
     _: c_int,
     _: *mut *const c_char,
 ) -> c_int {
-    authenticate_via_hello(pamh).unwrap_or_else(|err| {
-        if (flags & PAM_SILENT) == 0 {
-            println!("WSL Hello error: {}", err);
+    let mut attempt = Attempt {
+        silent: (flags & PAM_SILENT) != 0,
+        ..Attempt::default()
+    };
+    let result = authenticate_via_hello(pamh, &mut attempt);
+    let ignored = match result {
+        Ok(code) => code == PAM_IGNORE,
+        Err(ref err) => err.pam_code() == PAM_IGNORE,
+    };
+    if let (Some(ref user_name), false) = (&attempt.user_name, ignored) {
+        let service = get_item(pamh, PAM_SERVICE);
+        let terminal = get_item(pamh, PAM_TTY);
+        let hostname = get_item(pamh, PAM_RHOST);
+        linux_audit::log_auth_event(&AuthEvent {
+            user: user_name,
+            success: matches!(result, Ok(PAM_SUCCESS)),
+            key_fingerprint: attempt.key_fingerprint.as_deref(),
+            audit_log_head: attempt.audit_log_head.as_deref(),
+            service: service.as_deref(),
+            terminal: terminal.as_deref(),
+            hostname: hostname.as_deref(),
+        });
+    }
+    result.unwrap_or_else(|err| {
+        if let Some(untrusted) = err.untrusted_file() {
+            syslog(
+                pamh,
+                LOG_ERR,
+                &format!("refusing to authenticate; {}", untrusted),
+            );
         }
-        match err {
-            HelloAuthenticationError::PublicKeyFileError(ref err)
-                if err.kind() == io::ErrorKind::NotFound =>
-            {
-                PAM_USER_UNKNOWN
-            }
-            HelloAuthenticationError::AuthenticatorLaunchError(_) => PAM_AUTHINFO_UNAVAIL,
-            HelloAuthenticationError::AuthenticatorConnectionError(_) => PAM_AUTHINFO_UNAVAIL,
-            HelloAuthenticationError::AuthenticatorSignalled => PAM_AUTHINFO_UNAVAIL,
-            _ => PAM_AUTH_ERR,
+        // Skipping Windows Hello at the prompt or pressing Ctrl-C is what the user asked for, and
+        // an ignored module leaves the authentication to the next ones without a fuss
+        let quiet = err.pam_code() == PAM_IGNORE
+            || matches!(
+                err,
+                HelloAuthenticationError::HelloCancelled
+                    | HelloAuthenticationError::Interrupted(libc::SIGINT)
+            );
+        if (flags & PAM_SILENT) == 0 && !quiet {
+            println!("WSL Hello error: {}", err);
         }
+        err.pam_code()
     })
 }
 
-fn get_user(pamh: *mut pam_handle_t, prompt: Option<&str>) -> Result<Cow<str>, i32> {
+/// What is known about an authentication attempt besides its result
+#[derive(Debug, Default)]
+struct Attempt {
+    /// Whether the application asked for no messages with `PAM_SILENT`
+    silent: bool,
+    user_name: Option<String>,
+    /// The fingerprint of the key that Windows Hello signed with, once it is known
+    key_fingerprint: Option<String>,
+    /// The hash of the approval in `audit_log`, once it is recorded
+    audit_log_head: Option<String>,
+}
+
+pub(crate) fn get_user(pamh: *mut pam_handle_t, prompt: Option<&str>) -> Result<Cow<'_, str>, i32> {
     let mut c_user: *const c_char = ptr::null();
     let tmp_prompt_str: CString;
     let c_prompt = match prompt {
@@ -64,80 +132,68 @@ fn get_user(pamh: *mut pam_handle_t, prompt: Option<&str>) -> Result<Cow<str>, i
     }
 }
 
-#[derive(Debug)]
-enum ConfigError {
-    Io(io::Error),
-    Toml(toml::de::Error),
-    MissingField(String),
-    InvalidValueType(String),
-}
-
-impl From<io::Error> for ConfigError {
-    fn from(err: io::Error) -> ConfigError {
-        ConfigError::Io(err)
-    }
-}
-
-impl From<toml::de::Error> for ConfigError {
-    fn from(err: toml::de::Error) -> ConfigError {
-        ConfigError::Toml(err)
-    }
-}
-
-impl fmt::Display for ConfigError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            ConfigError::Io(ref ioerr) => write!(f, "{}", ioerr),
-            ConfigError::Toml(_) => write!(f, "TOML format error"),
-            ConfigError::MissingField(ref field) => write!(f, "field: '{}' is not found", field),
-            ConfigError::InvalidValueType(ref field) => {
-                write!(f, "field: '{}' has an invalid value type", field)
-            }
-        }
+/// Returns a string item such as `PAM_SERVICE` or `PAM_TTY`, or `None` if it is not set
+fn get_item(pamh: *mut pam_handle_t, item_type: c_int) -> Option<String> {
+    let mut item: *const c_void = ptr::null();
+    let err = unsafe { pam_get_item(pamh, item_type, &mut item) };
+    if err != PAM_SUCCESS || item.is_null() {
+        return None;
     }
-}
-
-fn get_authenticator_path() -> Result<String, ConfigError> {
-    let mut config_file = File::open("/etc/pam_wsl_hello/config")?;
-    let mut config = String::new();
-    config_file.read_to_string(&mut config)?;
-
-    let config_value = config.parse::<Value>()?;
-    let authenticator_path = config_value
-        .get("authenticator_path")
-        .ok_or_else(|| ConfigError::MissingField("authenticator_path".to_owned()))?
-        .as_str()
-        .ok_or_else(|| ConfigError::InvalidValueType("authenticator_path".to_owned()))?;
-    Ok(authenticator_path.to_owned())
-}
-
-fn get_win_mnt() -> Result<String, ConfigError> {
-    let mut config_file = File::open("/etc/pam_wsl_hello/config")?;
-    let mut config = String::new();
-    config_file.read_to_string(&mut config)?;
-
-    let config_value = config.parse::<Value>()?;
-    let win_mnt = config_value
-        .get("win_mnt")
-        .ok_or_else(|| ConfigError::MissingField("win_mnt".to_owned()))?
-        .as_str()
-        .ok_or_else(|| ConfigError::InvalidValueType("win_mnt".to_owned()))?;
-    Ok(win_mnt.to_owned())
+    let item = unsafe { CStr::from_ptr(item as *const c_char) };
+    Some(item.to_string_lossy().into_owned())
 }
 
 #[derive(Debug)]
-enum HelloAuthenticationError {
+pub(crate) enum HelloAuthenticationError {
     GetUserError(i32),
     ConfigError(ConfigError),
     PublicKeyFileError(io::Error),
+    UntrustedFile(PermissionError),
+    AuthorizedKeysError(AuthorizedKeysError),
+    KeyCommandError(KeyCommandError),
+    NoAuthorizedKey,
+    KeyRestricted(Restriction),
+    KeyExpired(Date),
+    KeyMetadataError(KeyMetadataError),
+    KeyRevoked(String),
+    Locked {
+        failures: u32,
+        remaining_seconds: u64,
+    },
+    LockoutError(LockoutError),
+    RevocationError(RevocationError),
+    HostKeyError(HostKeyError),
+    AuditLogError(AuditLogError),
     Io(io::Error),
-    InvalidPublicKey(openssl::error::ErrorStack),
+    InvalidPublicKey(keys::InvalidKey),
+    AttestationError(AttestationError),
+    CertificateError(CertificateError),
     OpenSslError(openssl::error::ErrorStack),
     AuthenticatorLaunchError(io::Error),
     AuthenticatorConnectionError(io::Error),
     AuthenticatorSignalled,
-    HelloAuthenticationFail(String),
+    HelloTimeout(Duration),
+    /// Windows Hello was found unsupported or unreachable a moment ago
+    HelloUnavailable {
+        reason: Unavailability,
+        remaining_seconds: u64,
+    },
+    HelloCancelled,
+    /// The application received the signal while Windows Hello was pending
+    Interrupted(c_int),
+    PasswordEntered,
+    InvalidPassword,
+    InvalidAuthenticatorResponse,
+    /// The bridge failed, for `reason` if its exit code is known, and printed `message`
+    HelloAuthenticationFail {
+        reason: Option<BridgeFailure>,
+        message: String,
+    },
     SignAuthenticationFail,
+    PublicKeyMismatch {
+        enrolled: String,
+        presented: String,
+    },
 }
 
 impl From<io::Error> for HelloAuthenticationError {
@@ -152,9 +208,79 @@ impl From<ConfigError> for HelloAuthenticationError {
     }
 }
 
+impl HelloAuthenticationError {
+    /// Returns the error of a file that failed the ownership and permission checks, if any
+    fn untrusted_file(&self) -> Option<&PermissionError> {
+        match *self {
+            HelloAuthenticationError::ConfigError(ConfigError::Untrusted(ref err))
+            | HelloAuthenticationError::KeyMetadataError(KeyMetadataError::Untrusted(ref err))
+            | HelloAuthenticationError::AuthorizedKeysError(AuthorizedKeysError::Untrusted(
+                ref err,
+            ))
+            | HelloAuthenticationError::HostKeyError(HostKeyError::Untrusted(ref err))
+            | HelloAuthenticationError::AttestationError(AttestationError::UntrustedFile(
+                ref err,
+            ))
+            | HelloAuthenticationError::CertificateError(CertificateError::UntrustedFile(
+                ref err,
+            ))
+            | HelloAuthenticationError::UntrustedFile(ref err) => Some(err),
+            _ => None,
+        }
+    }
+
+    fn pam_code(&self) -> c_int {
+        match *self {
+            HelloAuthenticationError::PublicKeyFileError(ref err)
+                if err.kind() == io::ErrorKind::NotFound =>
+            {
+                PAM_USER_UNKNOWN
+            }
+            HelloAuthenticationError::NoAuthorizedKey => PAM_USER_UNKNOWN,
+            HelloAuthenticationError::ConfigError(ConfigError::Untrusted(_)) => {
+                PAM_AUTHINFO_UNAVAIL
+            }
+            HelloAuthenticationError::UntrustedFile(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::Locked { .. } => PAM_MAXTRIES,
+            HelloAuthenticationError::LockoutError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::KeyExpired(_) => PAM_CRED_EXPIRED,
+            HelloAuthenticationError::KeyMetadataError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::RevocationError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::HostKeyError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::AuditLogError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::AuthorizedKeysError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::KeyCommandError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::AuthenticatorLaunchError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::AuthenticatorConnectionError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::AuthenticatorSignalled => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::HelloTimeout(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::HelloUnavailable { .. } => PAM_IGNORE,
+            HelloAuthenticationError::HelloCancelled => PAM_AUTHINFO_UNAVAIL,
+            // Ctrl-C ends the conversation with the user, anything else the application
+            HelloAuthenticationError::Interrupted(libc::SIGINT) => PAM_CONV_ERR,
+            HelloAuthenticationError::Interrupted(_) => PAM_ABORT,
+            HelloAuthenticationError::InvalidPassword => PAM_AUTHINFO_UNAVAIL,
+            // The password is left to the next modules, e.g. pam_unix with try_first_pass
+            HelloAuthenticationError::PasswordEntered => PAM_IGNORE,
+            HelloAuthenticationError::InvalidAuthenticatorResponse => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::AttestationError(_) => PAM_AUTHINFO_UNAVAIL,
+            HelloAuthenticationError::CertificateError(ref err) => match *err {
+                CertificateError::Untrusted(_)
+                | CertificateError::Revoked
+                | CertificateError::UserMismatch(_) => PAM_AUTH_ERR,
+                _ => PAM_AUTHINFO_UNAVAIL,
+            },
+            _ => PAM_AUTH_ERR,
+        }
+    }
+}
+
 impl fmt::Display for HelloAuthenticationError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            HelloAuthenticationError::GetUserError(code) => {
+                write!(f, "cannot get the user name; PAM error {}", code)
+            }
             HelloAuthenticationError::ConfigError(ref err) => write!(f, "config error; {}", err),
             HelloAuthenticationError::PublicKeyFileError(ref err) => match err.kind() {
                 io::ErrorKind::NotFound => {
@@ -162,97 +288,1181 @@ impl fmt::Display for HelloAuthenticationError {
                 }
                 _ => write!(f, "{}", err),
             },
+            HelloAuthenticationError::UntrustedFile(ref err) => write!(f, "{}", err),
+            HelloAuthenticationError::AuthorizedKeysError(ref err) => {
+                write!(f, "invalid authorized_keys; {}", err)
+            }
+            HelloAuthenticationError::KeyCommandError(ref err) => {
+                write!(f, "authorized_keys_command failed; {}", err)
+            }
+            HelloAuthenticationError::NoAuthorizedKey => {
+                write!(f, "no key is authorized for this user")
+            }
+            HelloAuthenticationError::KeyRestricted(ref restriction) => write!(f, "{}", restriction),
+            HelloAuthenticationError::KeyExpired(date) => write!(
+                f,
+                "your Windows Hello key expired on {}; re-enroll it with install.sh",
+                date
+            ),
+            HelloAuthenticationError::KeyMetadataError(ref err) => {
+                write!(f, "the key metadata file is invalid; {}", err)
+            }
+            HelloAuthenticationError::KeyRevoked(ref revocation) => {
+                write!(f, "your Windows Hello key is revoked; {}", revocation)
+            }
+            HelloAuthenticationError::RevocationError(ref err) => {
+                write!(f, "invalid revoked_keys; {}", err)
+            }
+            HelloAuthenticationError::HostKeyError(ref err) => {
+                write!(f, "cannot sign the request with the host key; {}", err)
+            }
+            HelloAuthenticationError::Locked {
+                failures,
+                remaining_seconds,
+            } => write!(
+                f,
+                "Windows Hello is locked for {} more minutes after {} failed attempts",
+                remaining_seconds.div_ceil(60),
+                failures
+            ),
+            HelloAuthenticationError::LockoutError(ref err) => {
+                write!(f, "cannot read the failure record; {}", err)
+            }
+            HelloAuthenticationError::AuditLogError(ref err) => {
+                write!(f, "cannot write the audit log; {}", err)
+            }
             HelloAuthenticationError::Io(ref err) => write!(f, "{}", err),
-            HelloAuthenticationError::InvalidPublicKey(_) => {
-                write!(f, "the pem file of the public key is invalid")
+            HelloAuthenticationError::InvalidPublicKey(ref err) => {
+                write!(f, "the public key file is invalid; {}", err)
+            }
+            HelloAuthenticationError::AttestationError(ref err) => {
+                write!(f, "the attestation of the public key is invalid; {}", err)
             }
+            HelloAuthenticationError::CertificateError(ref err) => {
+                write!(f, "the certificate of the key is rejected; {}", err)
+            }
+            HelloAuthenticationError::OpenSslError(ref err) => write!(f, "OpenSSL error; {}", err),
             HelloAuthenticationError::AuthenticatorLaunchError(ref err) => {
                 write!(f, "cannot launch Windows Hello; {}", err)
             }
             HelloAuthenticationError::AuthenticatorConnectionError(ref err) => {
                 write!(f, "cannot communicate with Windows Hello; {}", err)
             }
-            HelloAuthenticationError::HelloAuthenticationFail(ref msg) => {
-                write!(f, "authentication failed; {}", msg)
+            HelloAuthenticationError::HelloAuthenticationFail { ref message, .. } => {
+                write!(f, "authentication failed; {}", message)
+            }
+            HelloAuthenticationError::HelloTimeout(timeout) => write!(
+                f,
+                "Windows Hello did not answer within {} seconds",
+                timeout.as_secs()
+            ),
+            HelloAuthenticationError::HelloUnavailable {
+                reason,
+                remaining_seconds,
+            } => write!(
+                f,
+                "Windows Hello is {}, it is not asked for another {} seconds",
+                reason, remaining_seconds
+            ),
+            HelloAuthenticationError::HelloCancelled => write!(f, "Windows Hello is skipped"),
+            HelloAuthenticationError::Interrupted(signal) => {
+                write!(f, "Windows Hello is interrupted by signal {}", signal)
+            }
+            HelloAuthenticationError::PasswordEntered => write!(f, "a password is entered"),
+            HelloAuthenticationError::InvalidPassword => {
+                write!(f, "cannot pass the password on to the next modules")
+            }
+            HelloAuthenticationError::InvalidAuthenticatorResponse => {
+                write!(f, "cannot understand the response of Windows Hello")
             }
             HelloAuthenticationError::SignAuthenticationFail => write!(
                 f,
                 "the result of signature verification of the credential is failure"
             ),
+            HelloAuthenticationError::PublicKeyMismatch {
+                ref enrolled,
+                ref presented,
+            } => write!(
+                f,
+                "your Windows Hello key changed since it was enrolled; re-enroll it with install.sh \
+                 (enrolled: {}, presented: {})",
+                enrolled, presented
+            ),
             ref err => write!(f, "internal error; {:?}", err),
         }
     }
 }
 
-fn authenticate_via_hello(pamh: *mut pam_handle_t) -> Result<i32, HelloAuthenticationError> {
+/// The keys that may sign the challenge, which all belong to a single Windows Hello credential
+struct EnrolledKeys {
+    key_name: String,
+    keys: Vec<EnrolledKey>,
+}
+
+struct EnrolledKey {
+    key: PKey<Public>,
+    /// The first day on which the key is no longer accepted
+    expires: Option<Date>,
+}
+
+impl EnrolledKey {
+    fn new(key: PKey<Public>, metadata: KeyMetadata, config: &Config) -> EnrolledKey {
+        EnrolledKey {
+            key,
+            expires: metadata.expiry(config.max_key_age_days),
+        }
+    }
+}
+
+fn authenticate_via_hello(
+    pamh: *mut pam_handle_t,
+    attempt: &mut Attempt,
+) -> Result<i32, HelloAuthenticationError> {
     let user_name = get_user(pamh, None).map_err(HelloAuthenticationError::GetUserError)?;
-    let credential_key_name = format!("pam_wsl_hello_{}", user_name);
+    attempt.user_name = Some(user_name.to_string());
+    let config = Config::load()?;
+    if config.audit_only {
+        return Ok(audit_authentication(pamh, &user_name, &config, attempt));
+    }
+    let result = authenticate_user(pamh, &user_name, &config, attempt);
+    run_hooks(pamh, &config, attempt, &result);
+    result
+}
+
+fn authenticate_user(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    config: &Config,
+    attempt: &mut Attempt,
+) -> Result<i32, HelloAuthenticationError> {
+    let session = match config.grace_seconds {
+        0 => None,
+        _ => current_session(pamh, user_name),
+    };
+    if let Some(ref session) = session {
+        match session.granted_key(u64::from(config.grace_seconds), lockout::now()) {
+            Ok(Some(fingerprint)) => match check_grace_key(pamh, user_name, config, &fingerprint) {
+                Ok(expires) => {
+                    syslog(
+                        pamh,
+                        LOG_NOTICE,
+                        &format!("{} authenticated within the grace period", user_name),
+                    );
+                    if let Some(expires) = expires {
+                        account::remember_key_expiry(pamh, expires);
+                    }
+                    attempt.key_fingerprint = Some(fingerprint);
+                    return Ok(PAM_SUCCESS);
+                }
+                // Windows Hello decides, and fails for the same reason unless another key works
+                Err(err) => log_grace_error(pamh, user_name, &err),
+            },
+            Ok(None) => {}
+            Err(err) => log_grace_error(pamh, user_name, &err),
+        }
+    }
+
+    let result = verify_with_lockout(pamh, user_name, config, attempt);
+    delay_failure(pamh, config, &result);
+    if let Some(ref session) = session {
+        let updated = match (&result, &attempt.key_fingerprint) {
+            (Ok(_), Some(fingerprint)) => session.record(lockout::now(), fingerprint),
+            _ => session.invalidate(),
+        };
+        if let Err(err) = updated {
+            log_grace_error(pamh, user_name, &err);
+        }
+    }
+    result
+}
+
+/// Asks libpam to delay the failure of `result` by `fail_delay_ms`. Nothing is registered for
+/// other results, nor for a delay of 0, which would override the delays of other modules.
+fn delay_failure(
+    pamh: *mut pam_handle_t,
+    config: &Config,
+    result: &Result<i32, HelloAuthenticationError>,
+) {
+    let failed = match *result {
+        Ok(code) => code != PAM_SUCCESS && code != PAM_IGNORE,
+        Err(ref err) => err.pam_code() != PAM_IGNORE,
+    };
+    if failed && config.fail_delay_ms > 0 {
+        unsafe { pam_fail_delay(pamh, config.fail_delay_ms.saturating_mul(1000)) };
+    }
+}
 
-    let mut hello_public_key_file = File::open(format!(
-        "/etc/pam_wsl_hello/public_keys/{}.pem",
-        credential_key_name
+/// Checks that the key of the grace period of a session, given by its fingerprint, is still
+/// accepted for `user_name` apart from the signature, and returns when it expires. The user may
+/// have been locked out, or the key revoked, restricted or expired since the grace period began.
+fn check_grace_key(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    config: &Config,
+    fingerprint: &str,
+) -> Result<Option<Date>, HelloAuthenticationError> {
+    if config.lockout_policy().is_some() {
+        check_lockout(pamh, user_name)?;
+    }
+    let revoked_keys = RevocationList::load().map_err(HelloAuthenticationError::RevocationError)?;
+    if let Some(revoked) = revoked_keys.find_fingerprint(fingerprint) {
+        return Err(HelloAuthenticationError::KeyRevoked(revoked.to_string()));
+    }
+
+    let keys = match config.trust_mode {
+        TrustMode::Keys => {
+            let enrolled = load_enrolled_keys(pamh, user_name, config)?;
+            retain_attested_keys(config, &enrolled.key_name, enrolled.keys)?
+        }
+        TrustMode::Ca => {
+            // The certificate that the bridge presents lies next to it
+            let certificate_path = Path::new(&config.authenticator_path)
+                .with_file_name(format!("{}.crt", keys::credential_key_name(user_name)));
+            let certificate =
+                fs::read(certificate_path).map_err(HelloAuthenticationError::PublicKeyFileError)?;
+            let key = verify_certificate(pamh, config, user_name, Some(&certificate))
+                .map_err(HelloAuthenticationError::CertificateError)?;
+            vec![EnrolledKey::new(key, KeyMetadata::default(), config)]
+        }
+    };
+    keys.into_iter()
+        .find(|key| keys::fingerprint(&key.key).ok().as_deref() == Some(fingerprint))
+        .map(|key| key.expires)
+        .ok_or(HelloAuthenticationError::NoAuthorizedKey)
+}
+
+/// Starts the hook for the outcome of an authentication, if one is configured. Hooks are only
+/// logged when they cannot be started, so that they never change the result.
+fn run_hooks(
+    pamh: *mut pam_handle_t,
+    config: &Config,
+    attempt: &Attempt,
+    result: &Result<i32, HelloAuthenticationError>,
+) {
+    let (event, command) = match *result {
+        Ok(PAM_SUCCESS) => (HookEvent::Success, &config.on_success),
+        Ok(_) => return,
+        Err(ref err) if err.pam_code() == PAM_IGNORE => return,
+        Err(HelloAuthenticationError::HelloTimeout(_)) => (HookEvent::Timeout, &config.on_timeout),
+        Err(_) => (HookEvent::Failure, &config.on_failure),
+    };
+    let command = match *command {
+        Some(ref command) => command,
+        None => return,
+    };
+    let context = HookContext {
+        user: attempt.user_name.clone().unwrap_or_default(),
+        service: get_item(pamh, PAM_SERVICE),
+        tty: get_item(pamh, PAM_TTY),
+        rhost: get_item(pamh, PAM_RHOST),
+        ruser: get_item(pamh, PAM_RUSER),
+        key_fingerprint: attempt.key_fingerprint.clone(),
+        failure_reason: result.as_ref().err().map(|err| err.to_string()),
+    };
+    if let Err(err) = hooks::run_hook(
+        command,
+        event,
+        &context,
+        &config.hook_user,
+        config.hook_timeout,
+    ) {
+        syslog(
+            pamh,
+            LOG_WARNING,
+            &format!("cannot run the {} hook '{}'; {}", event, command, err),
+        );
+    }
+}
+
+/// Runs the whole verification, including the bridge, and logs what its result would have been,
+/// but leaves the decision to the other modules. Neither the grace period nor the failure record
+/// is touched, so the measurement does not change later authentications either.
+fn audit_authentication(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    config: &Config,
+    attempt: &mut Attempt,
+) -> c_int {
+    let started = Instant::now();
+    let result = verify_user(pamh, user_name, config, attempt);
+    let elapsed_ms = started.elapsed().as_millis();
+    let message = match result {
+        Ok(code) => format!(
+            "audit only: user={} result=\"{}\" elapsed_ms={}",
+            user_name,
+            pam_error_string(pamh, code),
+            elapsed_ms
+        ),
+        Err(err) => format!(
+            "audit only: user={} result=\"{}\" elapsed_ms={} reason=\"{}\"",
+            user_name,
+            pam_error_string(pamh, err.pam_code()),
+            elapsed_ms,
+            err
+        ),
+    };
+    syslog(pamh, LOG_NOTICE, &message);
+    PAM_IGNORE
+}
+
+fn pam_error_string(pamh: *mut pam_handle_t, code: c_int) -> String {
+    let message = unsafe { pam_strerror(pamh, code) };
+    if message.is_null() {
+        return format!("PAM error {}", code);
+    }
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn current_session(pamh: *mut pam_handle_t, user_name: &str) -> Option<Session> {
+    let tty = get_item(pamh, PAM_TTY);
+    Session::current(user_name, tty.as_deref())
+        .map_err(|err| log_grace_error(pamh, user_name, &err))
+        .ok()
+}
+
+fn log_grace_error(pamh: *mut pam_handle_t, user_name: &str, err: &dyn fmt::Display) {
+    syslog(
+        pamh,
+        LOG_WARNING,
+        &format!("cannot use the grace period of {}; {}", user_name, err),
+    );
+}
+
+fn verify_with_lockout(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    config: &Config,
+    attempt: &mut Attempt,
+) -> Result<i32, HelloAuthenticationError> {
+    let policy = match config.lockout_policy() {
+        Some(policy) => policy,
+        None => return verify_user(pamh, user_name, config, attempt),
+    };
+    let record = check_lockout(pamh, user_name)?;
+
+    let result = verify_user(pamh, user_name, config, attempt);
+    let saved = match result {
+        Ok(_) if record.failures > 0 => FailureRecord::reset(user_name),
+        Err(ref err) if err.pam_code() == PAM_AUTH_ERR => {
+            // Other authentications of the user may have failed meanwhile
+            FailureRecord::update(user_name, |record| {
+                match record.record_failure(&policy, lockout::now()) {
+                    true => Some(record.failures),
+                    false => None,
+                }
+            })
+            .map(|locked| {
+                if let Some(failures) = locked {
+                    syslog(
+                        pamh,
+                        LOG_WARNING,
+                        &format!(
+                            "{} is locked out for {} minutes after {} failed attempts",
+                            user_name, config.lockout_minutes, failures
+                        ),
+                    );
+                }
+            })
+        }
+        _ => Ok(()),
+    };
+    if let Err(err) = saved {
+        syslog(
+            pamh,
+            LOG_WARNING,
+            &format!("cannot update the failure record of {}; {}", user_name, err),
+        );
+    }
+    result
+}
+
+/// Fails if `user_name` is locked out, and returns its failure record otherwise
+fn check_lockout(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+) -> Result<FailureRecord, HelloAuthenticationError> {
+    let record = FailureRecord::load(user_name).map_err(HelloAuthenticationError::LockoutError)?;
+    if let Some(remaining_seconds) = record.remaining_lock(lockout::now()) {
+        syslog(
+            pamh,
+            LOG_NOTICE,
+            &format!(
+                "{} is locked out for {} seconds",
+                user_name, remaining_seconds
+            ),
+        );
+        return Err(HelloAuthenticationError::Locked {
+            failures: record.failures,
+            remaining_seconds,
+        });
+    }
+    Ok(record)
+}
+
+fn verify_user(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    config: &Config,
+    attempt: &mut Attempt,
+) -> Result<i32, HelloAuthenticationError> {
+    let _interruption = Interruption::catch();
+    let unavailable = UnavailableCache::new(config);
+    if config.unavailable_cache_seconds > 0 {
+        if let Some((reason, remaining_seconds)) = unavailable.lookup(lockout::now()) {
+            return Err(HelloAuthenticationError::HelloUnavailable {
+                reason,
+                remaining_seconds,
+            });
+        }
+    }
+    let revoked_keys = RevocationList::load().map_err(HelloAuthenticationError::RevocationError)?;
+    // In the CA trust mode, the key is known only once the authenticator presents its certificate
+    let (credential_key_name, hello_public_keys) = match config.trust_mode {
+        TrustMode::Keys => {
+            let enrolled = load_enrolled_keys(pamh, user_name, config)?;
+            let keys = retain_unrevoked_keys(pamh, user_name, &revoked_keys, enrolled.keys)?;
+            let keys = retain_attested_keys(config, &enrolled.key_name, keys)?;
+            (enrolled.key_name, keys)
+        }
+        TrustMode::Ca => (keys::credential_key_name(user_name), Vec::new()),
+    };
+
+    let envelope = describe_request(pamh, user_name, config);
+    let challenge = envelope.to_string();
+    let message = match config.shows_prompt_message(user_name) {
+        true => Some(envelope.describe()),
+        false => None,
+    };
+    let request = new_request(&credential_key_name, &challenge, message.as_deref())?;
+    let prompt_lock = wait_for_prompt_lock(pamh, user_name, config, attempt.silent)?;
+    let response = request_signature_with_retries(pamh, config, &request, attempt);
+    drop(prompt_lock);
+    let response =
+        response.inspect_err(|err| remember_unavailability(pamh, config, &unavailable, err))?;
+    attempt.key_fingerprint = response
+        .public_key
+        .as_deref()
+        .and_then(|der| PKey::public_key_from_der(der).ok())
+        .and_then(|key| keys::fingerprint(&key).ok());
+    check_presented_key(
+        pamh,
+        user_name,
+        &revoked_keys,
+        response.public_key.as_deref(),
+    )?;
+
+    let hello_public_keys = match config.trust_mode {
+        TrustMode::Keys => hello_public_keys,
+        TrustMode::Ca => {
+            let key = verify_certificate(pamh, config, user_name, response.certificate.as_deref())
+                .map_err(HelloAuthenticationError::CertificateError)?;
+            let key = EnrolledKey::new(key, KeyMetadata::default(), config);
+            let keys = retain_unrevoked_keys(pamh, user_name, &revoked_keys, vec![key])?;
+            retain_attested_keys(config, &credential_key_name, keys)?
+        }
+    };
+
+    for hello_public_key in &hello_public_keys {
+        if verify_signature(&hello_public_key.key, &challenge, &response.signature)? {
+            attempt.key_fingerprint = keys::fingerprint(&hello_public_key.key).ok();
+            // An approval that cannot be recorded is refused, so that the log misses none
+            if let (Some(ref path), false) = (&config.audit_log, config.audit_only) {
+                let approval = approval(
+                    pamh,
+                    user_name,
+                    config,
+                    &credential_key_name,
+                    &hello_public_key.key,
+                    &challenge,
+                    &response.signature,
+                )?;
+                let head = audit_log::append(path, &approval)
+                    .map_err(HelloAuthenticationError::AuditLogError)?;
+                // The head is also kept where whoever rewrites the log cannot change it, so that
+                // records cut off its end are noticed
+                syslog(
+                    pamh,
+                    LOG_NOTICE,
+                    &format!("{} audit_log_head={}", path, head),
+                );
+                attempt.audit_log_head = Some(head);
+            }
+            match hello_public_key.expires {
+                // In the audit-only mode, pam_sm_acct_mgmt must not act on the key either
+                Some(expires) if !config.audit_only => account::remember_key_expiry(pamh, expires),
+                _ => {}
+            }
+            return Ok(PAM_SUCCESS);
+        }
+    }
+    Err(diagnose_verification_failure(
+        pamh,
+        &hello_public_keys,
+        response.public_key,
     ))
-    .map_err(HelloAuthenticationError::PublicKeyFileError)?;
-    let mut key_str = String::new();
-    hello_public_key_file.read_to_string(&mut key_str)?;
-    let hello_public_key = PKey::public_key_from_pem(key_str.as_bytes())
-        .map_err(HelloAuthenticationError::InvalidPublicKey)?;
+}
 
-    let challenge = format!("pam_wsl_hello:{}:{}", user_name, Uuid::new_v4());
+/// Asks Windows Hello for a signature, and again for up to `hello_retries` times if it fails for a
+/// reason that another try may fix
+fn request_signature_with_retries(
+    pamh: *mut pam_handle_t,
+    config: &Config,
+    request: &AuthenticatorRequest,
+    attempt: &Attempt,
+) -> Result<AuthenticatorResponse, HelloAuthenticationError> {
+    let mut retries = 0;
+    loop {
+        let result = if config.race_password_prompt {
+            password_race::request_signature_or_password(pamh, config, request)
+        } else {
+            request_signature(config, request)
+        };
+        let reason = match result {
+            Err(HelloAuthenticationError::HelloAuthenticationFail {
+                reason: Some(reason),
+                ..
+            }) if reason.is_retryable() && retries < config.hello_retries => reason,
+            result => return result,
+        };
+        retries += 1;
+        if !attempt.silent {
+            let _ = conversation::send_message(
+                pamh,
+                PAM_TEXT_INFO,
+                &format!(
+                    "Windows Hello failed ({}). Trying again ({} of {})...",
+                    reason, retries, config.hello_retries
+                ),
+            );
+        }
+    }
+}
 
-    let auth_res;
-    let challenge_tmpfile_path = &format!("/tmp/{}", challenge);
-    {
-        // Since there seems to be a bug that C# applications cannot read from pipes on WSL,
-        // we create a temporary file to redirect
-        let mut challenge_tmpfile = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create_new(true)
-            .open(challenge_tmpfile_path)?;
-        challenge_tmpfile.write_all(challenge.as_bytes())?;
-        challenge_tmpfile.seek(SeekFrom::Start(0))?;
-
-        let challenge_tmpfile_in = Stdio::from(challenge_tmpfile);
-
-        let authenticator_path = get_authenticator_path()?;
-        let authenticator = Command::new(&authenticator_path)
+/// Makes the challenge for `user_name` with the application that asks for Windows Hello, which
+/// the bridge shows to the Windows user
+fn describe_request(pamh: *mut pam_handle_t, user_name: &str, config: &Config) -> Envelope {
+    Envelope {
+        service: get_item(pamh, PAM_SERVICE),
+        tty: get_item(pamh, PAM_TTY),
+        rhost: get_item(pamh, PAM_RHOST),
+        ..new_envelope(config, user_name)
+    }
+}
+
+/// Remembers for `unavailable_cache_seconds` that Windows Hello is unsupported or unreachable if
+/// `err` says so, so that the next authentications are ignored without launching the bridge
+fn remember_unavailability(
+    pamh: *mut pam_handle_t,
+    config: &Config,
+    unavailable: &UnavailableCache,
+    err: &HelloAuthenticationError,
+) {
+    let reason = match *err {
+        HelloAuthenticationError::HelloAuthenticationFail {
+            reason: Some(BridgeFailure::WindowsHelloNotSupported),
+            ..
+        } => Unavailability::Unsupported,
+        HelloAuthenticationError::AuthenticatorLaunchError(_) => Unavailability::Unreachable,
+        _ => return,
+    };
+    if config.unavailable_cache_seconds == 0 {
+        return;
+    }
+    let until = lockout::now() + u64::from(config.unavailable_cache_seconds);
+    if let Err(err) = unavailable.remember(reason, until) {
+        syslog(
+            pamh,
+            LOG_WARNING,
+            &format!("cannot remember that Windows Hello is {}; {}", reason, err),
+        );
+    }
+}
+
+/// Waits until no other Windows Hello prompt of the user is open, for at most `hello_timeout`, so
+/// that parallel authentications do not stack their dialogs. A lock file that cannot be used is
+/// logged and skipped rather than keeping the user out.
+fn wait_for_prompt_lock(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    config: &Config,
+    silent: bool,
+) -> Result<Option<PromptLock>, HelloAuthenticationError> {
+    let skip = |err: &dyn fmt::Display| {
+        syslog(
+            pamh,
+            LOG_WARNING,
+            &format!(
+                "cannot serialize the Windows Hello prompts of {}; {}",
+                user_name, err
+            ),
+        );
+        Ok(None)
+    };
+    let lock = match PromptLock::open(user_name) {
+        Ok(lock) => lock,
+        Err(err) => return skip(&err),
+    };
+    let deadline = config
+        .hello_timeout
+        .map(|timeout| (Instant::now() + timeout, timeout));
+    let mut waiting = false;
+    loop {
+        match lock.try_lock() {
+            Ok(true) => return Ok(Some(lock)),
+            Ok(false) => {}
+            Err(err) => return skip(&err),
+        }
+        if !waiting && !silent {
+            let _ = conversation::send_message(
+                pamh,
+                PAM_TEXT_INFO,
+                "Waiting for another Windows Hello prompt to close...",
+            );
+        }
+        waiting = true;
+        if let Some(signal) = interruption::interrupted() {
+            return Err(HelloAuthenticationError::Interrupted(signal));
+        }
+        match deadline {
+            Some((deadline, timeout)) if Instant::now() >= deadline => {
+                return Err(HelloAuthenticationError::HelloTimeout(timeout))
+            }
+            _ => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+/// Makes a challenge envelope for `user_name` with a random nonce, which names the distro so that
+/// the bridge knows the host key to verify it with
+pub(crate) fn new_envelope(config: &Config, user_name: &str) -> Envelope {
+    // WSL names the distribution in the environment, which applications like sudo may clear
+    let distro = config
+        .distro_name
+        .clone()
+        .or_else(|| std::env::var("WSL_DISTRO_NAME").ok());
+    Envelope {
+        nonce: Uuid::new_v4().to_string(),
+        issued: lockout::now(),
+        user: user_name.to_owned(),
+        distro,
+        ..Envelope::default()
+    }
+}
+
+/// Makes the request to sign `challenge` with `key_name`, which carries the signature of the host
+/// key if the distro has one, so that the bridge can tell it from requests of other programs
+pub(crate) fn new_request<'a>(
+    key_name: &'a str,
+    challenge: &'a str,
+    message: Option<&'a str>,
+) -> Result<AuthenticatorRequest<'a>, HelloAuthenticationError> {
+    let host_signature = match host_key::load().map_err(HelloAuthenticationError::HostKeyError)? {
+        Some(key) => {
+            Some(host_key::sign(&key, challenge).map_err(HelloAuthenticationError::OpenSslError)?)
+        }
+        None => None,
+    };
+    Ok(AuthenticatorRequest {
+        key_name,
+        challenge,
+        message,
+        host_signature,
+    })
+}
+
+fn approval(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    config: &Config,
+    key_name: &str,
+    key: &PKey<Public>,
+    challenge: &str,
+    signature: &[u8],
+) -> Result<Approval, HelloAuthenticationError> {
+    Ok(Approval {
+        timestamp: lockout::now(),
+        user: user_name.to_owned(),
+        service: get_item(pamh, PAM_SERVICE),
+        tty: get_item(pamh, PAM_TTY),
+        rhost: get_item(pamh, PAM_RHOST),
+        ruser: get_item(pamh, PAM_RUSER),
+        distro: config.distro_name.clone(),
+        key_name: key_name.to_owned(),
+        key_fingerprint: keys::fingerprint(key).map_err(HelloAuthenticationError::OpenSslError)?,
+        public_key: key
+            .public_key_to_der()
+            .map_err(HelloAuthenticationError::OpenSslError)?,
+        challenge: challenge.to_owned(),
+        signature: signature.to_vec(),
+    })
+}
+
+/// Asks Windows Hello to sign the challenge of `request` with its credential
+pub(crate) fn request_signature(
+    config: &Config,
+    request: &AuthenticatorRequest,
+) -> Result<AuthenticatorResponse, HelloAuthenticationError> {
+    Authenticator::start(config, request)?.finish(config.hello_timeout)
+}
+
+/// A running `WindowsHelloBridge.exe authenticator`. It reads the request from its stdin, and
+/// cancels Windows Hello once the module closes it.
+pub(crate) struct Authenticator {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Authenticator {
+    pub(crate) fn start(
+        config: &Config,
+        request: &AuthenticatorRequest,
+    ) -> Result<Authenticator, HelloAuthenticationError> {
+        // The bridge runs in a process group of its own, so that Ctrl-C on the terminal reaches
+        // only the module, which then stops the bridge together with the WSL interop process
+        let mut child = Command::new(&config.authenticator_path)
             .arg("authenticator")
-            .arg(credential_key_name)
-            .current_dir(Path::new(&get_win_mnt()?))
-            .stdin(challenge_tmpfile_in)
+            .arg(request.key_name)
+            .arg("--cancel-on-eof")
+            // The module reports an unsupported device itself rather than with a dialog
+            .arg("--non-interactive")
+            .current_dir(Path::new(&config.win_mnt))
+            .process_group(0)
+            .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn()
             .map_err(HelloAuthenticationError::AuthenticatorLaunchError)?;
+        let mut stdin = child.stdin.take();
+        let written = match stdin {
+            Some(ref mut stdin) => stdin.write_all(request.to_input().as_bytes()),
+            None => Ok(()),
+        };
+        let authenticator = Authenticator {
+            child,
+            stdin,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        if let Err(err) = written {
+            authenticator.stop();
+            return Err(HelloAuthenticationError::AuthenticatorConnectionError(err));
+        }
+        Ok(authenticator)
+    }
+
+    /// Returns a flag that stops the bridge once it is set, from any thread
+    pub(crate) fn canceller(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
 
-        auth_res = authenticator
+    /// Waits for the response of Windows Hello, for at most `timeout`. The bridge is stopped if
+    /// the timeout passes, the canceller is set, or the `Interruption` of the caller catches SIGINT
+    /// or SIGTERM.
+    pub(crate) fn finish(
+        mut self,
+        timeout: Option<Duration>,
+    ) -> Result<AuthenticatorResponse, HelloAuthenticationError> {
+        let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+        loop {
+            let exited = self
+                .child
+                .try_wait()
+                .map_err(HelloAuthenticationError::AuthenticatorConnectionError)?;
+            if exited.is_some() {
+                break;
+            }
+            let stopped = if let Some(signal) = interruption::interrupted() {
+                Some(HelloAuthenticationError::Interrupted(signal))
+            } else if self.cancelled.load(Ordering::SeqCst) {
+                Some(HelloAuthenticationError::HelloCancelled)
+            } else {
+                match deadline {
+                    Some((deadline, timeout)) if Instant::now() >= deadline => {
+                        Some(HelloAuthenticationError::HelloTimeout(timeout))
+                    }
+                    _ => None,
+                }
+            };
+            if let Some(err) = stopped {
+                self.stop();
+                return Err(err);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        // The bridge prints only a few lines, which fit in the pipe until they are read
+        self.stdin = None;
+        let auth_res = self
+            .child
             .wait_with_output()
             .map_err(HelloAuthenticationError::AuthenticatorConnectionError)?;
+        match auth_res.status.code() {
+            Some(0) => { /* Success */ }
+            Some(code) => {
+                return Err(HelloAuthenticationError::HelloAuthenticationFail {
+                    reason: BridgeFailure::from_code(code),
+                    message: String::from_utf8(auth_res.stdout)
+                        .unwrap_or_else(|_| "invalid utf8 output".to_string()),
+                })
+            }
+            None => return Err(HelloAuthenticationError::AuthenticatorSignalled),
+        }
+        AuthenticatorResponse::parse(&auth_res.stdout)
+            .ok_or(HelloAuthenticationError::InvalidAuthenticatorResponse)
     }
-    fs::remove_file(challenge_tmpfile_path)?;
 
-    match auth_res.status.code() {
-        Some(code) if code == 0 => { /* Success */ }
-        Some(_) => {
-            return Err(HelloAuthenticationError::HelloAuthenticationFail(
-                String::from_utf8(auth_res.stdout)
-                    .unwrap_or_else(|_| "invalid utf8 output".to_string()),
-            ))
+    /// Closes stdin, which makes the bridge cancel Windows Hello and close its dialog, and
+    /// kills the process group of the bridge unless it exits within a second
+    fn stop(mut self) {
+        self.stdin = None;
+        let deadline = Instant::now() + BRIDGE_EXIT_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Ok(Some(_)) = self.child.try_wait() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
         }
-        None => return Err(HelloAuthenticationError::AuthenticatorSignalled),
+        unsafe { libc::kill(-(self.child.id() as libc::pid_t), libc::SIGKILL) };
+        let _ = self.child.wait();
     }
-    let signature = auth_res.stdout;
+}
+
+/// How long the bridge may take to cancel Windows Hello before it is killed
+const BRIDGE_EXIT_TIMEOUT: Duration = Duration::from_secs(1);
 
-    let mut verifier = Verifier::new(MessageDigest::sha256(), &hello_public_key).unwrap();
+pub(crate) fn verify_signature(
+    key: &PKey<Public>,
+    challenge: &str,
+    signature: &[u8],
+) -> Result<bool, HelloAuthenticationError> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key)
+        .map_err(HelloAuthenticationError::OpenSslError)?;
     verifier
         .update(challenge.as_bytes())
         .map_err(HelloAuthenticationError::OpenSslError)?;
+    verifier
+        .verify(signature)
+        .map_err(HelloAuthenticationError::OpenSslError)
+}
+
+fn load_enrolled_keys(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    config: &Config,
+) -> Result<EnrolledKeys, HelloAuthenticationError> {
+    let command = match config.authorized_keys_command {
+        Some(ref command) => command,
+        None => return load_key_files(pamh, user_name, config),
+    };
+
+    let no_keys = EnrolledKeys {
+        key_name: keys::credential_key_name(user_name),
+        keys: Vec::new(),
+    };
+    let (mut enrolled, file_error) = match config.authorized_keys_command_mode {
+        KeyCommandMode::Replace => (no_keys, None),
+        KeyCommandMode::Add => match load_key_files(pamh, user_name, config) {
+            Ok(enrolled) => (enrolled, None),
+            // The command may still know keys of users without a usable key file
+            Err(HelloAuthenticationError::PublicKeyFileError(ref err))
+                if err.kind() == io::ErrorKind::NotFound =>
+            {
+                (no_keys, None)
+            }
+            Err(err @ HelloAuthenticationError::NoAuthorizedKey)
+            | Err(err @ HelloAuthenticationError::KeyRestricted(_))
+            | Err(err @ HelloAuthenticationError::KeyExpired(_)) => (no_keys, Some(err)),
+            Err(err) => return Err(err),
+        },
+    };
+
+    let command_keys = key_command::lookup_keys(
+        command,
+        &config.authorized_keys_command_user,
+        user_name,
+        &enrolled.key_name,
+        config.authorized_keys_command_timeout,
+    )
+    .map_err(|err| {
+        syslog(
+            pamh,
+            LOG_NOTICE,
+            &format!("authorized_keys_command for {} failed; {}", user_name, err),
+        );
+        HelloAuthenticationError::KeyCommandError(err)
+    })?;
+    enrolled.keys.extend(
+        command_keys
+            .into_iter()
+            .map(|key| EnrolledKey::new(key, KeyMetadata::default(), config)),
+    );
 
-    match verifier
-        .verify(&signature)
+    if enrolled.keys.is_empty() {
+        return Err(file_error.unwrap_or(HelloAuthenticationError::NoAuthorizedKey));
+    }
+    Ok(enrolled)
+}
+
+/// Loads the keys of `user_name` from `authorized_keys` if it exists, or from `public_keys`
+fn load_key_files(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    config: &Config,
+) -> Result<EnrolledKeys, HelloAuthenticationError> {
+    let authorized_keys =
+        authorized_keys::load().map_err(HelloAuthenticationError::AuthorizedKeysError)?;
+    let authorized_keys = match authorized_keys {
+        Some(authorized_keys) => authorized_keys,
+        None => {
+            let metadata =
+                KeyMetadata::load(user_name).map_err(HelloAuthenticationError::KeyMetadataError)?;
+            let credential_key_name = metadata.credential_key_name(user_name);
+            let key_data =
+                permissions::read_root_owned(&keys::key_file_path(&credential_key_name, "pem"))
+                    .map_err(|err| match err {
+                        PermissionError::Io(err) => {
+                            HelloAuthenticationError::PublicKeyFileError(err)
+                        }
+                        err => HelloAuthenticationError::UntrustedFile(err),
+                    })?;
+            let hello_public_key = keys::parse_public_key(&key_data)
+                .map_err(HelloAuthenticationError::InvalidPublicKey)?;
+            let hello_public_key = EnrolledKey::new(hello_public_key, metadata, config);
+            if let Some(expires) = hello_public_key
+                .expires
+                .filter(|&date| Date::today() >= date)
+            {
+                return Err(HelloAuthenticationError::KeyExpired(expires));
+            }
+            return Ok(EnrolledKeys {
+                key_name: credential_key_name,
+                keys: vec![hello_public_key],
+            });
+        }
+    };
+
+    // authorized_keys replaces the per-user key files so that it is the single inventory
+    let service = get_item(pamh, PAM_SERVICE);
+    let context = RequestContext {
+        service: service.as_deref(),
+        distro: config.distro_name.as_deref(),
+        today: Date::today(),
+    };
+    let mut restriction = None;
+    let mut enrolled: Option<EnrolledKeys> = None;
+    for authorized_key in authorized_keys {
+        if authorized_key.user != user_name {
+            continue;
+        }
+        let key = EnrolledKey::new(
+            authorized_key.key.clone(),
+            authorized_key.metadata(),
+            config,
+        );
+        let checked = authorized_key
+            .check(&context)
+            .and_then(|()| match key.expires {
+                // `max_key_age_days` may make the key expire before its `expires` option
+                Some(expires) if context.today >= expires => Err(Restriction::Expired(expires)),
+                _ => Ok(()),
+            });
+        if let Err(err) = checked {
+            syslog(
+                pamh,
+                LOG_NOTICE,
+                &format!(
+                    "{}:{} is not used for {}; {}",
+                    authorized_keys::AUTHORIZED_KEYS_PATH,
+                    authorized_key.line,
+                    user_name,
+                    err
+                ),
+            );
+            restriction = Some(err);
+            continue;
+        }
+
+        match enrolled {
+            // `authorized_keys::parse` makes sure that all lines of a user name one credential
+            Some(ref mut enrolled) => enrolled.keys.push(key),
+            None => {
+                enrolled = Some(EnrolledKeys {
+                    key_name: authorized_key.credential_key_name(),
+                    keys: vec![key],
+                })
+            }
+        }
+    }
+
+    match (enrolled, restriction) {
+        (Some(enrolled), _) => Ok(enrolled),
+        (None, Some(Restriction::Expired(expires))) => {
+            Err(HelloAuthenticationError::KeyExpired(expires))
+        }
+        (None, Some(restriction)) => Err(HelloAuthenticationError::KeyRestricted(restriction)),
+        (None, None) => Err(HelloAuthenticationError::NoAuthorizedKey),
+    }
+}
+
+/// Drops the revoked keys, and fails if no key is left
+fn retain_unrevoked_keys(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    revoked_keys: &RevocationList,
+    keys: Vec<EnrolledKey>,
+) -> Result<Vec<EnrolledKey>, HelloAuthenticationError> {
+    let mut revocation = None;
+    let mut retained = Vec::with_capacity(keys.len());
+    for key in keys {
+        match revoked_keys
+            .find(&key.key)
+            .map_err(HelloAuthenticationError::OpenSslError)?
+        {
+            Some(revoked) => {
+                syslog(
+                    pamh,
+                    LOG_NOTICE,
+                    &format!(
+                        "revoked key enrolled for {} is not used; {}",
+                        user_name, revoked
+                    ),
+                );
+                revocation = Some(revoked.to_string());
+            }
+            None => retained.push(key),
+        }
+    }
+    match revocation {
+        Some(revocation) if retained.is_empty() => {
+            Err(HelloAuthenticationError::KeyRevoked(revocation))
+        }
+        _ => Ok(retained),
+    }
+}
+
+/// Fails before the signature is verified if the authenticator signed with a revoked key
+fn check_presented_key(
+    pamh: *mut pam_handle_t,
+    user_name: &str,
+    revoked_keys: &RevocationList,
+    presented_key: Option<&[u8]>,
+) -> Result<(), HelloAuthenticationError> {
+    let presented_key = match presented_key.and_then(|der| PKey::public_key_from_der(der).ok()) {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+    match revoked_keys
+        .find(&presented_key)
         .map_err(HelloAuthenticationError::OpenSslError)?
     {
-        true => Ok(PAM_SUCCESS),
-        false => Err(HelloAuthenticationError::SignAuthenticationFail),
+        Some(revoked) => {
+            syslog(
+                pamh,
+                LOG_WARNING,
+                &format!("revoked key presented for {}; {}", user_name, revoked),
+            );
+            Err(HelloAuthenticationError::KeyRevoked(revoked.to_string()))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Drops the keys whose attestation cannot be verified when `require_attestation` is set
+fn retain_attested_keys(
+    config: &Config,
+    key_name: &str,
+    mut keys: Vec<EnrolledKey>,
+) -> Result<Vec<EnrolledKey>, HelloAuthenticationError> {
+    if !config.require_attestation {
+        return Ok(keys);
+    }
+    let mut attestation_error = None;
+    keys.retain(|key| match verify_attestation(config, key_name, &key.key) {
+        Ok(()) => true,
+        Err(err) => {
+            attestation_error = Some(err);
+            false
+        }
+    });
+    match attestation_error.filter(|_| keys.is_empty()) {
+        Some(err) => Err(HelloAuthenticationError::AttestationError(err)),
+        None => Ok(keys),
+    }
+}
+
+fn verify_certificate(
+    pamh: *mut pam_handle_t,
+    config: &Config,
+    user_name: &str,
+    certificate: Option<&[u8]>,
+) -> Result<PKey<Public>, CertificateError> {
+    let chain = certificate::parse_certificates(certificate.ok_or(CertificateError::Missing)?)?;
+    let policy = CaPolicy::load(
+        config.ca_certificates.as_deref().unwrap_or_default(),
+        config.crl.as_deref(),
+        &config.certificate_user_field,
+    )?;
+    policy.verify(&chain, user_name).map_err(|err| {
+        syslog(
+            pamh,
+            LOG_NOTICE,
+            &format!(
+                "certificate presented for {} is rejected; {}",
+                user_name, err
+            ),
+        );
+        err
+    })
+}
+
+fn verify_attestation(
+    config: &Config,
+    key_name: &str,
+    key: &PKey<Public>,
+) -> Result<(), AttestationError> {
+    let roots = match config.attestation_roots {
+        Some(ref path) => attestation::load_roots(path)?,
+        None => return Ok(()),
+    };
+    Attestation::load(key_name)?.verify(key, &roots)
+}
+
+/// Tells a credential that was re-created on Windows apart from a bare verification failure
+/// by comparing the enrolled key with the key that the authenticator reports to have used
+fn diagnose_verification_failure(
+    pamh: *mut pam_handle_t,
+    enrolled_keys: &[EnrolledKey],
+    presented_key: Option<Vec<u8>>,
+) -> HelloAuthenticationError {
+    let enrolled = match enrolled_keys
+        .iter()
+        .map(|enrolled| keys::fingerprint(&enrolled.key))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(fingerprints) => fingerprints,
+        Err(err) => return HelloAuthenticationError::OpenSslError(err),
+    };
+    let presented = presented_key
+        .and_then(|der| PKey::public_key_from_der(&der).ok())
+        .and_then(|key| keys::fingerprint(&key).ok());
+    syslog(
+        pamh,
+        LOG_NOTICE,
+        &format!(
+            "signature verification failed; enrolled key {}, presented key {}",
+            enrolled.join(" "),
+            presented.as_ref().map_or("unknown", String::as_str)
+        ),
+    );
+
+    match presented {
+        Some(presented) if !enrolled.contains(&presented) => {
+            HelloAuthenticationError::PublicKeyMismatch {
+                enrolled: enrolled.join(" "),
+                presented,
+            }
+        }
+        _ => HelloAuthenticationError::SignAuthenticationFail,
     }
 }
@@ -0,0 +1,414 @@
+use date::Date;
+use key_metadata::KeyMetadata;
+use keys;
+use openssl::pkey::{PKey, Public};
+use permissions::{self, PermissionError};
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+pub const AUTHORIZED_KEYS_PATH: &str = "/etc/pam_wsl_hello/authorized_keys";
+
+/// A line of `/etc/pam_wsl_hello/authorized_keys`.
+///
+/// Like sshd's `authorized_keys`, each line consists of comma-separated options followed by a
+/// key, e.g. `user=alice,services="sudo,su",expires=2027-01-01 MIIBIjANBgkq...`.
+/// The key is the rest of the line in any encoding that `keys::parse_public_key` understands
+/// and fits on a line, e.g. the body of a PEM public key or an OpenSSH `ssh-rsa` key.
+pub struct AuthorizedKey {
+    pub line: usize,
+    pub user: String,
+    /// PAM services that may use the key. Any service may if `None`.
+    pub services: Option<Vec<String>>,
+    /// The day on which the key was enrolled, from which `max_key_age_days` counts
+    pub created: Option<Date>,
+    /// The first day on which the key is no longer accepted
+    pub expires: Option<Date>,
+    /// The WSL distribution that may use the key. Any distribution may if `None`.
+    pub from_distro: Option<String>,
+    pub comment: Option<String>,
+    /// The name of the Windows Hello credential. Defaults to `pam_wsl_hello_<user>`.
+    pub key_name: Option<String>,
+    pub key: PKey<Public>,
+}
+
+/// Where an authentication request comes from, to be matched against the options of a key
+pub struct RequestContext<'a> {
+    pub service: Option<&'a str>,
+    pub distro: Option<&'a str>,
+    pub today: Date,
+}
+
+/// Why an authorized key cannot be used for a request
+#[derive(Debug)]
+pub enum Restriction {
+    Service(String),
+    Distro(String),
+    Expired(Date),
+}
+
+impl fmt::Display for Restriction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Restriction::Service(ref service) => {
+                write!(f, "the key is not allowed for the service '{}'", service)
+            }
+            Restriction::Distro(ref distro) => {
+                write!(f, "the key is not allowed in the distribution '{}'", distro)
+            }
+            Restriction::Expired(date) => write!(f, "the key expired on {}", date),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthorizedKeysError {
+    Io(io::Error),
+    Syntax { line: usize, reason: String },
+    Untrusted(PermissionError),
+}
+
+impl From<io::Error> for AuthorizedKeysError {
+    fn from(err: io::Error) -> AuthorizedKeysError {
+        AuthorizedKeysError::Io(err)
+    }
+}
+
+impl fmt::Display for AuthorizedKeysError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthorizedKeysError::Io(ref err) => write!(f, "{}", err),
+            AuthorizedKeysError::Syntax { line, ref reason } => {
+                write!(f, "{}:{}: {}", AUTHORIZED_KEYS_PATH, line, reason)
+            }
+            AuthorizedKeysError::Untrusted(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl AuthorizedKey {
+    pub fn check(&self, context: &RequestContext) -> Result<(), Restriction> {
+        if let Some(ref services) = self.services {
+            let service = context.service.unwrap_or("");
+            if !services.iter().any(|allowed| allowed == service) {
+                return Err(Restriction::Service(service.to_owned()));
+            }
+        }
+        if let Some(ref from_distro) = self.from_distro {
+            if context.distro != Some(from_distro.as_str()) {
+                return Err(Restriction::Distro(
+                    context.distro.unwrap_or("unknown").to_owned(),
+                ));
+            }
+        }
+        if let Some(expires) = self.expires {
+            if context.today >= expires {
+                return Err(Restriction::Expired(expires));
+            }
+        }
+        Ok(())
+    }
+
+    /// The name of the Windows Hello credential of the key
+    pub fn credential_key_name(&self) -> String {
+        self.key_name
+            .clone()
+            .unwrap_or_else(|| keys::credential_key_name(&self.user))
+    }
+
+    pub fn metadata(&self) -> KeyMetadata {
+        KeyMetadata {
+            created: self.created,
+            expires: self.expires,
+            key_name: self.key_name.clone(),
+        }
+    }
+}
+
+/// Returns the entries of `/etc/pam_wsl_hello/authorized_keys`, or `None` if it does not exist
+pub fn load() -> Result<Option<Vec<AuthorizedKey>>, AuthorizedKeysError> {
+    match permissions::read_root_owned(Path::new(AUTHORIZED_KEYS_PATH)) {
+        Ok(contents) => parse(&String::from_utf8_lossy(&contents)).map(Some),
+        Err(PermissionError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(PermissionError::Io(err)) => Err(AuthorizedKeysError::Io(err)),
+        Err(err) => Err(AuthorizedKeysError::Untrusted(err)),
+    }
+}
+
+/// Parses the lines of `authorized_keys`. The authenticator can sign with only one credential, so
+/// all lines of a user must name the same one.
+pub fn parse(contents: &str) -> Result<Vec<AuthorizedKey>, AuthorizedKeysError> {
+    let keys = contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            parse_line(line_number, line).map_err(|reason| AuthorizedKeysError::Syntax {
+                line: line_number,
+                reason,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut key_names = HashMap::new();
+    for key in &keys {
+        let key_name = key.credential_key_name();
+        match key_names.entry(key.user.as_str()) {
+            Entry::Occupied(entry) if *entry.get() != key_name => {
+                return Err(AuthorizedKeysError::Syntax {
+                    line: key.line,
+                    reason: format!(
+                        "key-name '{}' conflicts with '{}' of an earlier line of '{}'",
+                        key_name,
+                        entry.get(),
+                        key.user
+                    ),
+                });
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(key_name);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+fn parse_line(line_number: usize, line: &str) -> Result<AuthorizedKey, String> {
+    let (options, key) = split_first_field(line);
+    if key.is_empty() {
+        return Err("an options field and a key are required".to_owned());
+    }
+
+    let mut user = None;
+    let mut services = None;
+    let mut created = None;
+    let mut expires = None;
+    let mut from_distro = None;
+    let mut comment = None;
+    let mut key_name = None;
+    let mut given = HashSet::new();
+    for option in split_unquoted(options, ',') {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, unquote(value)?),
+            None => return Err(format!("option '{}' has no value", option)),
+        };
+        // A later value would silently replace the first, e.g. a looser `expires`
+        if !given.insert(name) {
+            return Err(format!("the '{}' option is given twice", name));
+        }
+        match name {
+            "user" => user = Some(value),
+            "services" => {
+                services = Some(
+                    value
+                        .split(',')
+                        .map(|service| service.trim().to_owned())
+                        .collect(),
+                )
+            }
+            "created" => created = Some(parse_date(&value)?),
+            "expires" => expires = Some(parse_date(&value)?),
+            "from-distro" => from_distro = Some(value),
+            "comment" => comment = Some(value),
+            "key-name" => key_name = Some(value),
+            _ => return Err(format!("unknown option '{}'", name)),
+        }
+    }
+
+    let key = keys::parse_public_key(key.as_bytes())
+        .map_err(|err| format!("the key is invalid; {}", err))?;
+
+    Ok(AuthorizedKey {
+        line: line_number,
+        user: user.ok_or_else(|| "the 'user' option is required".to_owned())?,
+        services,
+        created,
+        expires,
+        from_distro,
+        comment,
+        key_name,
+        key,
+    })
+}
+
+fn parse_date(value: &str) -> Result<Date, String> {
+    Date::parse(value).ok_or_else(|| format!("'{}' is not a YYYY-MM-DD date", value))
+}
+
+/// Splits `line` at the first whitespace that is not inside double quotes
+fn split_first_field(line: &str) -> (&str, &str) {
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                return (&line[..index], line[index..].trim_start())
+            }
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+fn split_unquoted(field: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (index, c) in field.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&field[start..index]);
+            start = index + 1;
+        }
+    }
+    parts.push(&field[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> Result<String, String> {
+    if !value.starts_with('"') {
+        return Ok(value.to_owned());
+    }
+    if value.len() < 2 || !value.ends_with('"') || value[1..value.len() - 1].contains('"') {
+        return Err(format!("{} is not properly quoted", value));
+    }
+    Ok(value[1..value.len() - 1].to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = include_str!("../tests/fixtures/attestation/hello_key.pem");
+
+    fn key_body() -> String {
+        KEY.lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect()
+    }
+
+    fn context<'a>(service: &'a str, distro: &'a str, today: &str) -> RequestContext<'a> {
+        RequestContext {
+            service: Some(service),
+            distro: Some(distro),
+            today: Date::parse(today).unwrap(),
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let keys = parse(&format!(
+            "# inventory\n\nuser=alice,services=\"sudo, su\",created=2026-01-01,expires=2027-01-01,from-distro=Ubuntu,\
+             comment=\"alice@laptop, work\",key-name=pam_wsl_hello_alice_2 {}\n",
+            key_body()
+        ))
+        .unwrap();
+        assert_eq!(keys.len(), 1);
+        let key = &keys[0];
+        assert_eq!(key.line, 3);
+        assert_eq!(key.user, "alice");
+        assert_eq!(key.services, Some(vec!["sudo".to_owned(), "su".to_owned()]));
+        assert_eq!(key.created.unwrap().to_string(), "2026-01-01");
+        assert_eq!(key.expires.unwrap().to_string(), "2027-01-01");
+        assert_eq!(key.from_distro.as_deref(), Some("Ubuntu"));
+        assert_eq!(key.comment.as_deref(), Some("alice@laptop, work"));
+        assert_eq!(key.key_name.as_deref(), Some("pam_wsl_hello_alice_2"));
+    }
+
+    #[test]
+    fn enforces_options() {
+        let keys = parse(&format!(
+            "user=alice,services=sudo,expires=2027-01-01,from-distro=Ubuntu {}",
+            key_body()
+        ))
+        .unwrap();
+        let key = &keys[0];
+        assert!(key.check(&context("sudo", "Ubuntu", "2026-12-31")).is_ok());
+        match key.check(&context("su", "Ubuntu", "2026-12-31")) {
+            Err(Restriction::Service(ref service)) if service == "su" => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match key.check(&context("sudo", "Debian", "2026-12-31")) {
+            Err(Restriction::Distro(ref distro)) if distro == "Debian" => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match key.check(&context("sudo", "Ubuntu", "2027-01-01")) {
+            Err(Restriction::Expired(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in &[
+            key_body(),
+            format!("services=sudo {}", key_body()),
+            format!("user=alice,expires=2027-02-30 {}", key_body()),
+            format!("user=alice,created=yesterday {}", key_body()),
+            format!("user=alice,no-pty {}", key_body()),
+            format!("user=alice,key-name=a,key-name=b {}", key_body()),
+            format!("user=alice,comment=\"open {}", key_body()),
+            "user=alice AAAA".to_owned(),
+        ] {
+            match parse(&format!("\n{}", line)) {
+                Err(AuthorizedKeysError::Syntax { line: 2, .. }) => {}
+                Err(err) => panic!("unexpected error for '{}': {}", line, err),
+                Ok(_) => panic!("'{}' was accepted", line),
+            }
+        }
+
+        // A later value must not replace the first, e.g. a looser `expires`
+        for &(option, first, second) in &[
+            ("user", "alice", "bob"),
+            ("services", "sudo", "\"sudo,su\""),
+            ("created", "2026-01-01", "2026-01-02"),
+            ("expires", "2020-01-01", "2099-01-01"),
+            ("from-distro", "Ubuntu", "Debian"),
+            ("comment", "laptop", "desktop"),
+            ("key-name", "a", "b"),
+        ] {
+            let user = if option == "user" { "" } else { "user=alice," };
+            let line = format!(
+                "{}{1}={2},{1}={3} {4}",
+                user,
+                option,
+                first,
+                second,
+                key_body()
+            );
+            match parse(&line) {
+                Err(AuthorizedKeysError::Syntax { line: 1, reason }) => {
+                    assert_eq!(reason, format!("the '{}' option is given twice", option))
+                }
+                Err(err) => panic!("unexpected error for '{}': {}", line, err),
+                Ok(_) => panic!("'{}' was accepted", line),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_conflicting_key_names() {
+        let default_name = format!("user=alice {}", key_body());
+        let other_name = format!("user=alice,key-name=pam_wsl_hello_alice_2 {}", key_body());
+        let other_user = format!("user=bob,key-name=pam_wsl_hello_alice_2 {}", key_body());
+        for lines in &[[&default_name, &other_name], [&other_name, &default_name]] {
+            match parse(&format!("{}\n{}\n", lines[0], lines[1])) {
+                Err(AuthorizedKeysError::Syntax { line: 2, .. }) => {}
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("{:?} was accepted", lines),
+            }
+        }
+        let same_name = format!("user=alice,key-name=pam_wsl_hello_alice {}", key_body());
+        let keys = parse(&format!(
+            "{}\n{}\n{}\n",
+            default_name, same_name, other_user
+        ))
+        .unwrap();
+        assert_eq!(keys.len(), 3);
+    }
+}
@@ -0,0 +1,205 @@
+extern crate pam_wsl_hello;
+
+use pam_wsl_hello::attestation::{self, Attestation};
+use pam_wsl_hello::audit_log;
+use pam_wsl_hello::config::Config;
+use pam_wsl_hello::grace;
+use pam_wsl_hello::host_key;
+use pam_wsl_hello::key_metadata::KeyMetadata;
+use pam_wsl_hello::keys;
+use pam_wsl_hello::lockout::FailureRecord;
+use pam_wsl_hello::rotation;
+use std::fs;
+
+const VERIFY_ATTESTATION: &str = "verify-attestation";
+const FINGERPRINT: &str = "fingerprint";
+const RESET_FAILURES: &str = "reset-failures";
+const GRACE: &str = "grace";
+const VERIFY_AUDIT_LOG: &str = "verify-audit-log";
+const HOST_KEY: &str = "host-key";
+const DELETE_CREDENTIAL: &str = "delete-credential";
+const YES: &str = "--yes";
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 2 || args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        display_help();
+        std::process::exit(1)
+    }
+
+    let result = match (args[1].as_str(), &args[2..]) {
+        (VERIFY_ATTESTATION, [user_name]) => verify_attestation(user_name),
+        (FINGERPRINT, [key_path]) => print_fingerprint(key_path),
+        (RESET_FAILURES, [user_name]) => reset_failures(user_name),
+        (GRACE, [flag, user_name]) if flag == "--reset" => reset_grace(user_name),
+        (VERIFY_AUDIT_LOG, []) => verify_audit_log(None),
+        (VERIFY_AUDIT_LOG, [path]) => verify_audit_log(Some(path)),
+        (HOST_KEY, []) => print_host_key(),
+        (DELETE_CREDENTIAL, [user_name]) => delete_credential(user_name, false),
+        (DELETE_CREDENTIAL, [flag, user_name]) if flag == YES => delete_credential(user_name, true),
+        _ => {
+            display_help();
+            std::process::exit(1)
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1)
+    }
+}
+
+fn verify_attestation(user_name: &str) -> Result<(), String> {
+    let config = Config::load().map_err(|e| format!("config error; {}", e))?;
+    let roots_path = match config.attestation_roots {
+        Some(path) => path,
+        None => {
+            println!(
+                "'attestation_roots' is not configured. Skipping the attestation verification."
+            );
+            return Ok(());
+        }
+    };
+    let roots = attestation::load_roots(&roots_path)
+        .map_err(|e| format!("cannot load '{}'; {}", roots_path, e))?;
+
+    let key_name = KeyMetadata::load(user_name)
+        .map_err(|e| format!("cannot load the key metadata of '{}'; {}", user_name, e))?
+        .credential_key_name(user_name);
+    let key_path = keys::key_file_path(&key_name, "pem");
+    let key =
+        fs::read(&key_path).map_err(|e| format!("cannot read '{}'; {}", key_path.display(), e))?;
+    let key = keys::parse_public_key(&key)
+        .map_err(|e| format!("'{}' is invalid; {}", key_path.display(), e))?;
+
+    Attestation::load(&key_name)
+        .and_then(|attestation| attestation.verify(&key, &roots))
+        .map_err(|e| format!("the key of '{}' is not attested; {}", user_name, e))?;
+
+    println!("The key of '{}' is attested by a trusted TPM.", user_name);
+    Ok(())
+}
+
+fn print_fingerprint(key_path: &str) -> Result<(), String> {
+    let key = fs::read(key_path).map_err(|e| format!("cannot read '{}'; {}", key_path, e))?;
+    let key =
+        keys::parse_public_key(&key).map_err(|e| format!("'{}' is invalid; {}", key_path, e))?;
+    let fingerprint = keys::fingerprint(&key).map_err(|e| format!("OpenSSL error; {}", e))?;
+    println!("{}", fingerprint);
+    Ok(())
+}
+
+fn reset_failures(user_name: &str) -> Result<(), String> {
+    FailureRecord::reset(user_name)
+        .map_err(|e| format!("cannot reset the failures of '{}'; {}", user_name, e))?;
+    println!("The failures of '{}' are reset.", user_name);
+    Ok(())
+}
+
+fn reset_grace(user_name: &str) -> Result<(), String> {
+    grace::reset(user_name)
+        .map_err(|e| format!("cannot reset the grace periods of '{}'; {}", user_name, e))?;
+    println!("The grace periods of '{}' are reset.", user_name);
+    Ok(())
+}
+
+fn verify_audit_log(path: Option<&String>) -> Result<(), String> {
+    let path = match path {
+        Some(path) => path.clone(),
+        None => Config::load()
+            .map_err(|e| format!("config error; {}", e))?
+            .audit_log
+            .ok_or_else(|| "'audit_log' is not configured".to_owned())?,
+    };
+    let contents =
+        fs::read_to_string(&path).map_err(|e| format!("cannot read '{}'; {}", path, e))?;
+    let enrolled_keys =
+        audit_log::enrolled_keys().map_err(|e| format!("cannot load the enrolled keys; {}", e))?;
+    let retired_keys =
+        audit_log::retired_keys().map_err(|e| format!("cannot load the retired keys; {}", e))?;
+    let verification = audit_log::verify(&contents, &enrolled_keys, &retired_keys)
+        .map_err(|e| format!("'{}' is not intact; {}", path, e))?;
+
+    for line in &verification.retired {
+        println!(
+            "Line {}: the key is retired, so the signature was verified with the key in the record.",
+            line
+        );
+    }
+    println!(
+        "'{}' is intact and its {} signatures are valid.",
+        path, verification.records
+    );
+    println!(
+        "Its head is {}, which must be the last 'audit_log_head' in syslog and the audit log.",
+        verification.head
+    );
+    Ok(())
+}
+
+fn print_host_key() -> Result<(), String> {
+    let key = host_key::load_or_generate()
+        .map_err(|e| format!("cannot load '{}'; {}", host_key::HOST_KEY_PATH, e))?;
+    let pem = key
+        .public_key_to_pem()
+        .map_err(|e| format!("OpenSSL error; {}", e))?;
+    print!("{}", String::from_utf8_lossy(&pem));
+    Ok(())
+}
+
+fn delete_credential(user_name: &str, confirmed: bool) -> Result<(), String> {
+    let config = Config::load().map_err(|e| format!("config error; {}", e))?;
+    let key_name = KeyMetadata::load(user_name)
+        .map_err(|e| format!("cannot load the key metadata of '{}'; {}", user_name, e))?
+        .credential_key_name(user_name);
+    rotation::delete_credential(&config, &key_name, confirmed)
+        .map_err(|e| format!("cannot delete the credential '{}'; {}", key_name, e))?;
+    println!(
+        "The credential '{}' of '{}' is deleted.",
+        key_name, user_name
+    );
+    Ok(())
+}
+
+fn display_help() {
+    println!("usage: wsl_hello_admin <command> [args]");
+    println!();
+
+    println!("command: ");
+    println!(
+        "    {} user_name: Verifies that the Windows Hello key enrolled for 'user_name' is",
+        VERIFY_ATTESTATION
+    );
+    println!("        attested by a TPM whose certificate chain leads to 'attestation_roots' in the config.");
+    println!(
+        "    {} key_file: Prints the fingerprint of a public key in the format of 'revoked_keys'.",
+        FINGERPRINT
+    );
+    println!(
+        "    {} user_name: Forgets the failed attempts of 'user_name', which also unlocks it.",
+        RESET_FAILURES
+    );
+    println!(
+        "    {} --reset user_name: Ends the grace periods of 'user_name' in all terminal sessions.",
+        GRACE
+    );
+    println!(
+        "    {} [path]: Checks the hash chain of the audit log, 'audit_log' in the config by default,",
+        VERIFY_AUDIT_LOG
+    );
+    println!("        verifies every signature in it again with the enrolled or retired keys, and prints its head.");
+    println!(
+        "    {}: Prints the public key of this distro for 'WindowsHelloBridge.exe register-host',",
+        HOST_KEY
+    );
+    println!(
+        "        generating '{}' first if it does not exist.",
+        host_key::HOST_KEY_PATH
+    );
+    println!(
+        "    {} [{}] user_name: Deletes the Windows Hello credential of 'user_name', the one named by",
+        DELETE_CREDENTIAL, YES
+    );
+    println!("        its key metadata, with 'WindowsHelloBridge.exe delete', which asks to confirm unless '--yes'.");
+}
@@ -0,0 +1,537 @@
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{CrlStatus, X509Crl, X509Ref, X509StoreContext, X509};
+use permissions::{self, PermissionError};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// The key usage extension, 2.5.29.15, as the contents of its OID
+const KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+/// The extended key usage extension, 2.5.29.37, as the contents of its OID
+const EXTENDED_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+/// The digitalSignature bit of the key usage, the first bit of its BIT STRING
+const DIGITAL_SIGNATURE: u8 = 0x80;
+
+/// The extended key usages of which a user certificate must have one: TLS client authentication,
+/// 1.3.6.1.5.5.7.3.2, and Microsoft smart card logon, 1.3.6.1.4.1.311.20.2.2
+const LOGON_USAGES: &[&[u8]] = &[
+    &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x02],
+    &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x14, 0x02, 0x02],
+];
+
+/// Which field of a user certificate names the Linux user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserField {
+    /// The common name of the subject equals the user name
+    CommonName,
+    /// An email address in the subject alternative name is `<user name>@<domain>`
+    Email { domain: String },
+}
+
+/// What the PAM module trusts in the enterprise CA mode
+pub struct CaPolicy {
+    pub ca_certificates: Vec<X509>,
+    /// The CRLs of any of the CAs, which are checked against the certificates that they issued
+    pub crls: Vec<X509Crl>,
+    pub user_field: UserField,
+}
+
+#[derive(Debug)]
+pub enum CertificateError {
+    Io(io::Error),
+    OpenSsl(ErrorStack),
+    /// The CA bundle or the CRL could have been written by someone else than root
+    UntrustedFile(PermissionError),
+    Missing,
+    Untrusted(String),
+    InvalidCrl(String),
+    Revoked,
+    /// The certificate is not for signing in with, as told by its key usages
+    WrongUsage(&'static str),
+    UserMismatch(String),
+}
+
+impl From<io::Error> for CertificateError {
+    fn from(err: io::Error) -> CertificateError {
+        CertificateError::Io(err)
+    }
+}
+
+impl From<PermissionError> for CertificateError {
+    fn from(err: PermissionError) -> CertificateError {
+        match err {
+            PermissionError::Io(err) => CertificateError::Io(err),
+            err => CertificateError::UntrustedFile(err),
+        }
+    }
+}
+
+impl From<ErrorStack> for CertificateError {
+    fn from(err: ErrorStack) -> CertificateError {
+        CertificateError::OpenSsl(err)
+    }
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CertificateError::Io(ref err) => write!(f, "{}", err),
+            CertificateError::OpenSsl(ref err) => write!(f, "OpenSSL error; {}", err),
+            CertificateError::UntrustedFile(ref err) => write!(f, "{}", err),
+            CertificateError::Missing => {
+                write!(f, "Windows Hello did not present a certificate for the key")
+            }
+            CertificateError::Untrusted(ref reason) => {
+                write!(f, "the certificate is not trusted; {}", reason)
+            }
+            CertificateError::InvalidCrl(ref reason) => write!(f, "the CRL is invalid; {}", reason),
+            CertificateError::Revoked => write!(f, "the certificate is revoked"),
+            CertificateError::WrongUsage(reason) => {
+                write!(f, "the certificate is not for authentication; {}", reason)
+            }
+            CertificateError::UserMismatch(ref user) => {
+                write!(f, "the certificate is not issued for '{}'", user)
+            }
+        }
+    }
+}
+
+impl CaPolicy {
+    pub fn load(
+        ca_certificates: &str,
+        crl: Option<&str>,
+        user_field: &UserField,
+    ) -> Result<CaPolicy, CertificateError> {
+        let crls = match crl {
+            Some(path) => parse_crls(&permissions::read_root_owned(Path::new(path))?)?,
+            None => Vec::new(),
+        };
+        Ok(CaPolicy {
+            ca_certificates: X509::stack_from_pem(&permissions::read_root_owned(Path::new(
+                ca_certificates,
+            ))?)?,
+            crls,
+            user_field: user_field.clone(),
+        })
+    }
+
+    /// Validates `chain`, the user certificate followed by optional intermediates, and returns
+    /// the public key of the user certificate if it is trusted to authenticate `user_name`
+    pub fn verify(
+        &self,
+        chain: &[X509],
+        user_name: &str,
+    ) -> Result<PKey<Public>, CertificateError> {
+        let (certificate, intermediates) = chain.split_first().ok_or(CertificateError::Missing)?;
+
+        let mut store = X509StoreBuilder::new()?;
+        for ca_certificate in &self.ca_certificates {
+            store.add_cert(ca_certificate.clone())?;
+        }
+        let store = store.build();
+        let mut untrusted = Stack::new()?;
+        for intermediate in intermediates {
+            untrusted.push(intermediate.clone())?;
+        }
+
+        // verify_cert also checks the validity period of every certificate in the chain
+        let mut context = X509StoreContext::new()?;
+        let verified_chain = context.init(&store, certificate, &untrusted, |context| {
+            if !context.verify_cert()? {
+                return Ok(Err(context.error().error_string().to_owned()));
+            }
+            Ok(Ok(context
+                .chain()
+                .map(|chain| chain.iter().map(X509Ref::to_owned).collect::<Vec<_>>())
+                .unwrap_or_default()))
+        })?;
+        let verified_chain = verified_chain.map_err(CertificateError::Untrusted)?;
+
+        self.check_crls(&verified_chain)?;
+
+        check_usage(certificate)?;
+
+        if !self.names_user(certificate, user_name) {
+            return Err(CertificateError::UserMismatch(user_name.to_owned()));
+        }
+
+        Ok(certificate.public_key()?)
+    }
+
+    /// Checks each certificate of `chain`, as built by the verification from the user certificate
+    /// up to a CA, against the CRL of its issuer. Certificates whose issuer has no CRL are not
+    /// checked, so that a CRL of one CA does not reject the certificates of the others.
+    fn check_crls(&self, chain: &[X509]) -> Result<(), CertificateError> {
+        for pair in chain.windows(2) {
+            let (certificate, issuer) = (&pair[0], &pair[1]);
+            let issuer_der = issuer.subject_name().to_der()?;
+            let crl = match self.crls.iter().find(|crl| {
+                crl.issuer_name()
+                    .to_der()
+                    .is_ok_and(|crl_issuer| crl_issuer == issuer_der)
+            }) {
+                Some(crl) => crl,
+                None => continue,
+            };
+            if !crl.verify(&*issuer.public_key()?)? {
+                return Err(CertificateError::InvalidCrl(
+                    "its signature is invalid".to_owned(),
+                ));
+            }
+
+            if let Some(next_update) = crl.next_update() {
+                let now = openssl::asn1::Asn1Time::days_from_now(0)?;
+                if next_update < now {
+                    return Err(CertificateError::InvalidCrl("it is outdated".to_owned()));
+                }
+            }
+
+            match crl.get_by_serial(certificate.serial_number()) {
+                CrlStatus::NotRevoked | CrlStatus::RemoveFromCrl(_) => {}
+                CrlStatus::Revoked(_) => return Err(CertificateError::Revoked),
+            }
+        }
+        Ok(())
+    }
+
+    fn names_user(&self, certificate: &X509, user_name: &str) -> bool {
+        match self.user_field {
+            UserField::CommonName => certificate
+                .subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .any(|entry| entry.data().as_slice() == user_name.as_bytes()),
+            UserField::Email { ref domain } => {
+                certificate.subject_alt_names().is_some_and(|names| {
+                    names
+                        .iter()
+                        .filter_map(|name| name.email())
+                        .filter_map(|email| email.rsplit_once('@'))
+                        .any(|(local_part, email_domain)| {
+                            local_part == user_name && email_domain.eq_ignore_ascii_case(domain)
+                        })
+                })
+            }
+        }
+    }
+}
+
+/// Checks that the key usage of `certificate` allows digital signatures and that it has one of
+/// the `LOGON_USAGES` as an extended key usage
+fn check_usage(certificate: &X509) -> Result<(), CertificateError> {
+    let der = certificate.to_der()?;
+    // The BIT STRING starts with the number of its unused bits
+    let digital_signature = extension_value(&der, KEY_USAGE)
+        .and_then(der_element)
+        .and_then(|(_, bits, _)| bits.get(1))
+        .is_some_and(|&bits| bits & DIGITAL_SIGNATURE != 0);
+    if !digital_signature {
+        return Err(CertificateError::WrongUsage(
+            "its key usage does not include digitalSignature",
+        ));
+    }
+    if !extended_key_usages(certificate)?
+        .iter()
+        .any(|usage| LOGON_USAGES.contains(&&usage[..]))
+    {
+        return Err(CertificateError::WrongUsage(
+            "its extended key usage includes neither clientAuth nor smart card logon",
+        ));
+    }
+    Ok(())
+}
+
+/// Parses either a PEM bundle of CRLs or a single DER CRL
+fn parse_crls(data: &[u8]) -> Result<Vec<X509Crl>, ErrorStack> {
+    if !data.starts_with(b"-----BEGIN") {
+        return Ok(vec![X509Crl::from_der(data)?]);
+    }
+    String::from_utf8_lossy(data)
+        .split_inclusive("-----END X509 CRL-----")
+        .filter(|block| block.contains("-----BEGIN"))
+        .map(|block| X509Crl::from_pem(block.as_bytes()))
+        .collect()
+}
+
+/// Parses either a PEM bundle or DER certificates concatenated back to back
+pub fn parse_certificates(data: &[u8]) -> Result<Vec<X509>, ErrorStack> {
+    if data.starts_with(b"-----BEGIN") {
+        return X509::stack_from_pem(data);
+    }
+
+    let mut certificates = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let length = der_length(rest).unwrap_or(rest.len());
+        certificates.push(X509::from_der(&rest[..length])?);
+        rest = &rest[length..];
+    }
+    Ok(certificates)
+}
+
+/// Returns the extended key usages of `certificate` as the contents of their OIDs. OpenSSL
+/// tells only the well-known usages, so they are read from the DER of the certificate.
+pub fn extended_key_usages(certificate: &X509Ref) -> Result<Vec<Vec<u8>>, ErrorStack> {
+    let der = certificate.to_der()?;
+    let mut usages = Vec::new();
+    if let Some(mut value) = extension_value(&der, EXTENDED_KEY_USAGE)
+        .and_then(der_element)
+        .map(|(_, contents, _)| contents)
+    {
+        while let Some((_, usage, rest)) = der_element(value) {
+            usages.push(usage.to_vec());
+            value = rest;
+        }
+    }
+    Ok(usages)
+}
+
+/// Returns the value of the extension `oid` in the DER certificate `der`
+fn extension_value<'a>(der: &'a [u8], oid: &[u8]) -> Option<&'a [u8]> {
+    let (_, certificate, _) = der_element(der)?;
+    let (_, mut fields, _) = der_element(certificate)?;
+    // The extensions are the optional [3] at the end of TBSCertificate
+    let extensions = loop {
+        let (tag, contents, rest) = der_element(fields)?;
+        if tag == 0xa3 {
+            break contents;
+        }
+        fields = rest;
+    };
+    let (_, mut extensions, _) = der_element(extensions)?;
+    while let Some((_, extension, rest)) = der_element(extensions) {
+        extensions = rest;
+        let (_, id, mut fields) = der_element(extension)?;
+        if id != oid {
+            continue;
+        }
+        // The value follows the optional critical flag
+        while let Some((tag, contents, rest)) = der_element(fields) {
+            if tag == 0x04 {
+                return Some(contents);
+            }
+            fields = rest;
+        }
+    }
+    None
+}
+
+/// Returns the total length of the DER element at the head of `data`
+fn der_length(data: &[u8]) -> Option<usize> {
+    der_element(data).map(|(_, _, rest)| data.len() - rest.len())
+}
+
+/// Splits the DER element at the head of `data` into its tag, its contents and the rest of `data`
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (header, content) = if first < 0x80 {
+        (2, first)
+    } else {
+        let octets = first & 0x7f;
+        if octets == 0 || octets > 4 {
+            return None;
+        }
+        let content = data
+            .get(2..2 + octets)?
+            .iter()
+            .fold(0, |length, &octet| (length << 8) | octet as usize);
+        (2 + octets, content)
+    };
+    if header + content > data.len() {
+        return None;
+    }
+    Some((
+        tag,
+        &data[header..header + content],
+        &data[header + content..],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA: &[u8] = include_bytes!("../tests/fixtures/ca/ca.pem");
+    const INTERMEDIATE: &[u8] = include_bytes!("../tests/fixtures/ca/intermediate.pem");
+    const CRL: &[u8] = include_bytes!("../tests/fixtures/ca/crl.pem");
+    const INTERMEDIATE_CRL: &[u8] = include_bytes!("../tests/fixtures/ca/intermediate_crl.pem");
+    const FORGED_CRL: &[u8] = include_bytes!("../tests/fixtures/ca/forged_crl.pem");
+    const REVOKED_INTERMEDIATE: &[u8] =
+        include_bytes!("../tests/fixtures/ca/revoked_intermediate.pem");
+    const ALICE: &[u8] = include_bytes!("../tests/fixtures/ca/alice.pem");
+    const ALICE_OF_INTERMEDIATE: &[u8] =
+        include_bytes!("../tests/fixtures/ca/alice_of_intermediate.pem");
+    const MALLORY_OF_INTERMEDIATE: &[u8] =
+        include_bytes!("../tests/fixtures/ca/mallory_of_intermediate.pem");
+    const ALICE_OF_REVOKED_INTERMEDIATE: &[u8] =
+        include_bytes!("../tests/fixtures/ca/alice_of_revoked_intermediate.pem");
+    const SMARTCARD: &[u8] = include_bytes!("../tests/fixtures/ca/smartcard.pem");
+    const MALLORY: &[u8] = include_bytes!("../tests/fixtures/ca/mallory.pem");
+    const EXPIRED: &[u8] = include_bytes!("../tests/fixtures/ca/expired.pem");
+    const SELF_SIGNED: &[u8] = include_bytes!("../tests/fixtures/ca/self_signed.pem");
+    const SERVER: &[u8] = include_bytes!("../tests/fixtures/ca/server.pem");
+    const NO_EXTENDED_USAGE: &[u8] = include_bytes!("../tests/fixtures/ca/no_extended_usage.pem");
+    const ENCIPHERMENT: &[u8] = include_bytes!("../tests/fixtures/ca/encipherment.pem");
+
+    fn email(domain: &str) -> UserField {
+        UserField::Email {
+            domain: domain.to_owned(),
+        }
+    }
+
+    fn policy(user_field: UserField) -> CaPolicy {
+        CaPolicy {
+            ca_certificates: X509::stack_from_pem(CA).unwrap(),
+            crls: vec![X509Crl::from_pem(CRL).unwrap()],
+            user_field,
+        }
+    }
+
+    fn chain(certificates: &[&[u8]]) -> Vec<X509> {
+        let mut der = Vec::new();
+        for certificate in certificates {
+            der.extend(X509::from_pem(certificate).unwrap().to_der().unwrap());
+        }
+        parse_certificates(&der).unwrap()
+    }
+
+    #[test]
+    fn accepts_certificate_of_user() {
+        let key = policy(UserField::CommonName)
+            .verify(&chain(&[ALICE]), "alice")
+            .unwrap();
+        let expected = X509::from_pem(ALICE).unwrap().public_key().unwrap();
+        assert!(key.public_eq(&expected));
+        policy(email("example.com"))
+            .verify(&chain(&[ALICE]), "alice")
+            .unwrap();
+        policy(email("EXAMPLE.com"))
+            .verify(&chain(&[SMARTCARD]), "alice")
+            .unwrap();
+    }
+
+    #[test]
+    fn accepts_certificate_of_intermediate_ca() {
+        // The CRL of the root checks the intermediate, and nothing checks its certificates
+        let policy = policy(UserField::CommonName);
+        let certificate = &chain(&[ALICE_OF_INTERMEDIATE, INTERMEDIATE]);
+        let key = policy.verify(certificate, "alice").unwrap();
+        assert!(key.public_eq(&certificate[0].public_key().unwrap()));
+        policy
+            .verify(&chain(&[MALLORY_OF_INTERMEDIATE, INTERMEDIATE]), "mallory")
+            .unwrap();
+        match policy.verify(&chain(&[ALICE_OF_INTERMEDIATE]), "alice") {
+            Err(CertificateError::Untrusted(_)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn checks_every_certificate_of_the_chain_against_the_crl_of_its_issuer() {
+        let mut bundle = CRL.to_vec();
+        bundle.extend_from_slice(INTERMEDIATE_CRL);
+        let bundled = CaPolicy {
+            crls: parse_crls(&bundle).unwrap(),
+            ..policy(UserField::CommonName)
+        };
+        assert_eq!(bundled.crls.len(), 2);
+
+        bundled
+            .verify(&chain(&[ALICE_OF_INTERMEDIATE, INTERMEDIATE]), "alice")
+            .unwrap();
+        bundled.verify(&chain(&[ALICE]), "alice").unwrap();
+        for (certificate, user_name) in &[
+            (chain(&[MALLORY_OF_INTERMEDIATE, INTERMEDIATE]), "mallory"),
+            (
+                chain(&[ALICE_OF_REVOKED_INTERMEDIATE, REVOKED_INTERMEDIATE]),
+                "alice",
+            ),
+            (chain(&[MALLORY]), "mallory"),
+        ] {
+            match bundled.verify(certificate, user_name) {
+                Err(CertificateError::Revoked) => {}
+                other => panic!("unexpected result: {:?}", other.map(|_| ())),
+            }
+        }
+
+        // A CRL that names the issuer must also be signed by it
+        let forged = CaPolicy {
+            crls: vec![X509Crl::from_pem(FORGED_CRL).unwrap()],
+            ..policy(UserField::CommonName)
+        };
+        match forged.verify(&chain(&[ALICE_OF_INTERMEDIATE, INTERMEDIATE]), "alice") {
+            Err(CertificateError::InvalidCrl(_)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejects_certificate_of_other_user() {
+        for user_field in &[UserField::CommonName, email("example.com")] {
+            match policy(user_field.clone()).verify(&chain(&[ALICE]), "bob") {
+                Err(CertificateError::UserMismatch(_)) => {}
+                other => panic!("unexpected result: {:?}", other.map(|_| ())),
+            }
+        }
+        for domain in &["example.org", "mail.example.com", ""] {
+            match policy(email(domain)).verify(&chain(&[ALICE]), "alice") {
+                Err(CertificateError::UserMismatch(_)) => {}
+                other => panic!("unexpected result: {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_certificate_for_other_usages() {
+        for certificate in &[SERVER, NO_EXTENDED_USAGE, ENCIPHERMENT] {
+            match policy(UserField::CommonName).verify(&chain(&[certificate]), "alice") {
+                Err(CertificateError::WrongUsage(_)) => {}
+                other => panic!("unexpected result: {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_revoked_expired_and_untrusted_certificates() {
+        match policy(UserField::CommonName).verify(&chain(&[MALLORY]), "mallory") {
+            Err(CertificateError::Revoked) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        for certificate in &[EXPIRED, SELF_SIGNED] {
+            match policy(UserField::CommonName).verify(&chain(&[certificate]), "alice") {
+                Err(CertificateError::Untrusted(_)) => {}
+                other => panic!("unexpected result: {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_missing_certificate() {
+        match policy(UserField::CommonName).verify(&[], "alice") {
+            Err(CertificateError::Missing) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn loads_the_policy_only_from_root_owned_files() {
+        // The temporary directory is writable by everyone
+        let path = std::env::temp_dir().join("pam_wsl_hello_ca_test.pem");
+        std::fs::write(&path, CA).unwrap();
+        let path = path.to_str().unwrap();
+        let ca_result = CaPolicy::load(path, None, &UserField::CommonName);
+        let crl_result = CaPolicy::load("/etc/ssl/certs", Some(path), &UserField::CommonName);
+        std::fs::remove_file(path).unwrap();
+        for result in &[ca_result, crl_result] {
+            match *result {
+                Err(CertificateError::UntrustedFile(_)) => {}
+                Err(ref err) => panic!("unexpected error: {:?}", err),
+                Ok(_) => panic!("a file that is not root-owned is loaded"),
+            }
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use certificate::UserField;
+use key_command::KeyCommandMode;
+use lockout::LockoutPolicy;
+use permissions::{self, PermissionError};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use toml;
+use toml::Value;
+
+pub const CONFIG_PATH: &str = "/etc/pam_wsl_hello/config";
+
+/// The contents of `/etc/pam_wsl_hello/config`
+pub struct Config {
+    pub authenticator_path: String,
+    pub win_mnt: String,
+    /// The name of this WSL distribution, matched against `from-distro` in authorized_keys
+    pub distro_name: Option<String>,
+    /// PEM bundle of the TPM manufacturer root certificates that the attestation of enrolled
+    /// keys must chain up to. Attestations are not verified unless this is set.
+    pub attestation_roots: Option<String>,
+    /// Whether the attestation is verified on every authentication, not only at enrollment
+    pub require_attestation: bool,
+    pub trust_mode: TrustMode,
+    /// PEM bundle of the CA certificates that issue user certificates in the CA trust mode
+    pub ca_certificates: Option<String>,
+    /// A CRL of the issuing CA. Revocation is not checked unless this is set.
+    pub crl: Option<String>,
+    pub certificate_user_field: UserField,
+    /// A root-owned command that prints the public keys of the user given as its arguments
+    pub authorized_keys_command: Option<String>,
+    pub authorized_keys_command_mode: KeyCommandMode,
+    /// The user that runs `authorized_keys_command`
+    pub authorized_keys_command_user: String,
+    pub authorized_keys_command_timeout: Duration,
+    /// Keys are refused once they are older than this, counted from their creation date
+    pub max_key_age_days: Option<u32>,
+    /// How many days before its expiry `pam_sm_acct_mgmt` starts warning about a key
+    pub expiry_warning_days: u32,
+    /// Whether `pam_sm_acct_mgmt` requires the key to be rotated once it warns about its expiry
+    pub rotate_before_expiry: bool,
+    /// How many consecutive failures lock a user out. Users are never locked out if `None`.
+    pub lockout_attempts: Option<u32>,
+    pub lockout_minutes: u32,
+    /// The minimum delay after a failed authentication, passed to `pam_fail_delay`
+    pub fail_delay_ms: u32,
+    /// How long a successful authentication is remembered for the terminal session, or 0
+    pub grace_seconds: u32,
+    /// Whether the module only logs what it would have decided and always returns `PAM_IGNORE`
+    pub audit_only: bool,
+    /// A root-only file to which every approval is appended as a hash-chained JSON line
+    pub audit_log: Option<String>,
+    /// How long Windows Hello may take before the bridge is killed. It may take forever if `None`.
+    pub hello_timeout: Option<Duration>,
+    /// How many more times Windows Hello is asked after a failure that another try may fix
+    pub hello_retries: u32,
+    /// How long an unsupported or unreachable Windows Hello is remembered, or 0
+    pub unavailable_cache_seconds: u32,
+    /// Root-owned commands that are started after an authentication, without waiting for them
+    pub on_success: Option<String>,
+    pub on_failure: Option<String>,
+    pub on_timeout: Option<String>,
+    /// The user that runs the hooks
+    pub hook_user: String,
+    pub hook_timeout: Duration,
+    /// Whether the password is prompted for while Windows Hello is asked for a signature, which
+    /// needs a thread-safe conversation function
+    pub race_password_prompt: bool,
+    /// Whether the Windows user is told which application asks for Windows Hello
+    pub prompt_message: bool,
+    /// Users for whom `prompt_message` is turned off
+    pub prompt_message_opt_out: Vec<String>,
+}
+
+/// How the PAM module decides which public keys may authenticate a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustMode {
+    /// Keys enrolled per user or listed in authorized_keys
+    Keys,
+    /// Any key with a certificate that is issued for the user by a trusted CA
+    Ca,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    MissingField(String),
+    InvalidValueType(String),
+    Untrusted(PermissionError),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> ConfigError {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<PermissionError> for ConfigError {
+    fn from(err: PermissionError) -> ConfigError {
+        match err {
+            PermissionError::Io(err) => ConfigError::Io(err),
+            err => ConfigError::Untrusted(err),
+        }
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> ConfigError {
+        ConfigError::Toml(err)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref ioerr) => write!(f, "{}", ioerr),
+            ConfigError::Toml(_) => write!(f, "TOML format error"),
+            ConfigError::MissingField(ref field) => write!(f, "field: '{}' is not found", field),
+            ConfigError::InvalidValueType(ref field) => {
+                write!(f, "field: '{}' has an invalid value type", field)
+            }
+            ConfigError::Untrusted(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        let config = permissions::read_root_owned(Path::new(CONFIG_PATH))?;
+        Config::parse(&String::from_utf8_lossy(&config))
+    }
+
+    pub fn parse(config: &str) -> Result<Config, ConfigError> {
+        let config_value = config.parse::<Value>()?;
+        let config = Config {
+            authenticator_path: required_str(&config_value, "authenticator_path")?,
+            win_mnt: required_str(&config_value, "win_mnt")?,
+            distro_name: optional_str(&config_value, "distro_name")?,
+            attestation_roots: optional_str(&config_value, "attestation_roots")?,
+            require_attestation: optional_bool(&config_value, "require_attestation")?
+                .unwrap_or(false),
+            trust_mode: match optional_str(&config_value, "trust_mode")?.as_deref() {
+                None | Some("keys") => TrustMode::Keys,
+                Some("ca") => TrustMode::Ca,
+                Some(_) => return Err(ConfigError::InvalidValueType("trust_mode".to_owned())),
+            },
+            ca_certificates: optional_str(&config_value, "ca_certificates")?,
+            crl: optional_str(&config_value, "crl")?,
+            certificate_user_field: match optional_str(&config_value, "certificate_user_field")?
+                .as_deref()
+            {
+                None | Some("cn") => UserField::CommonName,
+                Some("email") => UserField::Email {
+                    domain: required_str(&config_value, "certificate_email_domain")?,
+                },
+                Some(_) => {
+                    return Err(ConfigError::InvalidValueType(
+                        "certificate_user_field".to_owned(),
+                    ))
+                }
+            },
+            authorized_keys_command: optional_str(&config_value, "authorized_keys_command")?,
+            authorized_keys_command_mode: match optional_str(
+                &config_value,
+                "authorized_keys_command_mode",
+            )?
+            .as_deref()
+            {
+                None | Some("add") => KeyCommandMode::Add,
+                Some("replace") => KeyCommandMode::Replace,
+                Some(_) => {
+                    return Err(ConfigError::InvalidValueType(
+                        "authorized_keys_command_mode".to_owned(),
+                    ))
+                }
+            },
+            authorized_keys_command_user: optional_str(
+                &config_value,
+                "authorized_keys_command_user",
+            )?
+            .unwrap_or_else(|| "nobody".to_owned()),
+            authorized_keys_command_timeout: Duration::from_secs(
+                optional_unsigned(&config_value, "authorized_keys_command_timeout")?.unwrap_or(5),
+            ),
+            max_key_age_days: optional_unsigned(&config_value, "max_key_age_days")?,
+            expiry_warning_days: optional_unsigned(&config_value, "expiry_warning_days")?
+                .unwrap_or(14),
+            rotate_before_expiry: optional_bool(&config_value, "rotate_before_expiry")?
+                .unwrap_or(false),
+            lockout_attempts: optional_unsigned(&config_value, "lockout_attempts")?
+                .filter(|&attempts| attempts > 0),
+            lockout_minutes: optional_unsigned(&config_value, "lockout_minutes")?.unwrap_or(10),
+            fail_delay_ms: optional_unsigned(&config_value, "fail_delay_ms")?.unwrap_or(2000),
+            grace_seconds: optional_unsigned(&config_value, "grace_seconds")?.unwrap_or(0),
+            audit_only: optional_bool(&config_value, "audit_only")?.unwrap_or(false),
+            audit_log: optional_str(&config_value, "audit_log")?,
+            hello_timeout: optional_unsigned(&config_value, "hello_timeout")?
+                .filter(|&seconds| seconds > 0)
+                .map(Duration::from_secs),
+            hello_retries: optional_unsigned(&config_value, "hello_retries")?.unwrap_or(0),
+            unavailable_cache_seconds: optional_unsigned(
+                &config_value,
+                "unavailable_cache_seconds",
+            )?
+            .unwrap_or(0),
+            on_success: optional_str(&config_value, "on_success")?,
+            on_failure: optional_str(&config_value, "on_failure")?,
+            on_timeout: optional_str(&config_value, "on_timeout")?,
+            hook_user: optional_str(&config_value, "hook_user")?
+                .unwrap_or_else(|| "nobody".to_owned()),
+            hook_timeout: Duration::from_secs(
+                optional_unsigned(&config_value, "hook_timeout")?.unwrap_or(10),
+            ),
+            race_password_prompt: optional_bool(&config_value, "race_password_prompt")?
+                .unwrap_or(false),
+            prompt_message: optional_bool(&config_value, "prompt_message")?.unwrap_or(true),
+            prompt_message_opt_out: optional_str_array(&config_value, "prompt_message_opt_out")?
+                .unwrap_or_default(),
+        };
+        if config.require_attestation && config.attestation_roots.is_none() {
+            return Err(ConfigError::MissingField("attestation_roots".to_owned()));
+        }
+        if config.trust_mode == TrustMode::Ca && config.ca_certificates.is_none() {
+            return Err(ConfigError::MissingField("ca_certificates".to_owned()));
+        }
+        Ok(config)
+    }
+
+    pub fn shows_prompt_message(&self, user_name: &str) -> bool {
+        self.prompt_message
+            && !self
+                .prompt_message_opt_out
+                .iter()
+                .any(|user| user == user_name)
+    }
+
+    pub fn lockout_policy(&self) -> Option<LockoutPolicy> {
+        self.lockout_attempts.map(|attempts| LockoutPolicy {
+            attempts,
+            lock_seconds: u64::from(self.lockout_minutes) * 60,
+        })
+    }
+}
+
+fn required_str(config_value: &Value, field: &str) -> Result<String, ConfigError> {
+    optional_str(config_value, field)?.ok_or_else(|| ConfigError::MissingField(field.to_owned()))
+}
+
+fn optional_str(config_value: &Value, field: &str) -> Result<Option<String>, ConfigError> {
+    match config_value.get(field) {
+        Some(value) => value
+            .as_str()
+            .map(|value| Some(value.to_owned()))
+            .ok_or_else(|| ConfigError::InvalidValueType(field.to_owned())),
+        None => Ok(None),
+    }
+}
+
+fn optional_str_array(
+    config_value: &Value,
+    field: &str,
+) -> Result<Option<Vec<String>>, ConfigError> {
+    let invalid = || ConfigError::InvalidValueType(field.to_owned());
+    match config_value.get(field) {
+        Some(value) => value
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|item| item.as_str().map(str::to_owned).ok_or_else(invalid))
+            .collect::<Result<_, _>>()
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+fn optional_bool(config_value: &Value, field: &str) -> Result<Option<bool>, ConfigError> {
+    match config_value.get(field) {
+        Some(value) => value
+            .as_bool()
+            .map(Some)
+            .ok_or_else(|| ConfigError::InvalidValueType(field.to_owned())),
+        None => Ok(None),
+    }
+}
+
+fn optional_unsigned<T: TryFrom<i64>>(
+    config_value: &Value,
+    field: &str,
+) -> Result<Option<T>, ConfigError> {
+    match config_value.get(field) {
+        Some(value) => value
+            .as_integer()
+            .and_then(|value| T::try_from(value).ok())
+            .map(Some)
+            .ok_or_else(|| ConfigError::InvalidValueType(field.to_owned())),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,54 @@
+use bindings::*;
+use libc::{self, c_int, c_void};
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+/// Shows `message` to the user, with `style` being `PAM_TEXT_INFO` or `PAM_ERROR_MSG`
+pub fn send_message(pamh: *mut pam_handle_t, style: c_int, message: &str) -> Result<(), c_int> {
+    converse(pamh, style, message).map(|_| ())
+}
+
+/// Passes a single message to the conversation function of the application and returns the
+/// response, if any
+pub fn converse(
+    pamh: *mut pam_handle_t,
+    style: c_int,
+    message: &str,
+) -> Result<Option<String>, c_int> {
+    let mut conv: *const c_void = ptr::null();
+    let err = unsafe { pam_get_item(pamh, PAM_CONV, &mut conv) };
+    if err != PAM_SUCCESS {
+        return Err(err);
+    }
+    let conv = unsafe { (conv as *const pam_conv).as_ref() }.ok_or(PAM_CONV_ERR)?;
+    let conv_fn = conv.conv.ok_or(PAM_CONV_ERR)?;
+
+    let message = CString::new(message).map_err(|_| PAM_CONV_ERR)?;
+    let pam_message = pam_message {
+        msg_style: style,
+        msg: message.as_ptr(),
+    };
+    let mut messages = [&pam_message as *const pam_message];
+    let mut response: *mut pam_response = ptr::null_mut();
+    let err = unsafe { conv_fn(1, messages.as_mut_ptr(), &mut response, conv.appdata_ptr) };
+    if err != PAM_SUCCESS {
+        return Err(err);
+    }
+    if response.is_null() {
+        return Ok(None);
+    }
+
+    // The application allocates the responses with malloc and the module frees them
+    unsafe {
+        let text = (*response).resp;
+        let result = if text.is_null() {
+            None
+        } else {
+            let result = CStr::from_ptr(text).to_string_lossy().into_owned();
+            libc::free(text as *mut c_void);
+            Some(result)
+        };
+        libc::free(response as *mut c_void);
+        Ok(result)
+    }
+}
@@ -0,0 +1,306 @@
+use auth;
+use bindings::*;
+use libc::{self, c_char, c_int, LOG_WARNING};
+use permissions;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use syslog::syslog;
+use toml::Value;
+
+/// Where the times of successful authentications are kept, in a directory per user with a file
+/// per terminal session like sudo's time stamps
+pub const GRACE_DIR: &str = "/var/lib/pam_wsl_hello/grace";
+
+/// Forgets the grace periods of terminal sessions of the user that have ended. Each record also
+/// stops being valid by itself once its session leader exits, so this only cleans up.
+#[no_mangle]
+pub fn pam_sm_close_session(
+    pamh: *mut pam_handle_t,
+    _: c_int,
+    _: c_int,
+    _: *mut *const c_char,
+) -> c_int {
+    let user_name = match auth::get_user(pamh, None) {
+        Ok(user_name) => user_name,
+        Err(_) => return PAM_IGNORE,
+    };
+    if let Err(err) = remove_ended_sessions(&user_name) {
+        syslog(
+            pamh,
+            LOG_WARNING,
+            &format!(
+                "cannot clean up the grace periods of {}; {}",
+                user_name, err
+            ),
+        );
+    }
+    PAM_IGNORE
+}
+
+#[derive(Debug)]
+pub enum GraceError {
+    Io(io::Error),
+    InvalidUserName(String),
+    NoSession,
+}
+
+impl From<io::Error> for GraceError {
+    fn from(err: io::Error) -> GraceError {
+        GraceError::Io(err)
+    }
+}
+
+impl fmt::Display for GraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GraceError::Io(ref err) => write!(f, "{}", err),
+            GraceError::InvalidUserName(ref user) => {
+                write!(f, "'{}' cannot be used as a file name", user)
+            }
+            GraceError::NoSession => write!(f, "the terminal session cannot be identified"),
+        }
+    }
+}
+
+/// A terminal session of a user, which is identified by its session leader and the TTY. The
+/// start time of the leader tells it apart from a later process that reuses its PID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub user_name: String,
+    pub session_id: i32,
+    pub leader_start: u64,
+    pub tty: String,
+}
+
+impl Session {
+    pub fn current(user_name: &str, tty: Option<&str>) -> Result<Session, GraceError> {
+        if !permissions::is_safe_file_name(user_name) {
+            return Err(GraceError::InvalidUserName(user_name.to_owned()));
+        }
+        let session_id = unsafe { libc::getsid(0) };
+        if session_id <= 0 {
+            return Err(GraceError::NoSession);
+        }
+        let leader_start = process_start_time(session_id).ok_or(GraceError::NoSession)?;
+        Ok(Session {
+            user_name: user_name.to_owned(),
+            session_id,
+            leader_start,
+            tty: tty.unwrap_or("").to_owned(),
+        })
+    }
+
+    fn record_path(&self) -> PathBuf {
+        let tty = match self.tty.trim_start_matches("/dev/") {
+            "" => "none".to_owned(),
+            tty => tty.replace('/', "_"),
+        };
+        PathBuf::from(GRACE_DIR)
+            .join(&self.user_name)
+            .join(format!("{}-{}", self.session_id, tty))
+    }
+
+    /// Returns the fingerprint of the key with which the user authenticated in this session, if
+    /// that was less than `grace_seconds` before `now`
+    pub fn granted_key(&self, grace_seconds: u64, now: u64) -> Result<Option<String>, GraceError> {
+        let contents = match fs::read_to_string(self.record_path()) {
+            Ok(contents) => contents,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(GraceError::Io(err)),
+        };
+        Ok(GraceRecord::parse(&contents)
+            .filter(|record| record.is_valid_for(self, grace_seconds, now))
+            .map(|record| record.key_fingerprint))
+    }
+
+    /// Remembers that the user authenticated in this session at `now` with the key of
+    /// `key_fingerprint`
+    pub fn record(&self, now: u64, key_fingerprint: &str) -> Result<(), GraceError> {
+        let path = self.record_path();
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(path.parent().unwrap_or_else(|| GRACE_DIR.as_ref()))?;
+        let record = GraceRecord {
+            authenticated: now,
+            leader_start: self.leader_start,
+            key_fingerprint: key_fingerprint.to_owned(),
+        };
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, record.to_toml())?;
+        fs::rename(&temporary_path, &path)?;
+        Ok(())
+    }
+
+    /// Ends the grace period of this session, e.g. after a failed authentication
+    pub fn invalidate(&self) -> Result<(), GraceError> {
+        match fs::remove_file(self.record_path()) {
+            Ok(()) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(GraceError::Io(err)),
+        }
+    }
+}
+
+/// Ends the grace periods of all sessions of `user_name`
+pub fn reset(user_name: &str) -> Result<(), GraceError> {
+    match fs::remove_dir_all(user_dir(user_name)?) {
+        Ok(()) => Ok(()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(GraceError::Io(err)),
+    }
+}
+
+fn remove_ended_sessions(user_name: &str) -> Result<(), GraceError> {
+    let entries = match fs::read_dir(user_dir(user_name)?) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(GraceError::Io(err)),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let session_id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('-').next())
+            .and_then(|id| id.parse().ok());
+        let record = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| GraceRecord::parse(&contents));
+        let alive = match (session_id, record) {
+            (Some(session_id), Some(record)) => {
+                process_start_time(session_id) == Some(record.leader_start)
+            }
+            _ => false,
+        };
+        if !alive {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn user_dir(user_name: &str) -> Result<PathBuf, GraceError> {
+    if !permissions::is_safe_file_name(user_name) {
+        return Err(GraceError::InvalidUserName(user_name.to_owned()));
+    }
+    Ok(PathBuf::from(GRACE_DIR).join(user_name))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GraceRecord {
+    /// Seconds since the UNIX epoch
+    authenticated: u64,
+    leader_start: u64,
+    /// The key that authenticated the user, which must still be acceptable during the grace period
+    key_fingerprint: String,
+}
+
+impl GraceRecord {
+    fn parse(contents: &str) -> Option<GraceRecord> {
+        let value = contents.parse::<Value>().ok()?;
+        let field = |name: &str| {
+            value
+                .get(name)
+                .and_then(Value::as_integer)
+                .and_then(|value| u64::try_from(value).ok())
+        };
+        Some(GraceRecord {
+            authenticated: field("authenticated")?,
+            leader_start: field("leader_start")?,
+            key_fingerprint: value.get("key_fingerprint")?.as_str()?.to_owned(),
+        })
+    }
+
+    fn to_toml(&self) -> String {
+        format!(
+            "authenticated = {}\nleader_start = {}\nkey_fingerprint = {}\n",
+            self.authenticated,
+            self.leader_start,
+            Value::from(self.key_fingerprint.as_str())
+        )
+    }
+
+    /// A record from the future is never valid, so that setting the clock back does not extend it
+    fn is_valid_for(&self, session: &Session, grace_seconds: u64, now: u64) -> bool {
+        self.leader_start == session.leader_start
+            && self.authenticated <= now
+            && now - self.authenticated < grace_seconds
+    }
+}
+
+/// Returns the start time of a process in clock ticks since boot, the 22nd field of
+/// `/proc/<pid>/stat`
+fn process_start_time(pid: i32) -> Option<u64> {
+    parse_start_time(&fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+fn parse_start_time(stat: &str) -> Option<u64> {
+    // The command name in the second field is in parentheses and may contain spaces
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(tty: &str) -> Session {
+        Session {
+            user_name: "alice".to_owned(),
+            session_id: 4242,
+            leader_start: 1234,
+            tty: tty.to_owned(),
+        }
+    }
+
+    #[test]
+    fn keys_records_by_user_session_and_tty() {
+        assert_eq!(
+            session("/dev/pts/3").record_path(),
+            PathBuf::from("/var/lib/pam_wsl_hello/grace/alice/4242-pts_3")
+        );
+        assert_eq!(
+            session("").record_path(),
+            PathBuf::from("/var/lib/pam_wsl_hello/grace/alice/4242-none")
+        );
+        assert!(Session::current("../root", None).is_err());
+        assert!(reset(".").is_err());
+    }
+
+    #[test]
+    fn expires_records() {
+        let record = GraceRecord {
+            authenticated: 1000,
+            leader_start: 1234,
+            key_fingerprint: "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU".to_owned(),
+        };
+        assert_eq!(GraceRecord::parse(&record.to_toml()), Some(record.clone()));
+        // Records without the key are from an older version
+        assert_eq!(
+            GraceRecord::parse("authenticated = 1000\nleader_start = 1234\n"),
+            None
+        );
+        assert!(record.is_valid_for(&session("pts/3"), 300, 1000));
+        assert!(record.is_valid_for(&session("pts/3"), 300, 1299));
+        assert!(!record.is_valid_for(&session("pts/3"), 300, 1300));
+        assert!(!record.is_valid_for(&session("pts/3"), 300, 999));
+
+        let mut reused = session("pts/3");
+        reused.leader_start = 5678;
+        assert!(!record.is_valid_for(&reused, 300, 1000));
+    }
+
+    #[test]
+    fn parses_process_start_times() {
+        let stat = "4242 (my (odd) shell) S 1 4242 4242 34816 4242 4194560 1201 0 0 0 1 0 0 0 \
+                    20 0 1 0 987654 8994816 1280 18446744073709551615";
+        assert_eq!(parse_start_time(stat), Some(987654));
+        assert_eq!(parse_start_time("garbage"), None);
+        assert!(process_start_time(unsafe { libc::getpid() }).is_some());
+    }
+}
@@ -0,0 +1,138 @@
+use permissions::{self, PermissionError};
+use spawn;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+/// What a hook is run for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Success,
+    Failure,
+    /// Windows Hello did not answer within `hello_timeout`
+    Timeout,
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            HookEvent::Success => "success",
+            HookEvent::Failure => "failure",
+            HookEvent::Timeout => "timeout",
+        })
+    }
+}
+
+/// The outcome of an authentication as the hooks see it
+#[derive(Debug, Default)]
+pub struct HookContext {
+    pub user: String,
+    pub service: Option<String>,
+    pub tty: Option<String>,
+    pub rhost: Option<String>,
+    pub ruser: Option<String>,
+    pub key_fingerprint: Option<String>,
+    pub failure_reason: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum HookError {
+    UntrustedCommand(PermissionError),
+    UnknownUser(String),
+    Launch(io::Error),
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HookError::UntrustedCommand(ref err) => write!(f, "{}", err),
+            HookError::UnknownUser(ref user) => write!(f, "the user '{}' does not exist", user),
+            HookError::Launch(ref err) => write!(f, "cannot launch the hook; {}", err),
+        }
+    }
+}
+
+/// Returns the environment of a hook, named like that of pam_exec where it overlaps
+fn environment(event: HookEvent, context: &HookContext) -> Vec<(&'static str, String)> {
+    let mut environment = vec![
+        ("PATH", "/usr/sbin:/usr/bin:/sbin:/bin".to_owned()),
+        ("PAM_TYPE", "auth".to_owned()),
+        ("PAM_USER", context.user.clone()),
+        ("WSL_HELLO_EVENT", event.to_string()),
+    ];
+    let optional = [
+        ("PAM_SERVICE", &context.service),
+        ("PAM_TTY", &context.tty),
+        ("PAM_RHOST", &context.rhost),
+        ("PAM_RUSER", &context.ruser),
+        ("WSL_HELLO_KEY_FINGERPRINT", &context.key_fingerprint),
+        ("WSL_HELLO_FAILURE_REASON", &context.failure_reason),
+    ];
+    for &(name, value) in &optional {
+        if let Some(ref value) = *value {
+            environment.push((name, value.clone()));
+        }
+    }
+    environment
+}
+
+/// Starts the root-owned `command` for `event` as `user_name` in a process group of its own and
+/// returns without waiting for it. A detached supervisor kills the group after `timeout`, or once
+/// the hook exits, so nothing that the hook starts is left running.
+pub fn run_hook(
+    command: &str,
+    event: HookEvent,
+    context: &HookContext,
+    user_name: &str,
+    timeout: Duration,
+) -> Result<(), HookError> {
+    let command =
+        permissions::check_root_owned(Path::new(command)).map_err(HookError::UntrustedCommand)?;
+    let (uid, gid) = spawn::lookup_user(user_name)
+        .ok_or_else(|| HookError::UnknownUser(user_name.to_owned()))?;
+    let invalid = |_| HookError::Launch(io::ErrorKind::InvalidInput.into());
+    let program = CString::new(command.as_os_str().as_bytes()).map_err(invalid)?;
+    let args = [CString::new(event.to_string()).map_err(invalid)?];
+    let env = environment(event, context)
+        .into_iter()
+        .map(|(name, value)| CString::new(format!("{}={}", name, value)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    spawn::spawn_supervised(&program, &args, &env, (uid, gid), timeout).map_err(HookError::Launch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_the_outcome_in_the_environment() {
+        let context = HookContext {
+            user: "alice".to_owned(),
+            service: Some("sudo".to_owned()),
+            tty: Some("/dev/pts/3".to_owned()),
+            failure_reason: Some("Windows Hello failed".to_owned()),
+            ..HookContext::default()
+        };
+        let environment = environment(HookEvent::Failure, &context);
+        let get = |name: &str| {
+            environment
+                .iter()
+                .find(|&&(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(get("PAM_USER"), Some("alice"));
+        assert_eq!(get("PAM_SERVICE"), Some("sudo"));
+        assert_eq!(get("PAM_TTY"), Some("/dev/pts/3"));
+        assert_eq!(get("WSL_HELLO_EVENT"), Some("failure"));
+        assert_eq!(
+            get("WSL_HELLO_FAILURE_REASON"),
+            Some("Windows Hello failed")
+        );
+        assert_eq!(get("PAM_RHOST"), None);
+        assert_eq!(get("WSL_HELLO_KEY_FINGERPRINT"), None);
+    }
+}
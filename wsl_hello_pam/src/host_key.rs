@@ -0,0 +1,115 @@
+use openssl::base64;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use permissions::{self, PermissionError};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, prelude::*};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+
+/// The private key with which the module signs its challenges for the bridge, whose public half
+/// is registered with the bridge as the key of this distro
+pub const HOST_KEY_PATH: &str = "/etc/pam_wsl_hello/host_key.pem";
+
+const HOST_KEY_BITS: u32 = 2048;
+
+#[derive(Debug)]
+pub enum HostKeyError {
+    Io(io::Error),
+    Untrusted(PermissionError),
+    OpenSslError(ErrorStack),
+}
+
+impl From<io::Error> for HostKeyError {
+    fn from(err: io::Error) -> HostKeyError {
+        HostKeyError::Io(err)
+    }
+}
+
+impl From<ErrorStack> for HostKeyError {
+    fn from(err: ErrorStack) -> HostKeyError {
+        HostKeyError::OpenSslError(err)
+    }
+}
+
+impl fmt::Display for HostKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HostKeyError::Io(ref err) => write!(f, "{}", err),
+            HostKeyError::Untrusted(ref err) => write!(f, "{}", err),
+            HostKeyError::OpenSslError(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Loads the host key, or returns `None` if the distro has none, in which case requests go to the
+/// bridge unsigned and are refused. The key must be private to root.
+pub fn load() -> Result<Option<PKey<Private>>, HostKeyError> {
+    load_from(Path::new(HOST_KEY_PATH))
+}
+
+fn load_from(path: &Path) -> Result<Option<PKey<Private>>, HostKeyError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let path = permissions::check_root_owned(path).map_err(HostKeyError::Untrusted)?;
+    if fs::metadata(&path)?.mode() & 0o077 != 0 {
+        return Err(HostKeyError::Untrusted(PermissionError::Untrusted {
+            path,
+            reason: "it is accessible by the group or others".to_owned(),
+        }));
+    }
+    Ok(Some(PKey::private_key_from_pem(&fs::read(path)?)?))
+}
+
+/// Loads the host key, generating it first if the distro has none yet
+pub fn load_or_generate() -> Result<PKey<Private>, HostKeyError> {
+    if let Some(key) = load()? {
+        return Ok(key);
+    }
+    let key = PKey::from_rsa(Rsa::generate(HOST_KEY_BITS)?)?;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(HOST_KEY_PATH)?
+        .write_all(&key.private_key_to_pem_pkcs8()?)?;
+    Ok(key)
+}
+
+/// Signs `challenge` for the bridge, which it sends base64-encoded along with the challenge
+pub fn sign(key: &PKey<Private>, challenge: &str) -> Result<String, ErrorStack> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.update(challenge.as_bytes())?;
+    Ok(base64::encode_block(&signer.sign_to_vec()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::sign::Verifier;
+
+    #[test]
+    fn signs_challenges_with_a_private_key() {
+        // The temporary directory is writable by everyone
+        let file = std::env::temp_dir().join("pam_wsl_hello_host_key_test");
+        let key = PKey::from_rsa(Rsa::generate(HOST_KEY_BITS).unwrap()).unwrap();
+        fs::write(&file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        match load_from(&file) {
+            Err(HostKeyError::Untrusted(_)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        fs::remove_file(&file).unwrap();
+        assert!(load_from(&file).unwrap().is_none());
+
+        let challenge = "wsl-hello-challenge/1 nonce=1234 issued=0 user=alice distro=Ubuntu";
+        let signature = base64::decode_block(&sign(&key, challenge).unwrap()).unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key).unwrap();
+        verifier.update(challenge.as_bytes()).unwrap();
+        assert!(verifier.verify(&signature).unwrap());
+    }
+}
@@ -0,0 +1,98 @@
+use libc::{self, c_int};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+
+/// The last SIGINT or SIGTERM that the application received while an `Interruption` was active
+static INTERRUPTED_BY: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn record_interruption(signal: c_int) {
+    INTERRUPTED_BY.store(signal, Ordering::SeqCst);
+}
+
+/// Catches SIGINT and SIGTERM while the module waits for Windows Hello, so that it can stop the
+/// bridge and return instead of leaving it behind, and restores the handlers of the application
+/// when dropped. Signal handlers belong to the whole process, so an `Interruption` is only made
+/// on the thread that the application called the module on, once per call, and the threads that
+/// the module starts meanwhile block the signals with `spawn_worker`.
+pub struct Interruption {
+    previous: Vec<(c_int, libc::sigaction)>,
+}
+
+impl Interruption {
+    pub fn catch() -> Interruption {
+        INTERRUPTED_BY.store(0, Ordering::SeqCst);
+        let mut previous = Vec::new();
+        for &signal in &[libc::SIGINT, libc::SIGTERM] {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = record_interruption as extern "C" fn(c_int) as usize;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut old: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(signal, &action, &mut old) == 0 {
+                    previous.push((signal, old));
+                }
+            }
+        }
+        Interruption { previous }
+    }
+}
+
+impl Drop for Interruption {
+    fn drop(&mut self) {
+        for &(signal, ref old) in &self.previous {
+            unsafe { libc::sigaction(signal, old, ptr::null_mut()) };
+        }
+        INTERRUPTED_BY.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Returns the signal that interrupted the module, if an `Interruption` caught one
+pub fn interrupted() -> Option<c_int> {
+    match INTERRUPTED_BY.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Starts a thread with SIGINT and SIGTERM blocked, so that they are delivered to the thread of
+/// the application rather than to it. The signals are blocked before the thread starts, which
+/// inherits the signal mask, so that none can reach it before it could block them itself.
+pub fn spawn_worker<F, T>(f: F) -> thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let mut previous: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        let mut blocked: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut blocked);
+        libc::sigaddset(&mut blocked, libc::SIGINT);
+        libc::sigaddset(&mut blocked, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &blocked, &mut previous);
+    }
+    let worker = thread::spawn(f);
+    unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &previous, ptr::null_mut()) };
+    worker
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(signal: c_int) -> bool {
+        unsafe {
+            let mut mask: libc::sigset_t = std::mem::zeroed();
+            libc::pthread_sigmask(libc::SIG_BLOCK, ptr::null(), &mut mask);
+            libc::sigismember(&mask, signal) == 1
+        }
+    }
+
+    #[test]
+    fn blocks_signals_only_in_workers() {
+        let worker = spawn_worker(|| (blocks(libc::SIGINT), blocks(libc::SIGTERM)));
+        assert_eq!(worker.join().unwrap(), (true, true));
+        assert!(!blocks(libc::SIGINT));
+        assert!(!blocks(libc::SIGTERM));
+    }
+}
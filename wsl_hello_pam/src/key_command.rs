@@ -0,0 +1,276 @@
+use keys;
+use libc;
+use openssl::pkey::{PKey, Public};
+use permissions::{self, PermissionError};
+use spawn;
+use std::fmt;
+use std::io::{self, prelude::*};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The maximum size of the output of the command. Larger output is an error rather than
+/// being truncated, which could cut a key in the middle.
+pub const MAX_OUTPUT_SIZE: u64 = 64 * 1024;
+
+/// How the keys printed by `authorized_keys_command` combine with the key files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCommandMode {
+    /// The keys are accepted in addition to `public_keys` or `authorized_keys`
+    Add,
+    /// Only the keys are accepted, and the key files are not read
+    Replace,
+}
+
+#[derive(Debug)]
+pub enum KeyCommandError {
+    Io(io::Error),
+    UntrustedCommand(PermissionError),
+    UnknownUser(String),
+    Launch(io::Error),
+    Timeout(Duration),
+    OutputTooLarge,
+    Failed(ExitStatus),
+    InvalidKey { line: usize, reason: String },
+}
+
+impl From<io::Error> for KeyCommandError {
+    fn from(err: io::Error) -> KeyCommandError {
+        KeyCommandError::Io(err)
+    }
+}
+
+impl fmt::Display for KeyCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyCommandError::Io(ref err) => write!(f, "{}", err),
+            KeyCommandError::UntrustedCommand(ref err) => write!(f, "{}", err),
+            KeyCommandError::UnknownUser(ref user) => {
+                write!(f, "the user '{}' does not exist", user)
+            }
+            KeyCommandError::Launch(ref err) => write!(f, "cannot launch the command; {}", err),
+            KeyCommandError::Timeout(timeout) => {
+                write!(
+                    f,
+                    "the command did not finish in {} seconds",
+                    timeout.as_secs()
+                )
+            }
+            KeyCommandError::OutputTooLarge => write!(
+                f,
+                "the output of the command exceeds {} bytes",
+                MAX_OUTPUT_SIZE
+            ),
+            KeyCommandError::Failed(status) => write!(f, "the command failed; {}", status),
+            KeyCommandError::InvalidKey { line, ref reason } => {
+                write!(
+                    f,
+                    "line {} of the output is not a valid key; {}",
+                    line, reason
+                )
+            }
+        }
+    }
+}
+
+/// Runs `command user_name key_name` as `command_user` like sshd's `AuthorizedKeysCommand` and
+/// returns the keys that it prints, one per line in any encoding that `keys::parse_public_key`
+/// understands
+pub fn lookup_keys(
+    command: &str,
+    command_user: &str,
+    user_name: &str,
+    key_name: &str,
+    timeout: Duration,
+) -> Result<Vec<PKey<Public>>, KeyCommandError> {
+    let command = permissions::check_root_owned(Path::new(command))
+        .map_err(KeyCommandError::UntrustedCommand)?;
+    let ids = spawn::lookup_user(command_user)
+        .ok_or_else(|| KeyCommandError::UnknownUser(command_user.to_owned()))?;
+    let output = run(&command, &[user_name, key_name], ids, timeout)?;
+    parse_output(&output)
+}
+
+fn run(
+    command: &Path,
+    args: &[&str],
+    (uid, gid): (libc::uid_t, libc::gid_t),
+    timeout: Duration,
+) -> Result<Vec<u8>, KeyCommandError> {
+    let mut command = Command::new(command);
+    command
+        .args(args)
+        .env_clear()
+        .env("PATH", "/usr/sbin:/usr/bin:/sbin:/bin")
+        .current_dir("/")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    spawn::run_as(&mut command, uid, gid);
+    let mut child = command.spawn().map_err(KeyCommandError::Launch)?;
+
+    let result = wait_for_output(&mut child, Instant::now() + timeout, timeout);
+    // Nothing that the command started in the background outlives it
+    spawn::kill_group(child.id());
+    let status = child.wait()?;
+    let output = result?;
+    if !status.success() {
+        return Err(KeyCommandError::Failed(status));
+    }
+    Ok(output)
+}
+
+/// Reads the output of `child` until it closes its standard output and exits, and leaves it
+/// unreaped so that its process group can still be killed
+fn wait_for_output(
+    child: &mut Child,
+    deadline: Instant,
+    timeout: Duration,
+) -> Result<Vec<u8>, KeyCommandError> {
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut output = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut poll_fd = libc::pollfd {
+            fd: stdout.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, remaining.as_millis() as libc::c_int) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+        if ready == 0 {
+            // A background process of the command may hold the pipe open
+            return Err(KeyCommandError::Timeout(timeout));
+        }
+        match stdout.read(&mut buffer)? {
+            0 => break,
+            length => output.extend_from_slice(&buffer[..length]),
+        }
+        if output.len() as u64 > MAX_OUTPUT_SIZE {
+            return Err(KeyCommandError::OutputTooLarge);
+        }
+    }
+
+    loop {
+        // WNOWAIT leaves the command a zombie, which keeps its process group ID from being reused
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, child.id(), &mut info, options) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if unsafe { info.si_pid() } != 0 {
+            return Ok(output);
+        }
+        if Instant::now() >= deadline {
+            return Err(KeyCommandError::Timeout(timeout));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn parse_output(output: &[u8]) -> Result<Vec<PKey<Public>>, KeyCommandError> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            keys::parse_public_key(line.as_bytes()).map_err(|err| KeyCommandError::InvalidKey {
+                line: line_number,
+                reason: err.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = include_str!("../tests/fixtures/attestation/hello_key.pem");
+
+    fn sh(script: &str) -> Result<Vec<u8>, KeyCommandError> {
+        run(
+            Path::new("/bin/sh"),
+            &["-c", script],
+            unsafe { (libc::geteuid(), libc::getegid()) },
+            Duration::from_secs(2),
+        )
+    }
+
+    #[test]
+    fn parses_keys_from_output() {
+        let body: String = KEY
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let output = sh(&format!(
+            "echo '# from LDAP'; echo; echo {}; echo {}",
+            body, body
+        ))
+        .unwrap();
+        assert_eq!(parse_output(&output).unwrap().len(), 2);
+        match parse_output(b"AAAA\n") {
+            Err(KeyCommandError::InvalidKey { line: 1, .. }) => {}
+            other => panic!("unexpected result: {:?}", other.map(|keys| keys.len())),
+        }
+    }
+
+    #[test]
+    fn limits_the_command() {
+        match sh("sleep 10") {
+            Err(KeyCommandError::Timeout(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match sh("sleep 10 & echo started") {
+            Err(KeyCommandError::Timeout(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match sh("yes") {
+            Err(KeyCommandError::OutputTooLarge) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match sh("exit 1") {
+            Err(KeyCommandError::Failed(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn kills_what_the_command_leaves_behind() {
+        let output = sh("sleep 10 >/dev/null & echo $!").unwrap();
+        let stat = format!("/proc/{}/stat", String::from_utf8(output).unwrap().trim());
+        // SIGKILL takes effect asynchronously, and the killed process may linger as a zombie
+        // until init reaps it
+        let dead = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            std::fs::read_to_string(&stat).map_or(true, |stat| stat.contains(") Z "))
+        });
+        assert!(dead);
+    }
+
+    #[test]
+    fn runs_as_the_command_user() {
+        let (uid, gid) = spawn::lookup_user("nobody").unwrap();
+        let output = run(
+            Path::new("/bin/sh"),
+            &["-c", "id -u; id -G"],
+            (uid, gid),
+            Duration::from_secs(2),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("{}\n{}\n", uid, gid)
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use date::Date;
+use keys;
+use permissions::{self, PermissionError};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use toml::{self, Value};
+
+/// The dates of the key enrolled for a user, kept in `pam_wsl_hello_<user>.meta` next to the
+/// public key, e.g.
+///
+/// ```toml
+/// created = "2026-01-15"
+/// expires = "2027-01-15"
+/// key_name = "pam_wsl_hello_alice_3f2a9c1e"
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyMetadata {
+    pub created: Option<Date>,
+    /// The first day on which the key is no longer accepted
+    pub expires: Option<Date>,
+    /// The name of the Windows Hello credential, which differs from `pam_wsl_hello_<user>` once
+    /// the key has been rotated. Its public key is `<key_name>.pem`.
+    pub key_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum KeyMetadataError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    InvalidDate(String),
+    InvalidValueType(String),
+    Untrusted(PermissionError),
+}
+
+impl From<io::Error> for KeyMetadataError {
+    fn from(err: io::Error) -> KeyMetadataError {
+        KeyMetadataError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for KeyMetadataError {
+    fn from(err: toml::de::Error) -> KeyMetadataError {
+        KeyMetadataError::Toml(err)
+    }
+}
+
+impl fmt::Display for KeyMetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyMetadataError::Io(ref err) => write!(f, "{}", err),
+            KeyMetadataError::Toml(ref err) => write!(f, "TOML format error; {}", err),
+            KeyMetadataError::InvalidDate(ref field) => {
+                write!(f, "field: '{}' is not a YYYY-MM-DD date", field)
+            }
+            KeyMetadataError::InvalidValueType(ref field) => {
+                write!(f, "field: '{}' has an invalid value type", field)
+            }
+            KeyMetadataError::Untrusted(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl KeyMetadata {
+    /// Loads the metadata of the key enrolled for `user_name`. A key without a metadata file has
+    /// no dates.
+    pub fn load(user_name: &str) -> Result<KeyMetadata, KeyMetadataError> {
+        match permissions::read_root_owned(&KeyMetadata::path(user_name)) {
+            Ok(contents) => KeyMetadata::parse(&String::from_utf8_lossy(&contents)),
+            Err(PermissionError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(KeyMetadata::default())
+            }
+            Err(PermissionError::Io(err)) => Err(KeyMetadataError::Io(err)),
+            Err(err) => Err(KeyMetadataError::Untrusted(err)),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<KeyMetadata, KeyMetadataError> {
+        let value = contents.parse::<Value>()?;
+        Ok(KeyMetadata {
+            created: optional_date(&value, "created")?,
+            expires: optional_date(&value, "expires")?,
+            key_name: match value.get("key_name") {
+                Some(key_name) => Some(
+                    key_name
+                        .as_str()
+                        .ok_or_else(|| KeyMetadataError::InvalidValueType("key_name".to_owned()))?
+                        .to_owned(),
+                ),
+                None => None,
+            },
+        })
+    }
+
+    pub fn path(user_name: &str) -> PathBuf {
+        keys::key_file_path(&keys::credential_key_name(user_name), "meta")
+    }
+
+    /// Returns the name of the Windows Hello credential enrolled for `user_name`
+    pub fn credential_key_name(&self, user_name: &str) -> String {
+        self.key_name
+            .clone()
+            .unwrap_or_else(|| keys::credential_key_name(user_name))
+    }
+
+    /// Formats the metadata in the format that `parse` reads
+    pub fn to_toml(&self) -> String {
+        let mut contents = String::new();
+        if let Some(created) = self.created {
+            contents.push_str(&format!("created = \"{}\"\n", created));
+        }
+        if let Some(expires) = self.expires {
+            contents.push_str(&format!("expires = \"{}\"\n", expires));
+        }
+        if let Some(ref key_name) = self.key_name {
+            contents.push_str(&format!("key_name = {}\n", Value::String(key_name.clone())));
+        }
+        contents
+    }
+
+    /// Returns the first day on which the key is no longer accepted, which is the earlier of
+    /// `expires` and the day on which the key becomes older than `max_age_days`
+    pub fn expiry(&self, max_age_days: Option<u32>) -> Option<Date> {
+        let aged = match (self.created, max_age_days) {
+            (Some(created), Some(max_age_days)) => Some(created.add_days(i64::from(max_age_days))),
+            _ => None,
+        };
+        match (self.expires, aged) {
+            (Some(expires), Some(aged)) => Some(expires.min(aged)),
+            (expires, aged) => expires.or(aged),
+        }
+    }
+}
+
+fn optional_date(value: &Value, field: &str) -> Result<Option<Date>, KeyMetadataError> {
+    match value.get(field) {
+        Some(date) => date
+            .as_str()
+            .and_then(Date::parse)
+            .map(Some)
+            .ok_or_else(|| KeyMetadataError::InvalidDate(field.to_owned())),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> Option<Date> {
+        Some(Date::parse(date).unwrap())
+    }
+
+    #[test]
+    fn computes_expiry() {
+        let metadata = KeyMetadata::parse("created = \"2026-01-15\"\n").unwrap();
+        assert_eq!(metadata.expiry(None), None);
+        assert_eq!(metadata.expiry(Some(365)), date("2027-01-15"));
+
+        let metadata =
+            KeyMetadata::parse("created = \"2026-01-15\"\nexpires = \"2026-06-01\"\n").unwrap();
+        assert_eq!(metadata.expiry(None), date("2026-06-01"));
+        assert_eq!(metadata.expiry(Some(30)), date("2026-02-14"));
+        assert_eq!(metadata.expiry(Some(365)), date("2026-06-01"));
+    }
+
+    #[test]
+    fn round_trips() {
+        let metadata = KeyMetadata {
+            created: date("2026-01-15"),
+            expires: None,
+            key_name: Some("pam_wsl_hello_alice_3f2a9c1e".to_owned()),
+        };
+        assert_eq!(KeyMetadata::parse(&metadata.to_toml()).unwrap(), metadata);
+        assert_eq!(
+            metadata.credential_key_name("alice"),
+            "pam_wsl_hello_alice_3f2a9c1e"
+        );
+        assert_eq!(
+            KeyMetadata::default().credential_key_name("alice"),
+            "pam_wsl_hello_alice"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        for contents in &["created = 2026", "expires = \"2026-02-30\""] {
+            match KeyMetadata::parse(contents) {
+                Err(KeyMetadataError::InvalidDate(_)) => {}
+                other => panic!("unexpected result for '{}': {:?}", contents, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use openssl::base64;
+use openssl::bn::BigNum;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use serde_json;
+use std::fmt;
+use std::path::PathBuf;
+
+pub const PUBLIC_KEYS_DIR: &str = "/etc/pam_wsl_hello/public_keys";
+
+/// Returns the name of the Windows Hello credential that is enrolled for `user_name`
+pub fn credential_key_name(user_name: &str) -> String {
+    format!("pam_wsl_hello_{}", user_name)
+}
+
+/// Returns the path of `<key_name>.<extension>` in the public key directory
+pub fn key_file_path(key_name: &str, extension: &str) -> PathBuf {
+    PathBuf::from(format!("{}/{}.{}", PUBLIC_KEYS_DIR, key_name, extension))
+}
+
+/// Returns the fingerprint of `key` in the same format as `ssh-keygen -l`, i.e.
+/// `SHA256:` followed by the unpadded base64 of the SHA-256 digest of its DER encoding
+pub fn fingerprint(key: &PKey<Public>) -> Result<String, ErrorStack> {
+    let der = key.public_key_to_der()?;
+    let digest = hash(MessageDigest::sha256(), &der)?;
+    Ok(format!(
+        "SHA256:{}",
+        base64::encode_block(&digest).trim_end_matches('=')
+    ))
+}
+
+/// The encodings of public keys that `parse_public_key` understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// `-----BEGIN PUBLIC KEY-----` or `-----BEGIN RSA PUBLIC KEY-----`
+    Pem,
+    /// DER of a SubjectPublicKeyInfo or of a PKCS#1 RSAPublicKey
+    Der,
+    /// `ssh-rsa AAAA... comment`
+    OpenSsh,
+    /// `{"kty": "RSA", "n": ..., "e": ...}`
+    Jwk,
+    /// `BCRYPT_RSAPUBLIC_BLOB`, i.e. `CryptographicPublicKeyBlobType::BCryptPublicKey`
+    BcryptRsaPublicBlob,
+    /// CAPI `PUBLICKEYBLOB`, i.e. `CryptographicPublicKeyBlobType::Capi1PublicKey`
+    CapiPublicKeyBlob,
+}
+
+impl fmt::Display for KeyFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            KeyFormat::Pem => "PEM",
+            KeyFormat::Der => "DER",
+            KeyFormat::OpenSsh => "OpenSSH",
+            KeyFormat::Jwk => "JWK",
+            KeyFormat::BcryptRsaPublicBlob => "BCRYPT_RSAPUBLIC_BLOB",
+            KeyFormat::CapiPublicKeyBlob => "CAPI PUBLICKEYBLOB",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidKey {
+    /// The format that the key looked like, if any
+    pub format: Option<KeyFormat>,
+    /// Whether the key was base64-encoded on top of `format`
+    pub base64: bool,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.format {
+            Some(format) => write!(
+                f,
+                "it looks like {}{} but {}",
+                if self.base64 { "base64-encoded " } else { "" },
+                format,
+                self.reason
+            ),
+            None => write!(f, "{}", self.reason),
+        }
+    }
+}
+
+impl InvalidKey {
+    fn new<R: fmt::Display>(format: KeyFormat, reason: R) -> InvalidKey {
+        InvalidKey {
+            format: Some(format),
+            base64: false,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Parses a public key in any of the `KeyFormat`s, detecting the format automatically.
+/// Binary formats may also be base64-encoded.
+pub fn parse_public_key(data: &[u8]) -> Result<PKey<Public>, InvalidKey> {
+    let text = match ::std::str::from_utf8(data) {
+        Ok(text) => text.trim(),
+        Err(_) => return parse_binary_public_key(data),
+    };
+
+    if text.starts_with("-----BEGIN RSA PUBLIC KEY-----") {
+        Rsa::public_key_from_pem_pkcs1(text.as_bytes())
+            .and_then(PKey::from_rsa)
+            .map_err(|err| InvalidKey::new(KeyFormat::Pem, err))
+    } else if text.starts_with("-----BEGIN") {
+        PKey::public_key_from_pem(text.as_bytes())
+            .map_err(|err| InvalidKey::new(KeyFormat::Pem, err))
+    } else if text.starts_with("ssh-") {
+        parse_openssh(text).map_err(|reason| InvalidKey::new(KeyFormat::OpenSsh, reason))
+    } else if text.starts_with('{') {
+        parse_jwk(text).map_err(|reason| InvalidKey::new(KeyFormat::Jwk, reason))
+    } else if !text.is_empty() && text.bytes().all(is_base64_or_space) {
+        let text: String = text.split_whitespace().collect();
+        match base64::decode_block(&text) {
+            Ok(decoded) => parse_binary_public_key(&decoded).map_err(|err| InvalidKey {
+                base64: err.format.is_some(),
+                ..err
+            }),
+            Err(_) => parse_binary_public_key(data),
+        }
+    } else {
+        parse_binary_public_key(data)
+    }
+}
+
+fn parse_binary_public_key(data: &[u8]) -> Result<PKey<Public>, InvalidKey> {
+    if data.starts_with(b"RSA1") {
+        parse_bcrypt_blob(data)
+            .map_err(|reason| InvalidKey::new(KeyFormat::BcryptRsaPublicBlob, reason))
+    } else if data.starts_with(&[PUBLICKEYBLOB, CUR_BLOB_VERSION]) {
+        parse_capi_blob(data)
+            .map_err(|reason| InvalidKey::new(KeyFormat::CapiPublicKeyBlob, reason))
+    } else if data.starts_with(&[0x30]) {
+        PKey::public_key_from_der(data)
+            .or_else(|_| Rsa::public_key_from_der_pkcs1(data).and_then(PKey::from_rsa))
+            .map_err(|err| InvalidKey::new(KeyFormat::Der, err))
+    } else {
+        Err(InvalidKey {
+            format: None,
+            base64: false,
+            reason: "the public key is in an unrecognized format; PEM, DER, OpenSSH, JWK, \
+                     BCRYPT_RSAPUBLIC_BLOB and CAPI PUBLICKEYBLOB are supported"
+                .to_owned(),
+        })
+    }
+}
+
+fn is_base64_or_space(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'+' || c == b'/' || c == b'=' || c.is_ascii_whitespace()
+}
+
+fn rsa_key(n: &[u8], e: &[u8]) -> Result<PKey<Public>, String> {
+    let n = BigNum::from_slice(n).map_err(|err| err.to_string())?;
+    let e = BigNum::from_slice(e).map_err(|err| err.to_string())?;
+    Rsa::from_public_components(n, e)
+        .and_then(PKey::from_rsa)
+        .map_err(|err| err.to_string())
+}
+
+fn parse_openssh(text: &str) -> Result<PKey<Public>, String> {
+    let mut fields = text.split_whitespace();
+    let key_type = fields.next().unwrap_or("");
+    if key_type != "ssh-rsa" {
+        return Err(format!("'{}' keys are not supported", key_type));
+    }
+    let blob = fields
+        .next()
+        .and_then(|blob| base64::decode_block(blob).ok())
+        .ok_or_else(|| "the key is not base64".to_owned())?;
+
+    let mut rest = &blob[..];
+    let mut next_string = || -> Result<&[u8], String> {
+        let truncated = || "the key is truncated".to_owned();
+        let length = rest.get(..4).ok_or_else(truncated)?;
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        let string = rest.get(4..4 + length).ok_or_else(truncated)?;
+        rest = &rest[4 + length..];
+        Ok(string)
+    };
+    if next_string()? != b"ssh-rsa" {
+        return Err("the key type in the blob is not 'ssh-rsa'".to_owned());
+    }
+    let e = next_string()?;
+    let n = next_string()?;
+    rsa_key(n, e)
+}
+
+fn parse_jwk(text: &str) -> Result<PKey<Public>, String> {
+    let jwk: serde_json::Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+    let member = |name: &str| {
+        jwk.get(name)
+            .and_then(|value| value.as_str())
+            .ok_or_else(|| format!("'{}' is missing", name))
+    };
+    if member("kty")? != "RSA" {
+        return Err("only 'RSA' keys are supported".to_owned());
+    }
+    let decode = |name: &str| {
+        let value = member(name)?;
+        let mut value = value.replace('-', "+").replace('_', "/");
+        while value.len() % 4 != 0 {
+            value.push('=');
+        }
+        base64::decode_block(&value).map_err(|_| format!("'{}' is not base64url", name))
+    };
+    rsa_key(&decode("n")?, &decode("e")?)
+}
+
+const BCRYPT_RSAPUBLIC_MAGIC: u32 = 0x3141_5352; // "RSA1"
+const PUBLICKEYBLOB: u8 = 0x06;
+const CUR_BLOB_VERSION: u8 = 0x02;
+
+fn read_u32_le(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| "the blob is truncated".to_owned())
+}
+
+/// `BCRYPT_RSAKEY_BLOB` followed by the big-endian exponent and modulus
+fn parse_bcrypt_blob(data: &[u8]) -> Result<PKey<Public>, String> {
+    if read_u32_le(data, 0)? != BCRYPT_RSAPUBLIC_MAGIC {
+        return Err("the magic is not BCRYPT_RSAPUBLIC_MAGIC".to_owned());
+    }
+    let exponent_length = read_u32_le(data, 8)? as usize;
+    let modulus_length = read_u32_le(data, 12)? as usize;
+    let header_length = 24;
+    let e = data
+        .get(header_length..header_length + exponent_length)
+        .ok_or_else(|| "the blob is truncated".to_owned())?;
+    let n = data
+        .get(header_length + exponent_length..header_length + exponent_length + modulus_length)
+        .ok_or_else(|| "the blob is truncated".to_owned())?;
+    rsa_key(n, e)
+}
+
+/// `BLOBHEADER` and `RSAPUBKEY` followed by the little-endian modulus
+fn parse_capi_blob(data: &[u8]) -> Result<PKey<Public>, String> {
+    if read_u32_le(data, 8)? != BCRYPT_RSAPUBLIC_MAGIC {
+        return Err("the magic is not 'RSA1'".to_owned());
+    }
+    let bit_length = read_u32_le(data, 12)? as usize;
+    let e = read_u32_le(data, 16)?.to_be_bytes();
+    let header_length = 20;
+    let mut n = data
+        .get(header_length..header_length + bit_length.div_ceil(8))
+        .ok_or_else(|| "the blob is truncated".to_owned())?
+        .to_vec();
+    n.reverse();
+    rsa_key(&n, &e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEM: &[u8] = include_bytes!("../tests/fixtures/attestation/hello_key.pem");
+
+    fn components() -> (Vec<u8>, Vec<u8>) {
+        let rsa = PKey::public_key_from_pem(PEM).unwrap().rsa().unwrap();
+        (rsa.n().to_vec(), rsa.e().to_vec())
+    }
+
+    fn assert_same_key(data: &[u8]) {
+        let expected = fingerprint(&PKey::public_key_from_pem(PEM).unwrap()).unwrap();
+        let key = parse_public_key(data).unwrap_or_else(|err| panic!("{}", err));
+        assert_eq!(fingerprint(&key).unwrap(), expected);
+    }
+
+    #[test]
+    fn parses_pem_and_der() {
+        let key = PKey::public_key_from_pem(PEM).unwrap();
+        let rsa = key.rsa().unwrap();
+        assert_same_key(PEM);
+        assert_same_key(&rsa.public_key_to_pem_pkcs1().unwrap());
+        assert_same_key(&key.public_key_to_der().unwrap());
+        assert_same_key(&rsa.public_key_to_der_pkcs1().unwrap());
+        assert_same_key(base64::encode_block(&key.public_key_to_der().unwrap()).as_bytes());
+    }
+
+    #[test]
+    fn parses_openssh() {
+        let (n, e) = components();
+        let mut blob = Vec::new();
+        for string in &[&b"ssh-rsa"[..], &e, &[&[0u8][..], &n].concat()] {
+            blob.extend(&(string.len() as u32).to_be_bytes());
+            blob.extend(*string);
+        }
+        assert_same_key(
+            format!("ssh-rsa {} alice@laptop\n", base64::encode_block(&blob)).as_bytes(),
+        );
+    }
+
+    #[test]
+    fn parses_jwk() {
+        let (n, e) = components();
+        let base64url = |data: &[u8]| {
+            base64::encode_block(data)
+                .trim_end_matches('=')
+                .replace('+', "-")
+                .replace('/', "_")
+        };
+        assert_same_key(
+            format!(
+                r#"{{"kty": "RSA", "n": "{}", "e": "{}", "alg": "RS256"}}"#,
+                base64url(&n),
+                base64url(&e)
+            )
+            .as_bytes(),
+        );
+    }
+
+    #[test]
+    fn parses_windows_blobs() {
+        let (n, e) = components();
+        let mut bcrypt = b"RSA1".to_vec();
+        for field in &[n.len() as u32 * 8, e.len() as u32, n.len() as u32, 0, 0] {
+            bcrypt.extend(&field.to_le_bytes());
+        }
+        bcrypt.extend(&e);
+        bcrypt.extend(&n);
+        assert_same_key(&bcrypt);
+        assert_same_key(base64::encode_block(&bcrypt).as_bytes());
+
+        let mut capi = vec![PUBLICKEYBLOB, CUR_BLOB_VERSION, 0, 0];
+        capi.extend(&0x0000_a400u32.to_le_bytes());
+        capi.extend(b"RSA1");
+        capi.extend(&(n.len() as u32 * 8).to_le_bytes());
+        let mut exponent = [0u8; 4];
+        exponent[4 - e.len()..].copy_from_slice(&e);
+        capi.extend(&u32::from_be_bytes(exponent).to_le_bytes());
+        capi.extend(n.iter().rev());
+        assert_same_key(&capi);
+    }
+
+    #[test]
+    fn reports_detected_format() {
+        let err = parse_public_key(b"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA alice").unwrap_err();
+        assert_eq!(err.format, Some(KeyFormat::OpenSsh));
+        let err = parse_public_key(base64::encode_block(b"RSA1 truncated").as_bytes()).unwrap_err();
+        assert_eq!(err.format, Some(KeyFormat::BcryptRsaPublicBlob));
+        assert!(err.base64);
+        let err = parse_public_key(b"not a key").unwrap_err();
+        assert_eq!(err.format, None);
+    }
+}
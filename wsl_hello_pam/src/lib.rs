@@ -4,11 +4,39 @@
 
 extern crate libc;
 extern crate openssl;
+extern crate serde_json;
 extern crate toml;
 extern crate uuid;
+extern crate wsl_hello_protocol;
 
+pub mod account;
+pub mod attestation;
+pub mod audit_log;
 pub mod auth;
+pub mod authorized_keys;
 pub mod bindings;
+pub mod certificate;
+pub mod config;
+mod conversation;
+pub mod date;
+pub mod grace;
+pub mod hooks;
+pub mod host_key;
+mod interruption;
+pub mod key_command;
+pub mod key_metadata;
+pub mod keys;
+pub mod linux_audit;
+pub mod lockout;
+mod password_race;
+pub mod permissions;
+mod prompt_lock;
+mod protocol;
+pub mod revocation;
+pub mod rotation;
+mod spawn;
+mod syslog;
+mod unavailable;
 
 use bindings::*;
 
@@ -23,17 +51,6 @@ pub fn pam_sm_setcred(
     PAM_IGNORE
 }
 
-#[no_mangle]
-#[allow(unused_variables)]
-pub fn pam_sm_acct_mgmt(
-    pamh: *mut pam_handle_t,
-    flags: ::std::os::raw::c_int,
-    argc: ::std::os::raw::c_int,
-    argv: *mut *const ::std::os::raw::c_char,
-) -> ::std::os::raw::c_int {
-    PAM_IGNORE
-}
-
 #[no_mangle]
 #[allow(unused_variables)]
 pub fn pam_sm_open_session(
@@ -44,25 +61,3 @@ pub fn pam_sm_open_session(
 ) -> ::std::os::raw::c_int {
     PAM_IGNORE
 }
-
-#[no_mangle]
-#[allow(unused_variables)]
-pub fn pam_sm_close_session(
-    pamh: *mut pam_handle_t,
-    flags: ::std::os::raw::c_int,
-    argc: ::std::os::raw::c_int,
-    argv: *mut *const ::std::os::raw::c_char,
-) -> ::std::os::raw::c_int {
-    PAM_IGNORE
-}
-
-#[no_mangle]
-#[allow(unused_variables)]
-pub fn pam_sm_chauthtok(
-    pamh: *mut pam_handle_t,
-    flags: ::std::os::raw::c_int,
-    argc: ::std::os::raw::c_int,
-    argv: *mut *const ::std::os::raw::c_char,
-) -> ::std::os::raw::c_int {
-    PAM_IGNORE
-}
@@ -0,0 +1,164 @@
+use libc::{self, c_void};
+use std::mem;
+
+/// The message type of user space authentication records, from `linux/audit.h`
+const AUDIT_USER_AUTH: u16 = 1100;
+
+/// An authentication as `ausearch -m USER_AUTH` shows it
+pub struct AuthEvent<'a> {
+    pub user: &'a str,
+    pub success: bool,
+    pub key_fingerprint: Option<&'a str>,
+    /// The hash of the record that the approval appended to `audit_log`
+    pub audit_log_head: Option<&'a str>,
+    pub service: Option<&'a str>,
+    pub terminal: Option<&'a str>,
+    pub hostname: Option<&'a str>,
+}
+
+impl<'a> AuthEvent<'a> {
+    /// Formats the fields like libaudit's `audit_log_acct_message` does for pam_unix, so that
+    /// the records are searched in the same way. The kernel adds the pid, uid and session.
+    pub fn to_message(&self) -> String {
+        let optional = |value: Option<&str>| match value {
+            Some(value) if !value.is_empty() => encode_value(value),
+            _ => "?".to_owned(),
+        };
+        // libaudit leaves the host name and the terminal unquoted
+        let unquoted = |value: Option<&str>| match value {
+            Some(value) if !value.is_empty() => value.replace(char::is_whitespace, "_"),
+            _ => "?".to_owned(),
+        };
+        format!(
+            "op=PAM:authentication grantors={} method=windows-hello key_fingerprint={} \
+             audit_log_head={} service={} acct={} exe={} hostname={} addr=? terminal={} res={}",
+            if self.success { "pam_wsl_hello" } else { "?" },
+            optional(self.key_fingerprint),
+            unquoted(self.audit_log_head),
+            optional(self.service),
+            encode_value(self.user),
+            optional(
+                std::env::current_exe()
+                    .ok()
+                    .as_ref()
+                    .and_then(|path| path.to_str())
+            ),
+            unquoted(self.hostname),
+            unquoted(self.terminal.map(|tty| tty.trim_start_matches("/dev/"))),
+            if self.success { "success" } else { "failed" }
+        )
+    }
+}
+
+/// Quotes a value, or hex-encodes it like libaudit if it contains spaces, quotes or control
+/// characters that would break the parsing of the record
+fn encode_value(value: &str) -> String {
+    if value
+        .bytes()
+        .all(|byte| byte > b' ' && byte < 0x7f && byte != b'"')
+    {
+        format!("\"{}\"", value)
+    } else {
+        value.bytes().map(|byte| format!("{:02X}", byte)).collect()
+    }
+}
+
+/// Sends `event` to the audit subsystem. Nothing happens if there is none, e.g. in WSL kernels
+/// without `CONFIG_AUDIT`, or if the process may not write to it.
+pub fn log_auth_event(event: &AuthEvent) {
+    let _ = send(AUDIT_USER_AUTH, &event.to_message());
+}
+
+fn send(message_type: u16, message: &str) -> Option<()> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_AUDIT,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+
+    let header_len = mem::size_of::<libc::nlmsghdr>();
+    let mut packet = vec![0u8; header_len];
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    let header = libc::nlmsghdr {
+        nlmsg_len: packet.len() as u32,
+        nlmsg_type: message_type,
+        nlmsg_flags: libc::NLM_F_REQUEST as u16,
+        nlmsg_seq: 1,
+        nlmsg_pid: 0,
+    };
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            &header as *const libc::nlmsghdr as *const u8,
+            packet.as_mut_ptr(),
+            header_len,
+        )
+    };
+
+    let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    let sent = unsafe {
+        libc::sendto(
+            fd,
+            packet.as_ptr() as *const c_void,
+            packet.len(),
+            0,
+            &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    unsafe { libc::close(fd) };
+    if sent < 0 {
+        None
+    } else {
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_records_like_libaudit() {
+        let event = AuthEvent {
+            user: "alice",
+            success: true,
+            key_fingerprint: Some("SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8"),
+            audit_log_head: Some(
+                "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+            ),
+            service: Some("sudo"),
+            terminal: Some("/dev/pts/3"),
+            hostname: None,
+        };
+        let message = event.to_message();
+        assert!(message.starts_with(
+            "op=PAM:authentication grantors=pam_wsl_hello method=windows-hello \
+             key_fingerprint=\"SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8\" \
+             audit_log_head=9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08 \
+             service=\"sudo\" acct=\"alice\" exe="
+        ));
+        assert!(message.ends_with(" hostname=? addr=? terminal=pts/3 res=success"));
+
+        let event = AuthEvent {
+            user: "alice smith",
+            success: false,
+            key_fingerprint: None,
+            audit_log_head: None,
+            service: None,
+            terminal: None,
+            hostname: None,
+        };
+        let message = event.to_message();
+        assert!(message.contains(
+            " key_fingerprint=? audit_log_head=? service=? acct=616C69636520736D697468 "
+        ));
+        assert!(message.ends_with(" terminal=? res=failed"));
+    }
+}
@@ -0,0 +1,268 @@
+use libc;
+use permissions;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+use toml::Value;
+
+/// Where the failure records of users are kept, one file per user like pam_faillock's
+pub const FAILURES_DIR: &str = "/var/lib/pam_wsl_hello/failures";
+
+/// The file in `FAILURES_DIR` that is locked while a record is changed. User names cannot start
+/// with a dot, so it is no record.
+const LOCK_FILE: &str = ".lock";
+
+/// Locks Windows Hello for a user for `lock_seconds` after `attempts` consecutive failures
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    pub attempts: u32,
+    pub lock_seconds: u64,
+}
+
+/// The failures of a user since the last successful authentication
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FailureRecord {
+    pub failures: u32,
+    /// Seconds since the UNIX epoch
+    pub last_failure: u64,
+    /// Seconds since the UNIX epoch until which the user is locked out, or 0
+    pub locked_until: u64,
+}
+
+#[derive(Debug)]
+pub enum LockoutError {
+    Io(io::Error),
+    InvalidUserName(String),
+    InvalidRecord(String),
+}
+
+impl From<io::Error> for LockoutError {
+    fn from(err: io::Error) -> LockoutError {
+        LockoutError::Io(err)
+    }
+}
+
+impl fmt::Display for LockoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockoutError::Io(ref err) => write!(f, "{}", err),
+            LockoutError::InvalidUserName(ref user) => {
+                write!(f, "'{}' cannot be used as a file name", user)
+            }
+            LockoutError::InvalidRecord(ref path) => write!(f, "{} is corrupted", path),
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl FailureRecord {
+    pub fn load(user_name: &str) -> Result<FailureRecord, LockoutError> {
+        load_from(&record_path(Path::new(FAILURES_DIR), user_name)?)
+    }
+
+    /// Applies `change` to the record of `user_name` and saves it. Concurrent authentications
+    /// wait for each other, so that none of their failures is lost.
+    pub fn update<T, F>(user_name: &str, change: F) -> Result<T, LockoutError>
+    where
+        F: FnOnce(&mut FailureRecord) -> T,
+    {
+        update_in(Path::new(FAILURES_DIR), user_name, change)
+    }
+
+    /// Forgets the failures of `user_name`, which also unlocks it
+    pub fn reset(user_name: &str) -> Result<(), LockoutError> {
+        let path = record_path(Path::new(FAILURES_DIR), user_name)?;
+        let _lock = lock(Path::new(FAILURES_DIR))?;
+        match fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(LockoutError::Io(err)),
+        }
+    }
+
+    fn parse(contents: &str) -> Option<FailureRecord> {
+        let value = contents.parse::<Value>().ok()?;
+        let field = |name: &str| {
+            value
+                .get(name)
+                .and_then(Value::as_integer)
+                .and_then(|value| u64::try_from(value).ok())
+        };
+        Some(FailureRecord {
+            failures: u32::try_from(field("failures")?).ok()?,
+            last_failure: field("last_failure")?,
+            locked_until: field("locked_until")?,
+        })
+    }
+
+    fn to_toml(&self) -> String {
+        format!(
+            "failures = {}\nlast_failure = {}\nlocked_until = {}\n",
+            self.failures, self.last_failure, self.locked_until
+        )
+    }
+
+    /// Returns how many seconds the user is still locked out at `now`, if at all
+    pub fn remaining_lock(&self, now: u64) -> Option<u64> {
+        match self.locked_until.checked_sub(now) {
+            Some(remaining) if remaining > 0 => Some(remaining),
+            _ => None,
+        }
+    }
+
+    /// Counts a failure at `now` and returns whether it locks the user out. Failures older than
+    /// the lock duration, and those before an expired lock, are forgotten.
+    pub fn record_failure(&mut self, policy: &LockoutPolicy, now: u64) -> bool {
+        let expired = self.locked_until != 0 && self.locked_until <= now;
+        let stale = now.saturating_sub(self.last_failure) > policy.lock_seconds;
+        if expired || stale {
+            *self = FailureRecord::default();
+        }
+        self.failures += 1;
+        self.last_failure = now;
+        if self.failures >= policy.attempts {
+            self.locked_until = now + policy.lock_seconds;
+            return true;
+        }
+        false
+    }
+}
+
+fn load_from(path: &Path) -> Result<FailureRecord, LockoutError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => FailureRecord::parse(&contents)
+            .ok_or_else(|| LockoutError::InvalidRecord(path.display().to_string())),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(FailureRecord::default()),
+        Err(err) => Err(LockoutError::Io(err)),
+    }
+}
+
+fn update_in<T, F>(dir: &Path, user_name: &str, change: F) -> Result<T, LockoutError>
+where
+    F: FnOnce(&mut FailureRecord) -> T,
+{
+    let path = record_path(dir, user_name)?;
+    let _lock = lock(dir)?;
+    let mut record = load_from(&path)?;
+    let result = change(&mut record);
+
+    // The record is replaced at once, so that it is never read half written
+    let temporary_path = dir.join(format!(".{}.{}.tmp", user_name, process::id()));
+    // Left behind by a process that crashed, as the lock is held
+    let _ = fs::remove_file(&temporary_path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temporary_path)?;
+    let written = file
+        .write_all(record.to_toml().as_bytes())
+        .and_then(|()| file.sync_data())
+        .and_then(|()| fs::rename(&temporary_path, &path));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temporary_path);
+        return Err(LockoutError::Io(err));
+    }
+    Ok(result)
+}
+
+/// Locks the records in `dir` until the returned file is closed
+fn lock(dir: &Path) -> Result<File, LockoutError> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(dir.join(LOCK_FILE))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(LockoutError::Io(io::Error::last_os_error()));
+    }
+    Ok(file)
+}
+
+fn record_path(dir: &Path, user_name: &str) -> Result<PathBuf, LockoutError> {
+    if !permissions::is_safe_file_name(user_name) {
+        return Err(LockoutError::InvalidUserName(user_name.to_owned()));
+    }
+    Ok(dir.join(user_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: LockoutPolicy = LockoutPolicy {
+        attempts: 3,
+        lock_seconds: 600,
+    };
+
+    #[test]
+    fn locks_after_consecutive_failures() {
+        let mut record = FailureRecord::default();
+        assert!(!record.record_failure(&POLICY, 1000));
+        assert!(!record.record_failure(&POLICY, 1010));
+        assert!(record.record_failure(&POLICY, 1020));
+        assert_eq!(record.remaining_lock(1020), Some(600));
+        assert_eq!(record.remaining_lock(1619), Some(1));
+        assert_eq!(record.remaining_lock(1620), None);
+
+        // The lock expired, so counting starts over
+        assert!(!record.record_failure(&POLICY, 1700));
+        assert_eq!(record.failures, 1);
+        assert_eq!(FailureRecord::parse(&record.to_toml()), Some(record));
+    }
+
+    #[test]
+    fn forgets_stale_failures() {
+        let mut record = FailureRecord::default();
+        record.record_failure(&POLICY, 1000);
+        record.record_failure(&POLICY, 1010);
+        assert!(!record.record_failure(&POLICY, 2000));
+        assert_eq!(record.failures, 1);
+    }
+
+    #[test]
+    fn rejects_unsafe_user_names() {
+        for user_name in &["", "../etc/passwd", ".hidden"] {
+            assert!(record_path(Path::new(FAILURES_DIR), user_name).is_err());
+        }
+    }
+
+    #[test]
+    fn counts_concurrent_failures() {
+        let dir = std::env::temp_dir().join(format!("pam_wsl_hello_lockout_{}", process::id()));
+        let policy = LockoutPolicy {
+            attempts: 1000,
+            lock_seconds: 600,
+        };
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        update_in(&dir, "alice", |record| record.record_failure(&policy, 1000))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let record = load_from(&dir.join("alice")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(record.failures, 80);
+    }
+}
@@ -0,0 +1,99 @@
+use auth::{Authenticator, HelloAuthenticationError};
+use bindings::*;
+use config::Config;
+use conversation;
+use interruption;
+use libc::{c_int, c_void};
+use protocol::{AuthenticatorRequest, AuthenticatorResponse};
+use std::ffi::CString;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+
+const PROMPT: &str = "Password or Windows Hello (Enter to skip Windows Hello): ";
+
+/// The PAM handle moves to the thread of the password prompt, which uses it only to call the
+/// conversation function and does not touch it once that returns
+struct PamHandle(*mut pam_handle_t);
+
+unsafe impl Send for PamHandle {}
+
+enum Event {
+    Hello(Result<AuthenticatorResponse, HelloAuthenticationError>),
+    Prompt(Result<Option<String>, c_int>),
+}
+
+/// Asks Windows Hello to sign the challenge of `request` while prompting for the password, and returns the
+/// response of whichever the user finishes first. A typed password is passed on to the next
+/// modules as `PAM_AUTHTOK`, and an empty line cancels Windows Hello.
+///
+/// The conversation function of the application runs on another thread meanwhile, so it must be
+/// thread-safe. A prompt cannot be cancelled through PAM, so if Windows Hello finishes first, the
+/// prompt is left to end with the next line that the user enters, which is discarded.
+pub(crate) fn request_signature_or_password(
+    pamh: *mut pam_handle_t,
+    config: &Config,
+    request: &AuthenticatorRequest,
+) -> Result<AuthenticatorResponse, HelloAuthenticationError> {
+    let authenticator = Authenticator::start(config, request)?;
+    let cancel_hello = authenticator.canceller();
+    let timeout = config.hello_timeout;
+    let (send_event, receive_event) = mpsc::channel();
+
+    let send_hello = send_event.clone();
+    interruption::spawn_worker(move || {
+        let _ = send_hello.send(Event::Hello(authenticator.finish(timeout)));
+    });
+    let handle = PamHandle(pamh);
+    let prompt = interruption::spawn_worker(move || {
+        let handle = handle;
+        let response = conversation::converse(handle.0, PAM_PROMPT_ECHO_OFF, PROMPT);
+        let _ = send_event.send(Event::Prompt(response));
+    });
+
+    let first = receive_event
+        .recv()
+        .expect("the bridge thread always reports");
+    let response = match first {
+        // Joining the prompt would wait for the user to enter a line
+        Event::Hello(result) => return result,
+        Event::Prompt(Ok(response)) => {
+            cancel_hello.store(true, Ordering::SeqCst);
+            response
+        }
+        // Without a prompt, e.g. if the application has no terminal, only Windows Hello is left
+        Event::Prompt(Err(_)) => {
+            return wait_for_hello(&receive_event);
+        }
+    };
+    // The bridge is stopped by its thread, which reports once it has exited
+    let _ = wait_for_hello(&receive_event);
+    let _ = prompt.join();
+
+    match response {
+        Some(password) if !password.is_empty() => {
+            set_authtok(pamh, &password)?;
+            Err(HelloAuthenticationError::PasswordEntered)
+        }
+        _ => Err(HelloAuthenticationError::HelloCancelled),
+    }
+}
+
+fn wait_for_hello(
+    receive_event: &mpsc::Receiver<Event>,
+) -> Result<AuthenticatorResponse, HelloAuthenticationError> {
+    for event in receive_event {
+        if let Event::Hello(result) = event {
+            return result;
+        }
+    }
+    Err(HelloAuthenticationError::AuthenticatorSignalled)
+}
+
+fn set_authtok(pamh: *mut pam_handle_t, password: &str) -> Result<(), HelloAuthenticationError> {
+    let password = CString::new(password).map_err(|_| HelloAuthenticationError::InvalidPassword)?;
+    let err = unsafe { pam_set_item(pamh, PAM_AUTHTOK, password.as_ptr() as *const c_void) };
+    if err != PAM_SUCCESS {
+        return Err(HelloAuthenticationError::InvalidPassword);
+    }
+    Ok(())
+}
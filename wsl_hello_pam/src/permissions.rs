@@ -0,0 +1,100 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum PermissionError {
+    Io(io::Error),
+    Untrusted { path: PathBuf, reason: String },
+}
+
+impl From<io::Error> for PermissionError {
+    fn from(err: io::Error) -> PermissionError {
+        PermissionError::Io(err)
+    }
+}
+
+impl fmt::Display for PermissionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PermissionError::Io(ref err) => write!(f, "{}", err),
+            PermissionError::Untrusted {
+                ref path,
+                ref reason,
+            } => write!(f, "{} is not trusted; {}", path.display(), reason),
+        }
+    }
+}
+
+/// Makes sure that only root can modify `path`, i.e. that the file and all of its parent
+/// directories are owned by root and writable by neither the group nor others.
+/// Returns the canonical path, which is what was checked.
+pub fn check_root_owned(path: &Path) -> Result<PathBuf, PermissionError> {
+    let untrusted = |path: &Path, reason: &str| PermissionError::Untrusted {
+        path: path.to_owned(),
+        reason: reason.to_owned(),
+    };
+    if !path.is_absolute() {
+        return Err(untrusted(path, "it is not an absolute path"));
+    }
+    let path = fs::canonicalize(path)?;
+    for ancestor in path.ancestors() {
+        let metadata = fs::metadata(ancestor)?;
+        if metadata.uid() != 0 {
+            return Err(untrusted(ancestor, "it is not owned by root"));
+        }
+        if metadata.mode() & 0o022 != 0 {
+            return Err(untrusted(ancestor, "it is writable by the group or others"));
+        }
+    }
+    Ok(path)
+}
+
+/// Reads `path` after `check_root_owned` like sshd's StrictModes, so that nobody but root can
+/// have put its contents there
+pub fn read_root_owned(path: &Path) -> Result<Vec<u8>, PermissionError> {
+    let path = check_root_owned(path)?;
+    Ok(fs::read(path)?)
+}
+
+/// Whether `name`, such as a user name, can be used as a file name without escaping its directory
+pub fn is_safe_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.starts_with('.') && !name.contains('\0')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_untrusted_paths() {
+        match check_root_owned(Path::new("bin/sh")) {
+            Err(PermissionError::Untrusted { .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // The temporary directory is writable by everyone
+        let file = std::env::temp_dir().join("pam_wsl_hello_permissions_test");
+        fs::write(&file, "").unwrap();
+        let result = check_root_owned(&file);
+        let contents = read_root_owned(&file);
+        fs::remove_file(&file).unwrap();
+        match result {
+            Err(PermissionError::Untrusted { .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match contents {
+            Err(PermissionError::Untrusted { .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_unsafe_file_names() {
+        assert!(is_safe_file_name("alice"));
+        for name in &["", "../etc/passwd", ".hidden", "a/b"] {
+            assert!(!is_safe_file_name(name));
+        }
+    }
+}
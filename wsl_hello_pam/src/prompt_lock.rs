@@ -0,0 +1,99 @@
+use libc;
+use permissions;
+use std::fmt;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Where the lock files of the Windows Hello prompts are kept, one per user. They are only
+/// locked and never removed, so that every process of the user locks the same file.
+pub const PROMPT_LOCK_DIR: &str = "/run/pam_wsl_hello/prompts";
+
+#[derive(Debug)]
+pub enum PromptLockError {
+    Io(io::Error),
+    InvalidUserName(String),
+}
+
+impl From<io::Error> for PromptLockError {
+    fn from(err: io::Error) -> PromptLockError {
+        PromptLockError::Io(err)
+    }
+}
+
+impl fmt::Display for PromptLockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PromptLockError::Io(ref err) => write!(f, "{}", err),
+            PromptLockError::InvalidUserName(ref user) => {
+                write!(f, "'{}' cannot be used as a file name", user)
+            }
+        }
+    }
+}
+
+/// The lock file of a user, which allows one Windows Hello prompt at a time. A lock that is
+/// taken is released when the `PromptLock` is dropped or its process exits.
+pub struct PromptLock {
+    file: File,
+}
+
+impl PromptLock {
+    pub fn open(user_name: &str) -> Result<PromptLock, PromptLockError> {
+        PromptLock::open_in(Path::new(PROMPT_LOCK_DIR), user_name)
+    }
+
+    fn open_in(dir: &Path, user_name: &str) -> Result<PromptLock, PromptLockError> {
+        if !permissions::is_safe_file_name(user_name) {
+            return Err(PromptLockError::InvalidUserName(user_name.to_owned()));
+        }
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(dir.join(format!("{}.lock", user_name)))?;
+        Ok(PromptLock { file })
+    }
+
+    /// Takes the lock unless another prompt holds it, and returns whether it did
+    pub fn try_lock(&self) -> io::Result<bool> {
+        if unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(false);
+        }
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn allows_one_prompt_per_user() {
+        let dir = env::temp_dir().join(format!("pam_wsl_hello_prompts_{}", std::process::id()));
+        let first = PromptLock::open_in(&dir, "alice").unwrap();
+        let second = PromptLock::open_in(&dir, "alice").unwrap();
+        let other_user = PromptLock::open_in(&dir, "bob").unwrap();
+        assert!(first.try_lock().unwrap());
+        assert!(!second.try_lock().unwrap());
+        assert!(other_user.try_lock().unwrap());
+        drop(first);
+        assert!(second.try_lock().unwrap());
+
+        match PromptLock::open_in(&dir, "../alice") {
+            Err(PromptLockError::InvalidUserName(_)) => {}
+            _ => panic!("a user name with a path is accepted"),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
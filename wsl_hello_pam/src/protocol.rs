@@ -0,0 +1,235 @@
+use openssl::base64;
+use std::fmt;
+
+/// What `WindowsHelloBridge.exe authenticator` is asked to do.
+///
+/// Its input is the challenge line followed by optional `name: value` lines and an empty line,
+/// after which stdin stays open until the response is read.
+pub struct AuthenticatorRequest<'a> {
+    pub key_name: &'a str,
+    /// A challenge envelope, which is the only data that the bridge signs
+    pub challenge: &'a str,
+    /// Shown to the Windows user before Windows Hello, e.g. "sudo in Ubuntu-22.04 as alice". The
+    /// bridge shows it only if it is the description of `challenge`.
+    pub message: Option<&'a str>,
+    /// The base64 signature of `challenge` by the host key of this distro, which the bridge
+    /// requires of every request
+    pub host_signature: Option<String>,
+}
+
+impl<'a> AuthenticatorRequest<'a> {
+    pub fn to_input(&self) -> String {
+        let mut input = format!("{}\n", self.challenge);
+        if let Some(message) = self.message {
+            input.push_str(&format!("message: {}\n", message));
+        }
+        if let Some(ref signature) = self.host_signature {
+            input.push_str(&format!("host_signature: {}\n", signature));
+        }
+        input.push('\n');
+        input
+    }
+}
+
+/// The output of `WindowsHelloBridge.exe authenticator` on success.
+///
+/// It consists of `name: value` lines where binary values are base64-encoded.
+/// Unknown lines are ignored so that newer bridges can add fields, but a known field may appear
+/// only once.
+pub struct AuthenticatorResponse {
+    pub signature: Vec<u8>,
+    /// DER-encoded public key of the credential that made `signature`
+    pub public_key: Option<Vec<u8>>,
+    /// The certificate issued for the credential followed by optional intermediates, either
+    /// PEM or concatenated DER
+    pub certificate: Option<Vec<u8>>,
+}
+
+impl AuthenticatorResponse {
+    pub fn parse(output: &[u8]) -> Option<AuthenticatorResponse> {
+        let output = std::str::from_utf8(output).ok()?;
+        let mut signature = None;
+        let mut public_key = None;
+        let mut certificate = None;
+        for line in output.lines() {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            let field = match name {
+                "signature" => &mut signature,
+                "public_key" => &mut public_key,
+                "certificate" => &mut certificate,
+                _ => continue,
+            };
+            if field.replace(base64::decode_block(value).ok()?).is_some() {
+                return None;
+            }
+        }
+        Some(AuthenticatorResponse {
+            signature: signature?,
+            public_key,
+            certificate,
+        })
+    }
+}
+
+/// Why `WindowsHelloBridge.exe` failed, from its exit code. The codes are those of
+/// `FailureReason` in the bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeFailure {
+    WindowsHelloNotSupported,
+    CredentialExists,
+    CredentialNotFound,
+    DeviceLocked,
+    Unknown,
+    UserCancelled,
+    PrefersPassword,
+    Other,
+    Aborted,
+    RejectedChallenge,
+    /// Registering a host key failed, which the authentication modes never report
+    HostRegistrationFailed,
+}
+
+impl BridgeFailure {
+    pub fn from_code(code: i32) -> Option<BridgeFailure> {
+        Some(match code {
+            170 => BridgeFailure::WindowsHelloNotSupported,
+            171 => BridgeFailure::CredentialExists,
+            172 => BridgeFailure::CredentialNotFound,
+            173 => BridgeFailure::DeviceLocked,
+            175 => BridgeFailure::Unknown,
+            176 => BridgeFailure::UserCancelled,
+            177 => BridgeFailure::PrefersPassword,
+            178 => BridgeFailure::Other,
+            179 => BridgeFailure::Aborted,
+            180 => BridgeFailure::RejectedChallenge,
+            181 => BridgeFailure::HostRegistrationFailed,
+            _ => return None,
+        })
+    }
+
+    /// Whether asking Windows Hello again may succeed, e.g. after a face that is not recognized
+    /// or a misclick on Cancel. A missing credential or device support will not change.
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            BridgeFailure::Unknown | BridgeFailure::UserCancelled | BridgeFailure::Other
+        )
+    }
+}
+
+impl fmt::Display for BridgeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            BridgeFailure::WindowsHelloNotSupported => "Windows Hello is not supported",
+            BridgeFailure::CredentialExists => "the credential already exists",
+            BridgeFailure::CredentialNotFound => "the credential does not exist",
+            BridgeFailure::DeviceLocked => "the security device is locked",
+            BridgeFailure::Unknown => "unknown error",
+            BridgeFailure::UserCancelled => "cancelled",
+            BridgeFailure::PrefersPassword => "the user prefers a password",
+            BridgeFailure::Other => "error",
+            BridgeFailure::Aborted => "aborted",
+            BridgeFailure::RejectedChallenge => "the challenge is rejected",
+            BridgeFailure::HostRegistrationFailed => "the host key cannot be registered",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_requests() {
+        let request = AuthenticatorRequest {
+            key_name: "pam_wsl_hello_alice",
+            challenge: "wsl-hello-challenge/1 nonce=1234 issued=0 user=alice",
+            message: Some("sudo as alice"),
+            host_signature: Some("c2lnbmF0dXJl".to_owned()),
+        };
+        assert_eq!(
+            request.to_input(),
+            "wsl-hello-challenge/1 nonce=1234 issued=0 user=alice\nmessage: sudo as alice\n\
+             host_signature: c2lnbmF0dXJl\n\n"
+        );
+        let request = AuthenticatorRequest {
+            message: None,
+            host_signature: None,
+            ..request
+        };
+        assert_eq!(
+            request.to_input(),
+            "wsl-hello-challenge/1 nonce=1234 issued=0 user=alice\n\n"
+        );
+    }
+
+    #[test]
+    fn parses_responses() {
+        let response = AuthenticatorResponse::parse(
+            b"signature: c2lnbmF0dXJl\npublic_key: a2V5\ncertificate: Y2VydA==\nfuture: x\n",
+        )
+        .unwrap();
+        assert_eq!(response.signature, b"signature");
+        assert_eq!(response.public_key.as_deref(), Some(&b"key"[..]));
+        assert_eq!(response.certificate.as_deref(), Some(&b"cert"[..]));
+
+        // Older bridges print the signature only
+        let response = AuthenticatorResponse::parse(b"signature: c2lnbmF0dXJl\r\n").unwrap();
+        assert_eq!(response.signature, b"signature");
+        assert!(response.public_key.is_none());
+        assert!(response.certificate.is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        for output in &[
+            &b""[..],
+            b"public_key: a2V5\n",
+            b"signature: not base64!\n",
+            b"signature: c2lnbmF0dXJl\npublic_key: a2V5=\n",
+            b"signature: c2lnbmF0dXJl\nsignature: b3RoZXI=\n",
+            b"signature: c2lnbmF0dXJl\npublic_key: a2V5\npublic_key: a2V5\n",
+            b"signature: \xff\n",
+        ] {
+            assert!(
+                AuthenticatorResponse::parse(output).is_none(),
+                "{}",
+                String::from_utf8_lossy(output)
+            );
+        }
+    }
+
+    #[test]
+    fn retries_only_transient_failures() {
+        assert_eq!(
+            BridgeFailure::from_code(176),
+            Some(BridgeFailure::UserCancelled)
+        );
+        assert_eq!(
+            BridgeFailure::from_code(180),
+            Some(BridgeFailure::RejectedChallenge)
+        );
+        assert_eq!(
+            BridgeFailure::from_code(181),
+            Some(BridgeFailure::HostRegistrationFailed)
+        );
+        assert_eq!(BridgeFailure::from_code(174), None);
+        assert_eq!(BridgeFailure::from_code(182), None);
+        assert_eq!(BridgeFailure::from_code(1), None);
+        let retryable: Vec<_> = (170..=181)
+            .filter_map(BridgeFailure::from_code)
+            .filter(|failure| failure.is_retryable())
+            .collect();
+        assert_eq!(
+            retryable,
+            [
+                BridgeFailure::Unknown,
+                BridgeFailure::UserCancelled,
+                BridgeFailure::Other
+            ]
+        );
+    }
+}
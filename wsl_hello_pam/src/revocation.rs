@@ -0,0 +1,165 @@
+use date::Date;
+use keys;
+use openssl::error::ErrorStack;
+use openssl::pkey::{PKey, Public};
+use permissions::{self, PermissionError};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const REVOKED_KEYS_PATH: &str = "/etc/pam_wsl_hello/revoked_keys";
+
+/// A line of `/etc/pam_wsl_hello/revoked_keys`, which consists of the fingerprint of the key
+/// as `ssh-keygen -l` prints it, the day of the revocation and the reason, e.g.
+/// `SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8 2026-03-01 laptop stolen`
+#[derive(Debug)]
+pub struct RevokedKey {
+    pub fingerprint: String,
+    pub revoked: Date,
+    pub reason: String,
+}
+
+impl fmt::Display for RevokedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} was revoked on {}; {}",
+            self.fingerprint, self.revoked, self.reason
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum RevocationError {
+    Io(io::Error),
+    Untrusted(PermissionError),
+    Syntax { line: usize, reason: String },
+}
+
+impl From<io::Error> for RevocationError {
+    fn from(err: io::Error) -> RevocationError {
+        RevocationError::Io(err)
+    }
+}
+
+impl fmt::Display for RevocationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RevocationError::Io(ref err) => write!(f, "{}", err),
+            RevocationError::Untrusted(ref err) => write!(f, "{}", err),
+            RevocationError::Syntax { line, ref reason } => {
+                write!(f, "{}:{}: {}", REVOKED_KEYS_PATH, line, reason)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    keys: Vec<RevokedKey>,
+}
+
+impl RevocationList {
+    /// Loads `/etc/pam_wsl_hello/revoked_keys`, which must be root-owned so that a revocation
+    /// cannot be taken back by anyone else. No key is revoked if it does not exist.
+    pub fn load() -> Result<RevocationList, RevocationError> {
+        let path = Path::new(REVOKED_KEYS_PATH);
+        if !path.exists() {
+            return Ok(RevocationList::default());
+        }
+        let path = permissions::check_root_owned(path).map_err(RevocationError::Untrusted)?;
+        RevocationList::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<RevocationList, RevocationError> {
+        let keys = contents
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(line_number, line)| {
+                parse_line(line).map_err(|reason| RevocationError::Syntax {
+                    line: line_number,
+                    reason,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(RevocationList { keys })
+    }
+
+    /// Returns the revocation of `key`, if it is revoked
+    pub fn find(&self, key: &PKey<Public>) -> Result<Option<&RevokedKey>, ErrorStack> {
+        let fingerprint = keys::fingerprint(key)?;
+        Ok(self.find_fingerprint(&fingerprint))
+    }
+
+    pub fn find_fingerprint(&self, fingerprint: &str) -> Option<&RevokedKey> {
+        self.keys.iter().find(|key| key.fingerprint == fingerprint)
+    }
+
+    pub fn fingerprints(&self) -> impl Iterator<Item = &str> {
+        self.keys.iter().map(|key| key.fingerprint.as_str())
+    }
+}
+
+fn parse_line(line: &str) -> Result<RevokedKey, String> {
+    let mut fields = line.splitn(3, char::is_whitespace);
+    let fingerprint = fields.next().unwrap_or("");
+    if !fingerprint.starts_with("SHA256:") {
+        return Err(format!(
+            "'{}' is not a SHA256 fingerprint of a key",
+            fingerprint
+        ));
+    }
+    let revoked = fields.next().unwrap_or("");
+    let revoked =
+        Date::parse(revoked).ok_or_else(|| format!("'{}' is not a YYYY-MM-DD date", revoked))?;
+    let reason = fields.next().unwrap_or("").trim();
+    if reason.is_empty() {
+        return Err("a reason is required".to_owned());
+    }
+    Ok(RevokedKey {
+        fingerprint: fingerprint.to_owned(),
+        revoked,
+        reason: reason.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = include_bytes!("../tests/fixtures/attestation/hello_key.pem");
+    const OTHER_KEY: &[u8] = include_bytes!("../tests/fixtures/attestation/other_key.pem");
+
+    #[test]
+    fn finds_revoked_keys() {
+        let key = keys::parse_public_key(KEY).unwrap();
+        let other_key = keys::parse_public_key(OTHER_KEY).unwrap();
+        let list = RevocationList::parse(&format!(
+            "# revoked keys\n\n{} 2026-03-01 laptop stolen\n",
+            keys::fingerprint(&key).unwrap()
+        ))
+        .unwrap();
+
+        let revoked = list.find(&key).unwrap().unwrap();
+        assert_eq!(revoked.revoked.to_string(), "2026-03-01");
+        assert_eq!(revoked.reason, "laptop stolen");
+        assert!(list.find(&other_key).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in &[
+            "MD5:00:11:22 2026-03-01 stolen",
+            "SHA256:AAAA 2026-13-01 stolen",
+            "SHA256:AAAA 2026-03-01",
+        ] {
+            match RevocationList::parse(line) {
+                Err(RevocationError::Syntax { line: 1, .. }) => {}
+                other => panic!("unexpected result for '{}': {:?}", line, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,523 @@
+use attestation::{self, Attestation, AttestationError};
+use audit_log::{self, AuditLogError};
+use auth::{self, HelloAuthenticationError};
+use authorized_keys;
+use bindings::*;
+use config::{Config, ConfigError, TrustMode};
+use conversation;
+use date::Date;
+use interruption::Interruption;
+use key_command::KeyCommandMode;
+use key_metadata::{KeyMetadata, KeyMetadataError};
+use keys;
+use libc::{c_char, c_int, LOG_NOTICE, LOG_WARNING};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use syslog::syslog;
+use uuid::Uuid;
+
+/// Rotates the Windows Hello key of the user, or enrolls one, when the module is in the
+/// password stack. The preliminary check makes sure that the bridge is reachable, and the
+/// update creates a credential with a new name, verifies a signature made with it, and then
+/// switches the enrolled key over to it.
+#[no_mangle]
+pub fn pam_sm_chauthtok(
+    pamh: *mut pam_handle_t,
+    flags: c_int,
+    _: c_int,
+    _: *mut *const c_char,
+) -> c_int {
+    let result = if (flags & PAM_PRELIM_CHECK) != 0 {
+        check_bridge()
+    } else if (flags & PAM_UPDATE_AUTHTOK) != 0 {
+        rotate_key(pamh, flags)
+    } else {
+        return PAM_SERVICE_ERR;
+    };
+    result.unwrap_or_else(|err| {
+        if (flags & PAM_SILENT) == 0 {
+            let message = format!("WSL Hello error: {}", err);
+            let _ = conversation::send_message(pamh, PAM_ERROR_MSG, &message);
+        }
+        syslog(pamh, LOG_WARNING, &format!("key rotation failed; {}", err));
+        match err {
+            RotationError::BridgeUnreachable { .. } => PAM_TRY_AGAIN,
+            _ => PAM_AUTHTOK_ERR,
+        }
+    })
+}
+
+#[derive(Debug)]
+enum RotationError {
+    GetUserError(i32),
+    ConfigError(ConfigError),
+    KeyMetadataError(KeyMetadataError),
+    BridgeUnreachable { path: String, reason: String },
+    Io(io::Error),
+    CreatorLaunchError(io::Error),
+    CreatorFailed(String),
+    InvalidPublicKey(keys::InvalidKey),
+    AttestationError(AttestationError),
+    SignatureError(HelloAuthenticationError),
+    SignatureMismatch,
+    RetireError(AuditLogError),
+}
+
+impl From<io::Error> for RotationError {
+    fn from(err: io::Error) -> RotationError {
+        RotationError::Io(err)
+    }
+}
+
+impl From<ConfigError> for RotationError {
+    fn from(err: ConfigError) -> RotationError {
+        RotationError::ConfigError(err)
+    }
+}
+
+impl From<KeyMetadataError> for RotationError {
+    fn from(err: KeyMetadataError) -> RotationError {
+        RotationError::KeyMetadataError(err)
+    }
+}
+
+impl fmt::Display for RotationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RotationError::GetUserError(code) => {
+                write!(f, "cannot get the user name; PAM error {}", code)
+            }
+            RotationError::ConfigError(ref err) => write!(f, "config error; {}", err),
+            RotationError::KeyMetadataError(ref err) => {
+                write!(f, "the key metadata file is invalid; {}", err)
+            }
+            RotationError::BridgeUnreachable {
+                ref path,
+                ref reason,
+            } => write!(f, "cannot reach Windows Hello at '{}'; {}", path, reason),
+            RotationError::Io(ref err) => write!(f, "{}", err),
+            RotationError::CreatorLaunchError(ref err) => {
+                write!(f, "cannot launch the key creator; {}", err)
+            }
+            RotationError::CreatorFailed(ref msg) => {
+                write!(f, "cannot create a Windows Hello key; {}", msg)
+            }
+            RotationError::InvalidPublicKey(ref err) => {
+                write!(f, "the created public key is invalid; {}", err)
+            }
+            RotationError::AttestationError(ref err) => {
+                write!(f, "the attestation of the created key is invalid; {}", err)
+            }
+            RotationError::SignatureError(ref err) => {
+                write!(f, "cannot sign with the created key; {}", err)
+            }
+            RotationError::SignatureMismatch => write!(
+                f,
+                "the signature made with the created key does not match its public key"
+            ),
+            RotationError::RetireError(ref err) => {
+                write!(f, "cannot retire the old key; {}", err)
+            }
+        }
+    }
+}
+
+/// Returns the config if the key of the user is kept in `public_keys`, where this module can
+/// rotate it. Keys in `authorized_keys`, a directory behind `authorized_keys_command`, or
+/// certificates of a CA are managed by the administrator instead.
+fn managed_config() -> Result<Option<Config>, RotationError> {
+    let config = Config::load()?;
+    let managed = config.trust_mode == TrustMode::Keys
+        && !Path::new(authorized_keys::AUTHORIZED_KEYS_PATH).exists()
+        && !(config.authorized_keys_command.is_some()
+            && config.authorized_keys_command_mode == KeyCommandMode::Replace);
+    Ok(if managed { Some(config) } else { None })
+}
+
+fn check_bridge() -> Result<c_int, RotationError> {
+    let config = match managed_config()? {
+        Some(config) => config,
+        None => return Ok(PAM_IGNORE),
+    };
+    let unreachable = |path: &str, reason: String| RotationError::BridgeUnreachable {
+        path: path.to_owned(),
+        reason,
+    };
+
+    let bridge = fs::metadata(&config.authenticator_path)
+        .map_err(|err| unreachable(&config.authenticator_path, err.to_string()))?;
+    if !bridge.is_file() || bridge.permissions().mode() & 0o111 == 0 {
+        return Err(unreachable(
+            &config.authenticator_path,
+            "it is not an executable file".to_owned(),
+        ));
+    }
+    let win_mnt = fs::metadata(&config.win_mnt)
+        .map_err(|err| unreachable(&config.win_mnt, err.to_string()))?;
+    if !win_mnt.is_dir() {
+        return Err(unreachable(
+            &config.win_mnt,
+            "it is not a directory".to_owned(),
+        ));
+    }
+    Ok(PAM_SUCCESS)
+}
+
+fn rotate_key(pamh: *mut pam_handle_t, flags: c_int) -> Result<c_int, RotationError> {
+    let user_name = auth::get_user(pamh, None).map_err(RotationError::GetUserError)?;
+    let config = match managed_config()? {
+        Some(config) => config,
+        None => return Ok(PAM_IGNORE),
+    };
+    let metadata = KeyMetadata::load(&user_name)?;
+    let old_key_name = metadata.credential_key_name(&user_name);
+    let enrolled = keys::key_file_path(&old_key_name, "pem").exists();
+
+    // Applications pass PAM_CHANGE_EXPIRED_AUTHTOK when pam_sm_acct_mgmt asked for a rotation,
+    // in which case only keys that are about to expire are rotated
+    if (flags & PAM_CHANGE_EXPIRED_AUTHTOK) != 0 {
+        let expiring = enrolled
+            && metadata
+                .expiry(config.max_key_age_days)
+                .is_some_and(|expires| {
+                    Date::today().days_until(expires) <= i64::from(config.expiry_warning_days)
+                });
+        if !expiring {
+            return Ok(PAM_IGNORE);
+        }
+    }
+
+    let new_key_name = new_key_name(&user_name);
+    show(
+        pamh,
+        flags,
+        "Authenticate with Windows Hello to create a new key, and then once more to sign with it.",
+    );
+    let created = create_key(&config, &new_key_name)?;
+    let result = verify_created_key(&config, &user_name, &new_key_name, &created)
+        .and_then(|()| match enrolled && old_key_name != new_key_name {
+            true => retire_key(&user_name, &old_key_name),
+            false => Ok(()),
+        })
+        .and_then(|()| install_key(&user_name, &new_key_name, &created));
+    created.remove_files();
+    result?;
+
+    if enrolled && old_key_name != new_key_name {
+        for extension in &["pem", "attestation", "chain"] {
+            let _ = fs::remove_file(keys::key_file_path(&old_key_name, extension));
+        }
+        // The key is switched by now, so the old credential would only clutter Windows Hello
+        if let Err(err) = delete_credential(&config, &old_key_name, true) {
+            syslog(
+                pamh,
+                LOG_WARNING,
+                &format!(
+                    "cannot delete the Windows Hello credential {}; {}",
+                    old_key_name, err
+                ),
+            );
+        }
+    }
+    syslog(
+        pamh,
+        LOG_NOTICE,
+        &format!(
+            "the key of {} is rotated from {} to {}",
+            user_name,
+            if enrolled {
+                old_key_name.as_str()
+            } else {
+                "none"
+            },
+            new_key_name
+        ),
+    );
+    show(pamh, flags, "Your Windows Hello key is rotated.");
+    Ok(PAM_SUCCESS)
+}
+
+/// Returns a credential name for a new key of `user_name`, which differs from the names of its
+/// previous keys so that creating it never fails or replaces one of them
+fn new_key_name(user_name: &str) -> String {
+    format!(
+        "{}_{}",
+        keys::credential_key_name(user_name),
+        &Uuid::new_v4().simple().to_string()[..8]
+    )
+}
+
+/// Adds the key that is replaced to the retired keys, so that its approvals in the audit log are
+/// still verified
+fn retire_key(user_name: &str, key_name: &str) -> Result<(), RotationError> {
+    // A key that cannot be parsed never approved anything
+    let key = match keys::parse_public_key(&fs::read(keys::key_file_path(key_name, "pem"))?) {
+        Ok(key) => key,
+        Err(_) => return Ok(()),
+    };
+    let fingerprint = keys::fingerprint(&key)
+        .map_err(|err| RotationError::RetireError(AuditLogError::OpenSsl(err)))?;
+    let note = format!("{} {} rotated", Date::today(), user_name);
+    audit_log::retire_key(&fingerprint, &note).map_err(RotationError::RetireError)
+}
+
+fn show(pamh: *mut pam_handle_t, flags: c_int, message: &str) {
+    if (flags & PAM_SILENT) == 0 {
+        let _ = conversation::send_message(pamh, PAM_TEXT_INFO, message);
+    }
+}
+
+/// The files that `WindowsHelloBridge.exe creator` writes next to itself
+struct CreatedKey {
+    pem: PathBuf,
+    attestation: PathBuf,
+    chain: PathBuf,
+}
+
+impl CreatedKey {
+    fn remove_files(&self) {
+        for path in &[&self.pem, &self.attestation, &self.chain] {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn create_key(config: &Config, key_name: &str) -> Result<CreatedKey, RotationError> {
+    let bridge_dir = Path::new(&config.authenticator_path)
+        .parent()
+        .unwrap_or_else(|| Path::new("/"));
+    let output = Command::new(&config.authenticator_path)
+        .arg("creator")
+        .arg(key_name)
+        .current_dir(bridge_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .output()
+        .map_err(RotationError::CreatorLaunchError)?;
+    if !output.status.success() {
+        return Err(RotationError::CreatorFailed(
+            String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+        ));
+    }
+    Ok(CreatedKey {
+        pem: bridge_dir.join(format!("{}.pem", key_name)),
+        attestation: bridge_dir.join(format!("{}.attestation", key_name)),
+        chain: bridge_dir.join(format!("{}.chain", key_name)),
+    })
+}
+
+/// Deletes the Windows Hello credential `key_name` with `WindowsHelloBridge.exe delete`, which
+/// asks the user on the terminal to confirm unless `confirmed`
+pub fn delete_credential(config: &Config, key_name: &str, confirmed: bool) -> Result<(), String> {
+    let bridge_dir = Path::new(&config.authenticator_path)
+        .parent()
+        .unwrap_or_else(|| Path::new("/"));
+    let mut command = Command::new(&config.authenticator_path);
+    command.arg("delete").arg(key_name).current_dir(bridge_dir);
+    if confirmed {
+        command
+            .arg("--yes")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+    }
+    let output = command
+        .output()
+        .map_err(|err| format!("cannot launch '{}'; {}", config.authenticator_path, err))?;
+    if output.status.success() {
+        Ok(())
+    } else if output.stdout.is_empty() {
+        Err(format!("the bridge exited with {}", output.status))
+    } else {
+        Err(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+}
+
+/// Makes sure that the created credential really signs with the created public key before the
+/// enrolled key is replaced, so that a failed rotation never locks the user out
+fn verify_created_key(
+    config: &Config,
+    user_name: &str,
+    key_name: &str,
+    created: &CreatedKey,
+) -> Result<(), RotationError> {
+    let key = keys::parse_public_key(&fs::read(&created.pem)?)
+        .map_err(RotationError::InvalidPublicKey)?;
+
+    // Like at enrollment, the key must be attested whenever roots are configured, even if
+    // authentication does not check it every time
+    if let Some(ref roots) = config.attestation_roots {
+        let roots = attestation::load_roots(roots).map_err(RotationError::AttestationError)?;
+        Attestation::from_bytes(fs::read(&created.attestation)?, &fs::read(&created.chain)?)
+            .and_then(|attestation| attestation.verify(&key, &roots))
+            .map_err(RotationError::AttestationError)?;
+    }
+
+    let challenge = auth::new_envelope(config, user_name).to_string();
+    let request =
+        auth::new_request(key_name, &challenge, None).map_err(RotationError::SignatureError)?;
+    let response = {
+        let _interruption = Interruption::catch();
+        auth::request_signature(config, &request).map_err(RotationError::SignatureError)?
+    };
+    match auth::verify_signature(&key, &challenge, &response.signature) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(RotationError::SignatureMismatch),
+        Err(err) => Err(RotationError::SignatureError(err)),
+    }
+}
+
+/// Copies the created key into `public_keys` under its own name and then points the metadata
+/// of the user at it. Renaming the metadata file is the single step that switches keys, so
+/// authentication sees either the old key or the new one.
+fn install_key(user_name: &str, key_name: &str, created: &CreatedKey) -> Result<(), RotationError> {
+    install_key_in(
+        Path::new(keys::PUBLIC_KEYS_DIR),
+        user_name,
+        key_name,
+        created,
+    )
+}
+
+/// Installs the created key in `dir` like `install_key`, and removes what it installed if it
+/// fails before the switch, which leaves the old key enrolled
+fn install_key_in(
+    dir: &Path,
+    user_name: &str,
+    key_name: &str,
+    created: &CreatedKey,
+) -> Result<(), RotationError> {
+    let mut files = vec![(&created.pem, "pem")];
+    if created.attestation.exists() && created.chain.exists() {
+        files.push((&created.attestation, "attestation"));
+        files.push((&created.chain, "chain"));
+    }
+    let metadata_path = dir.join(format!("{}.meta", keys::credential_key_name(user_name)));
+    let temporary_path = metadata_path.with_extension("meta.tmp");
+
+    let metadata = KeyMetadata {
+        created: Some(Date::today()),
+        expires: None,
+        key_name: Some(key_name.to_owned()),
+    };
+    let installed = files
+        .iter()
+        .try_for_each(|&(from, extension)| {
+            install_file(from, &dir.join(format!("{}.{}", key_name, extension)))
+        })
+        .and_then(|()| {
+            fs::write(&temporary_path, metadata.to_toml())?;
+            fs::set_permissions(&temporary_path, fs::Permissions::from_mode(0o644))?;
+            fs::rename(&temporary_path, &metadata_path)?;
+            Ok(())
+        });
+    if installed.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+        for &(_, extension) in &files {
+            let _ = fs::remove_file(dir.join(format!("{}.{}", key_name, extension)));
+        }
+    }
+    installed
+}
+
+/// Copies a file from the bridge directory with permissions that authentication accepts
+/// whatever the umask
+fn install_file(from: &Path, to: &Path) -> Result<(), RotationError> {
+    fs::copy(from, to)?;
+    fs::set_permissions(to, fs::Permissions::from_mode(0o644))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// A directory of its own for `test`, with the files that `creator` writes for `key_name`
+    fn setup(test: &str, key_name: &str) -> (PathBuf, CreatedKey) {
+        let dir =
+            std::env::temp_dir().join(format!("pam_wsl_hello_rotation_{}_{}", test, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let bridge_dir = dir.join("bridge");
+        fs::create_dir_all(&bridge_dir).unwrap();
+        let created = CreatedKey {
+            pem: bridge_dir.join(format!("{}.pem", key_name)),
+            attestation: bridge_dir.join(format!("{}.attestation", key_name)),
+            chain: bridge_dir.join(format!("{}.chain", key_name)),
+        };
+        fs::write(&created.pem, "new key").unwrap();
+        fs::write(&created.attestation, "attestation").unwrap();
+        (dir, created)
+    }
+
+    fn metadata_path(dir: &Path) -> PathBuf {
+        dir.join("pam_wsl_hello_alice.meta")
+    }
+
+    #[test]
+    fn names_new_keys_after_the_user() {
+        let name = new_key_name("alice");
+        let suffix = name.strip_prefix("pam_wsl_hello_alice_").unwrap();
+        assert_eq!(suffix.len(), 8);
+        assert!(suffix.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(new_key_name("alice"), name);
+    }
+
+    #[test]
+    fn switches_the_metadata_to_the_installed_key() {
+        let (dir, created) = setup("install", "pam_wsl_hello_alice_1a2b3c4d");
+        fs::write(&created.chain, "chain").unwrap();
+        fs::write(
+            metadata_path(&dir),
+            "key_name = \"pam_wsl_hello_alice_00000000\"\n",
+        )
+        .unwrap();
+
+        install_key_in(&dir, "alice", "pam_wsl_hello_alice_1a2b3c4d", &created).unwrap();
+        let metadata =
+            KeyMetadata::parse(&fs::read_to_string(metadata_path(&dir)).unwrap()).unwrap();
+        assert_eq!(
+            metadata.credential_key_name("alice"),
+            "pam_wsl_hello_alice_1a2b3c4d"
+        );
+        assert_eq!(metadata.created, Some(Date::today()));
+        for extension in &["pem", "attestation", "chain", "meta"] {
+            let path = match *extension {
+                "meta" => metadata_path(&dir),
+                _ => dir.join(format!("pam_wsl_hello_alice_1a2b3c4d.{}", extension)),
+            };
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o644, "{}", path.display());
+        }
+        assert_eq!(
+            fs::read_to_string(dir.join("pam_wsl_hello_alice_1a2b3c4d.pem")).unwrap(),
+            "new key"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_old_key_when_the_install_fails() {
+        let (dir, created) = setup("rollback", "pam_wsl_hello_alice_1a2b3c4d");
+        // A directory cannot be copied, so the chain fails after the key and its attestation
+        fs::create_dir(&created.chain).unwrap();
+        let old_metadata = "key_name = \"pam_wsl_hello_alice_00000000\"\n";
+        fs::write(metadata_path(&dir), old_metadata).unwrap();
+
+        assert!(install_key_in(&dir, "alice", "pam_wsl_hello_alice_1a2b3c4d", &created).is_err());
+        assert_eq!(
+            fs::read_to_string(metadata_path(&dir)).unwrap(),
+            old_metadata
+        );
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["bridge", "pam_wsl_hello_alice.meta"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+use libc;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::ptr;
+use std::time::Duration;
+
+/// How often a supervisor checks whether the program it supervises has exited
+const SUPERVISOR_POLL_INTERVAL: libc::timespec = libc::timespec {
+    tv_sec: 0,
+    tv_nsec: 50_000_000,
+};
+
+/// Makes `command` start in a session and process group of its own, so that it and everything it
+/// starts can be killed together, and run as `uid` and `gid` without supplementary groups
+pub fn run_as(command: &mut Command, uid: libc::uid_t, gid: libc::gid_t) {
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::geteuid() != uid
+                && (libc::setgroups(0, std::ptr::null()) != 0
+                    || libc::setgid(gid) != 0
+                    || libc::setuid(uid) != 0)
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+}
+
+/// Kills the process group of `pid`, which `run_as` made the leader of its group. The process must
+/// not be reaped yet, or its ID may belong to another group already.
+pub fn kill_group(pid: u32) {
+    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+}
+
+/// Starts `program` with `args` and `env` as `uid` and `gid` in a process group of its own, and
+/// returns without waiting for it. A supervisor that is detached from the caller kills the group
+/// once `timeout` has passed or the program has exited, so nothing that the program starts keeps
+/// running, and the caller is left with no child process or thread.
+///
+/// The process is forked twice, and the intermediate child exits at once, so the supervisor is
+/// adopted by init. Since the caller may have other threads, the forked processes only make
+/// async-signal-safe calls; everything is allocated before. The standard streams of the program
+/// are `/dev/null`, and no other descriptor of the caller is inherited.
+pub fn spawn_supervised(
+    program: &CStr,
+    args: &[CString],
+    env: &[CString],
+    (uid, gid): (libc::uid_t, libc::gid_t),
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut argv: Vec<*const libc::c_char> = Some(program)
+        .into_iter()
+        .chain(args.iter().map(CString::as_c_str))
+        .map(CStr::as_ptr)
+        .collect();
+    argv.push(ptr::null());
+    let mut envp: Vec<*const libc::c_char> = env.iter().map(|value| value.as_ptr()).collect();
+    envp.push(ptr::null());
+    let dev_null = b"/dev/null\0".as_ptr() as *const libc::c_char;
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    };
+
+    let intermediate = unsafe { libc::fork() };
+    if intermediate < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if intermediate == 0 {
+        unsafe {
+            if libc::fork() == 0 {
+                supervise(
+                    program.as_ptr(),
+                    &argv,
+                    &envp,
+                    dev_null,
+                    (uid, gid),
+                    timeout,
+                );
+            }
+            libc::_exit(0)
+        }
+    }
+
+    let mut status = 0;
+    while unsafe { libc::waitpid(intermediate, &mut status, 0) } < 0 {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            // The application reaped it already, e.g. in a SIGCHLD handler
+            Some(libc::ECHILD) => break,
+            _ => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Runs in the supervisor, which never returns
+unsafe fn supervise(
+    program: *const libc::c_char,
+    argv: &[*const libc::c_char],
+    envp: &[*const libc::c_char],
+    dev_null: *const libc::c_char,
+    (uid, gid): (libc::uid_t, libc::gid_t),
+    timeout: libc::timespec,
+) -> ! {
+    // Leaves the session of the caller, so that signals from its terminal do not reach the program
+    libc::setsid();
+    let null = libc::open(dev_null, libc::O_RDWR);
+    if null < 0 {
+        libc::_exit(1);
+    }
+    for fd in 0..3 {
+        libc::dup2(null, fd);
+    }
+    close_descriptors_from(3);
+    let mut deadline: libc::timespec = std::mem::zeroed();
+    libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut deadline);
+    deadline.tv_sec = deadline.tv_sec.saturating_add(timeout.tv_sec);
+    deadline.tv_nsec += timeout.tv_nsec;
+    if deadline.tv_nsec >= 1_000_000_000 {
+        deadline.tv_sec = deadline.tv_sec.saturating_add(1);
+        deadline.tv_nsec -= 1_000_000_000;
+    }
+
+    let pid = libc::fork();
+    if pid < 0 {
+        libc::_exit(1);
+    }
+    if pid == 0 {
+        libc::setpgid(0, 0);
+        let mut no_signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut no_signals);
+        libc::sigprocmask(libc::SIG_SETMASK, &no_signals, ptr::null_mut());
+        if libc::chdir(b"/\0".as_ptr() as *const libc::c_char) != 0
+            || (libc::geteuid() != uid
+                && (libc::setgroups(0, ptr::null()) != 0
+                    || libc::setgid(gid) != 0
+                    || libc::setuid(uid) != 0))
+        {
+            libc::_exit(127);
+        }
+        libc::execve(program, argv.as_ptr(), envp.as_ptr());
+        libc::_exit(127);
+    }
+    // Both set the group, so that it exists before it may be killed
+    libc::setpgid(pid, pid);
+
+    loop {
+        // WNOWAIT leaves the program a zombie, which keeps its process group ID from being reused
+        let mut info: libc::siginfo_t = std::mem::zeroed();
+        let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        if libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, options) != 0
+            || info.si_pid() != 0
+        {
+            break;
+        }
+        let mut now: libc::timespec = std::mem::zeroed();
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
+        if (now.tv_sec, now.tv_nsec) >= (deadline.tv_sec, deadline.tv_nsec) {
+            break;
+        }
+        libc::nanosleep(&SUPERVISOR_POLL_INTERVAL, ptr::null_mut());
+    }
+    libc::kill(-pid, libc::SIGKILL);
+    libc::waitpid(pid, ptr::null_mut(), 0);
+    libc::_exit(0)
+}
+
+/// Closes every descriptor from `first` on
+unsafe fn close_descriptors_from(first: libc::c_int) {
+    let max = match libc::sysconf(libc::_SC_OPEN_MAX) {
+        max if max > 0 => max.min(65536) as libc::c_int,
+        _ => 1024,
+    };
+    for fd in first..max {
+        libc::close(fd);
+    }
+}
+
+/// Returns the user and group IDs of `user_name`
+pub fn lookup_user(user_name: &str) -> Option<(libc::uid_t, libc::gid_t)> {
+    let user_name = CString::new(user_name).ok()?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let err = unsafe {
+        libc::getpwnam_r(
+            user_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if err != 0 || result.is_null() {
+        return None;
+    }
+    Some((passwd.pw_uid, passwd.pw_gid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kills_supervised_programs_after_the_timeout() {
+        let pid_file =
+            std::env::temp_dir().join(format!("pam_wsl_hello_spawn_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&pid_file);
+        let script = format!("sleep 30 & echo $! > {}; sleep 30", pid_file.display());
+        let started = std::time::Instant::now();
+        spawn_supervised(
+            &CString::new("/bin/sh").unwrap(),
+            &[CString::new("-c").unwrap(), CString::new(script).unwrap()],
+            &[CString::new("PATH=/usr/bin:/bin").unwrap()],
+            unsafe { (libc::geteuid(), libc::getegid()) },
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let running = |pid: &str| {
+            std::fs::read_to_string(format!("/proc/{}/stat", pid))
+                .is_ok_and(|stat| !stat.contains(") Z "))
+        };
+        let mut pid = String::new();
+        for _ in 0..100 {
+            std::thread::sleep(Duration::from_millis(10));
+            pid = std::fs::read_to_string(&pid_file).unwrap_or_default();
+            if pid.ends_with('\n') {
+                break;
+            }
+        }
+        let pid = pid.trim();
+        assert!(running(pid));
+        // The background process of the program is killed with it
+        let dead = (0..300).any(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            !running(pid)
+        });
+        std::fs::remove_file(&pid_file).unwrap();
+        assert!(dead);
+    }
+
+    #[test]
+    fn looks_up_users() {
+        assert_eq!(lookup_user("root"), Some((0, 0)));
+        assert_eq!(lookup_user("no such user"), None);
+    }
+}
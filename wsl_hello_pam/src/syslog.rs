@@ -0,0 +1,17 @@
+use bindings::{pam_handle_t, pam_syslog};
+use libc::{c_char, c_int};
+use std::ffi::CString;
+
+/// Writes `message` to the system log via `pam_syslog`, which prefixes it with the module and
+/// service name like every other PAM module does
+pub fn syslog(pamh: *mut pam_handle_t, priority: c_int, message: &str) {
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    unsafe {
+        pam_syslog(
+            pamh,
+            priority,
+            b"%s\0".as_ptr() as *const c_char,
+            message.as_ptr(),
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use config::Config;
+use openssl::hash::{hash, MessageDigest};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Where the module remembers that Windows Hello cannot be used, one file per bridge and Windows
+/// user. It is on a tmpfs, so that a reboot or a new WSL instance tries again.
+pub const UNAVAILABLE_DIR: &str = "/run/pam_wsl_hello/unavailable";
+
+/// Why Windows Hello cannot be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailability {
+    /// The bridge reports that the device has no Windows Hello
+    Unsupported,
+    /// The bridge cannot be launched, e.g. because the interop with Windows is disabled
+    Unreachable,
+}
+
+impl Unavailability {
+    fn parse(value: &str) -> Option<Unavailability> {
+        match value {
+            "unsupported" => Some(Unavailability::Unsupported),
+            "unreachable" => Some(Unavailability::Unreachable),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Unavailability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Unavailability::Unsupported => "unsupported",
+            Unavailability::Unreachable => "unreachable",
+        })
+    }
+}
+
+/// The cache entry of a bridge and the Windows user that runs it
+pub struct UnavailableCache {
+    path: PathBuf,
+}
+
+impl UnavailableCache {
+    pub fn new(config: &Config) -> UnavailableCache {
+        UnavailableCache::in_dir(
+            Path::new(UNAVAILABLE_DIR),
+            &config.authenticator_path,
+            windows_user(&config.authenticator_path, &config.win_mnt),
+        )
+    }
+
+    fn in_dir(
+        dir: &Path,
+        authenticator_path: &str,
+        windows_user: Option<&str>,
+    ) -> UnavailableCache {
+        let key = format!("{}\n{}", authenticator_path, windows_user.unwrap_or(""));
+        let name: String = hash(MessageDigest::sha256(), key.as_bytes())
+            .map(|digest| digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+            .unwrap_or_else(|_| "default".to_owned());
+        UnavailableCache {
+            path: dir.join(name),
+        }
+    }
+
+    /// Returns why Windows Hello is unavailable and for how many more seconds that is assumed,
+    /// if it is at `now`
+    pub fn lookup(&self, now: u64) -> Option<(Unavailability, u64)> {
+        let value = fs::read_to_string(&self.path).ok()?.parse::<Value>().ok()?;
+        let reason = Unavailability::parse(value.get("reason")?.as_str()?)?;
+        let until = u64::try_from(value.get("until")?.as_integer()?).ok()?;
+        match until.checked_sub(now) {
+            Some(remaining) if remaining > 0 => Some((reason, remaining)),
+            _ => None,
+        }
+    }
+
+    /// Remembers that Windows Hello is unavailable for `reason` until `until`, in seconds since
+    /// the UNIX epoch
+    pub fn remember(&self, reason: Unavailability, until: u64) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(
+            &temporary_path,
+            format!("reason = \"{}\"\nuntil = {}\n", reason, until),
+        )?;
+        fs::rename(&temporary_path, &self.path)
+    }
+}
+
+/// Returns the Windows user whose profile the bridge is installed in, which `install.sh` does
+fn windows_user<'a>(authenticator_path: &'a str, win_mnt: &str) -> Option<&'a str> {
+    let profiles = format!("{}/Users/", win_mnt.trim_end_matches('/'));
+    authenticator_path
+        .strip_prefix(&profiles)?
+        .split('/')
+        .next()
+        .filter(|user| !user.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn remembers_unavailability_for_a_while() {
+        let dir = env::temp_dir().join(format!("pam_wsl_hello_unavailable_{}", std::process::id()));
+        let path =
+            "/mnt/c/Users/alice/AppData/Local/Programs/wsl-hello-sudo/WindowsHelloBridge.exe";
+        assert_eq!(windows_user(path, "/mnt/c/"), Some("alice"));
+        assert_eq!(windows_user("/opt/WindowsHelloBridge.exe", "/mnt/c"), None);
+
+        let cache = UnavailableCache::in_dir(&dir, path, Some("alice"));
+        assert_eq!(cache.lookup(1000), None);
+        cache.remember(Unavailability::Unreachable, 1300).unwrap();
+        assert_eq!(cache.lookup(1000), Some((Unavailability::Unreachable, 300)));
+        assert_eq!(cache.lookup(1300), None);
+        assert_eq!(
+            UnavailableCache::in_dir(&dir, path, Some("bob")).lookup(1000),
+            None
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
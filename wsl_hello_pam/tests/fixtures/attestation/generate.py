@@ -0,0 +1,145 @@
+#!/usr/bin/env python3
+"""Generates the attestation fixtures: a root, an intermediate and an attestation identity key
+certificate like those of a TPM vendor, and key attestations in the format of the Platform Crypto
+Provider that the attestation identity key signed about TPM keys.
+
+Run it in this directory; it needs the `cryptography` package.
+"""
+
+import datetime
+import hashlib
+import struct
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import padding, rsa
+from cryptography.x509.oid import ExtendedKeyUsageOID, NameOID
+
+AIK_CERTIFICATE = x509.ObjectIdentifier("2.23.133.8.3")
+NOT_BEFORE = datetime.datetime(2020, 1, 1)
+NOT_AFTER = datetime.datetime(2099, 1, 1)
+
+TPM_ALG_RSA = 0x0001
+TPM_ALG_SHA256 = 0x000B
+TPM_ALG_NULL = 0x0010
+TPM_ALG_RSASSA = 0x0014
+# fixedTPM, fixedParent, sensitiveDataOrigin, userWithAuth, noDA and sign
+TPM_KEY_ATTRIBUTES = 0x00040472
+# The same without fixedTPM and sensitiveDataOrigin, as for an imported key
+IMPORTED_KEY_ATTRIBUTES = TPM_KEY_ATTRIBUTES & ~(1 << 1) & ~(1 << 5)
+
+
+def key():
+    return rsa.generate_private_key(public_exponent=65537, key_size=2048)
+
+
+def certificate(name, key, issuer=None, ca=False, usage=None):
+    issuer_name, issuer_key = issuer if issuer else (name, key)
+    builder = (
+        x509.CertificateBuilder()
+        .subject_name(x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, name)]))
+        .issuer_name(x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, issuer_name)]))
+        .public_key(key.public_key())
+        .serial_number(x509.random_serial_number())
+        .not_valid_before(NOT_BEFORE)
+        .not_valid_after(NOT_AFTER)
+        .add_extension(x509.BasicConstraints(ca=ca, path_length=None), critical=True)
+        .add_extension(
+            x509.KeyUsage(
+                digital_signature=not ca,
+                content_commitment=False,
+                key_encipherment=False,
+                data_encipherment=False,
+                key_agreement=False,
+                key_cert_sign=ca,
+                crl_sign=ca,
+                encipher_only=False,
+                decipher_only=False,
+            ),
+            critical=True,
+        )
+    )
+    if usage:
+        builder = builder.add_extension(x509.ExtendedKeyUsage([usage]), critical=False)
+    return builder.sign(issuer_key, hashes.SHA256())
+
+
+def sized(data):
+    return struct.pack(">H", len(data)) + data
+
+
+def public_area(key, attributes):
+    numbers = key.public_key().public_numbers()
+    return (
+        struct.pack(">HHI", TPM_ALG_RSA, TPM_ALG_SHA256, attributes)
+        + sized(b"")
+        + struct.pack(">HHHI", TPM_ALG_NULL, TPM_ALG_NULL, 2048, 0)
+        + sized(numbers.n.to_bytes(256, "big"))
+    )
+
+
+def key_attestation(key, signer, attributes=TPM_KEY_ATTRIBUTES):
+    public = public_area(key, attributes)
+    name = struct.pack(">H", TPM_ALG_SHA256) + hashlib.sha256(public).digest()
+    certify_info = (
+        struct.pack(">IH", 0xFF544347, 0x8017)
+        + sized(b"\x00\x0b" + bytes(32))
+        + sized(b"")
+        + bytes(17)
+        + bytes(8)
+        + sized(name)
+        + sized(name)
+    )
+    signature = struct.pack(">HH", TPM_ALG_RSASSA, TPM_ALG_SHA256) + sized(
+        signer.sign(certify_info, padding.PKCS1v15(), hashes.SHA256())
+    )
+    blob_header = struct.pack("<4sIIIIIIII", b"PCPM", 36, 3, 0, 2 + len(public), 0, 0, 0, 0)
+    key_blob = blob_header + sized(public)
+    header = struct.pack(
+        "<4sIIIII", b"KADS", 2, 24, len(certify_info), len(signature), len(key_blob)
+    )
+    return header + certify_info + signature + key_blob
+
+
+def write_certificate(name, certificate):
+    with open(name + ".pem", "wb") as file:
+        file.write(certificate.public_bytes(serialization.Encoding.PEM))
+
+
+def write_public_key(name, key):
+    with open(name + ".pem", "wb") as file:
+        file.write(
+            key.public_key().public_bytes(
+                serialization.Encoding.PEM, serialization.PublicFormat.SubjectPublicKeyInfo
+            )
+        )
+
+
+def write_attestation(name, statement):
+    with open(name + ".attestation", "wb") as file:
+        file.write(statement)
+
+
+root_key, other_root_key, intermediate_key, aik_key, not_aik_key = (key() for _ in range(5))
+hello_key, other_key, forger_key = (key() for _ in range(3))
+
+write_certificate("root", certificate("TPM Root", root_key, ca=True))
+write_certificate("other_root", certificate("Other TPM Root", other_root_key, ca=True))
+write_certificate(
+    "intermediate",
+    certificate("TPM Intermediate", intermediate_key, ("TPM Root", root_key), ca=True),
+)
+intermediate = ("TPM Intermediate", intermediate_key)
+write_certificate("aik", certificate("AIK", aik_key, intermediate, usage=AIK_CERTIFICATE))
+write_certificate(
+    "not_aik",
+    certificate("Server", not_aik_key, intermediate, usage=ExtendedKeyUsageOID.SERVER_AUTH),
+)
+write_public_key("hello_key", hello_key)
+write_public_key("other_key", other_key)
+
+write_attestation("hello_key", key_attestation(hello_key, aik_key))
+write_attestation("other_key", key_attestation(other_key, aik_key))
+write_attestation("forged", key_attestation(hello_key, forger_key))
+write_attestation("not_aik", key_attestation(hello_key, not_aik_key))
+write_attestation("imported_key", key_attestation(hello_key, aik_key, IMPORTED_KEY_ATTRIBUTES))
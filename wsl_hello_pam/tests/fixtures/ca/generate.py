@@ -0,0 +1,149 @@
+#!/usr/bin/env python3
+"""Generates the enterprise CA fixtures: a root CA, two issuing intermediate CAs, user
+certificates of them, a CRL of the root that revokes mallory and one of the intermediates, and
+a CRL of the other intermediate that revokes its own mallory, and a CRL that claims to be of
+that intermediate but is signed by another key.
+
+Run it in this directory; it needs the `cryptography` package.
+"""
+
+import datetime
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import rsa
+from cryptography.x509.oid import ExtendedKeyUsageOID, NameOID
+
+NOT_BEFORE = datetime.datetime(2020, 1, 1)
+NOT_AFTER = datetime.datetime(2120, 1, 1)
+SMARTCARD_LOGON = x509.ObjectIdentifier("1.3.6.1.4.1.311.20.2.2")
+MALLORY_SERIAL = 1001
+MALLORY_OF_INTERMEDIATE_SERIAL = 1002
+REVOKED_INTERMEDIATE_SERIAL = 1003
+
+
+def key():
+    return rsa.generate_private_key(public_exponent=65537, key_size=2048)
+
+
+def name(common_name):
+    return x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, common_name)])
+
+
+def key_usage(digital_signature=False, key_encipherment=False, ca=False):
+    return x509.KeyUsage(
+        digital_signature=digital_signature,
+        content_commitment=False,
+        key_encipherment=key_encipherment,
+        data_encipherment=False,
+        key_agreement=False,
+        key_cert_sign=ca,
+        crl_sign=ca,
+        encipher_only=False,
+        decipher_only=False,
+    )
+
+
+def ca_certificate(common_name, key, issuer=None, serial=None):
+    issuer_name, issuer_key = issuer if issuer else (common_name, key)
+    return (
+        x509.CertificateBuilder()
+        .subject_name(name(common_name))
+        .issuer_name(name(issuer_name))
+        .public_key(key.public_key())
+        .serial_number(serial or x509.random_serial_number())
+        .not_valid_before(NOT_BEFORE)
+        .not_valid_after(NOT_AFTER)
+        .add_extension(x509.BasicConstraints(ca=True, path_length=None), critical=True)
+        .add_extension(key_usage(ca=True), critical=True)
+        .sign(issuer_key, hashes.SHA256())
+    )
+
+
+def user_certificate(
+    user,
+    issuer,
+    serial=None,
+    not_after=NOT_AFTER,
+    usage=key_usage(digital_signature=True),
+    extended_usages=(ExtendedKeyUsageOID.CLIENT_AUTH,),
+):
+    issuer_name, issuer_key = issuer
+    builder = (
+        x509.CertificateBuilder()
+        .subject_name(name(user))
+        .issuer_name(name(issuer_name))
+        .public_key(key().public_key())
+        .serial_number(serial or x509.random_serial_number())
+        .not_valid_before(NOT_BEFORE)
+        .not_valid_after(not_after)
+        .add_extension(x509.BasicConstraints(ca=False, path_length=None), critical=True)
+        .add_extension(usage, critical=True)
+        .add_extension(
+            x509.SubjectAlternativeName([x509.RFC822Name(user + "@example.com")]),
+            critical=False,
+        )
+    )
+    if extended_usages:
+        builder = builder.add_extension(
+            x509.ExtendedKeyUsage(list(extended_usages)), critical=False
+        )
+    return builder.sign(issuer_key, hashes.SHA256())
+
+
+def crl(issuer, revoked_serials):
+    issuer_name, issuer_key = issuer
+    now = datetime.datetime(2020, 1, 1)
+    builder = (
+        x509.CertificateRevocationListBuilder()
+        .issuer_name(name(issuer_name))
+        .last_update(now)
+        .next_update(NOT_AFTER)
+    )
+    for serial in revoked_serials:
+        builder = builder.add_revoked_certificate(
+            x509.RevokedCertificateBuilder().serial_number(serial).revocation_date(now).build()
+        )
+    return builder.sign(issuer_key, hashes.SHA256())
+
+
+def write(file_name, obj):
+    with open(file_name, "wb") as file:
+        file.write(obj.public_bytes(serialization.Encoding.PEM))
+
+
+ca_key, intermediate_key, revoked_intermediate_key, self_signed_key = key(), key(), key(), key()
+ca = ("Test Enterprise CA", ca_key)
+intermediate = ("Test Issuing CA", intermediate_key)
+revoked_intermediate = ("Test Revoked Issuing CA", revoked_intermediate_key)
+
+write("ca.pem", ca_certificate(*ca))
+write("intermediate.pem", ca_certificate(*intermediate, issuer=ca))
+write("alice.pem", user_certificate("alice", ca))
+write("alice_of_intermediate.pem", user_certificate("alice", intermediate))
+write(
+    "mallory_of_intermediate.pem",
+    user_certificate("mallory", intermediate, serial=MALLORY_OF_INTERMEDIATE_SERIAL),
+)
+write(
+    "revoked_intermediate.pem",
+    ca_certificate(*revoked_intermediate, issuer=ca, serial=REVOKED_INTERMEDIATE_SERIAL),
+)
+write("alice_of_revoked_intermediate.pem", user_certificate("alice", revoked_intermediate))
+write("smartcard.pem", user_certificate("alice", ca, extended_usages=(SMARTCARD_LOGON,)))
+write("mallory.pem", user_certificate("mallory", ca, serial=MALLORY_SERIAL))
+write("expired.pem", user_certificate("alice", ca, not_after=datetime.datetime(2021, 1, 1)))
+write(
+    "server.pem",
+    user_certificate("alice", ca, extended_usages=(ExtendedKeyUsageOID.SERVER_AUTH,)),
+)
+write("no_extended_usage.pem", user_certificate("alice", ca, extended_usages=()))
+write(
+    "encipherment.pem",
+    user_certificate("alice", ca, usage=key_usage(key_encipherment=True)),
+)
+write("self_signed.pem", ca_certificate("alice", self_signed_key))
+
+write("crl.pem", crl(ca, [MALLORY_SERIAL, REVOKED_INTERMEDIATE_SERIAL]))
+write("intermediate_crl.pem", crl(intermediate, [MALLORY_OF_INTERMEDIATE_SERIAL]))
+write("forged_crl.pem", crl((intermediate[0], self_signed_key), []))
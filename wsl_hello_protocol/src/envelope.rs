@@ -0,0 +1,267 @@
+use crate::prompt_message::PromptContext;
+use std::fmt;
+
+/// The first word of every challenge, which names the format and its version
+const MAGIC: &str = "wsl-hello-challenge";
+const VERSION: u32 = 1;
+
+/// The longest envelope that is accepted, in bytes
+const MAX_ENVELOPE_LEN: usize = 2048;
+
+/// A challenge that the PAM module asks Windows Hello to sign, with the context of the request.
+///
+/// It is a single line like
+/// `wsl-hello-challenge/1 nonce=<uuid> issued=<seconds> user=alice service=sudo tty=/dev/pts/3`
+/// in which values are percent-encoded. The bridge signs nothing else, so a signature for the
+/// PAM module can never be made over data that means something to another program, and the user
+/// sees what is signed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// Random, so that every signature is made over a different challenge
+    pub nonce: String,
+    /// Seconds since the UNIX epoch when the challenge was made
+    pub issued: u64,
+    /// The Linux user to be authenticated
+    pub user: String,
+    /// The PAM service, e.g. `sudo`
+    pub service: Option<String>,
+    /// The WSL distribution, which is also the host that signs the envelope for the bridge
+    pub distro: Option<String>,
+    pub tty: Option<String>,
+    pub rhost: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// The data is not a challenge envelope at all
+    NotAnEnvelope,
+    UnsupportedVersion(String),
+    TooLong,
+    MissingField(&'static str),
+    InvalidField(String),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::NotAnEnvelope => write!(f, "the data is not a challenge envelope"),
+            EnvelopeError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "the challenge envelope version '{}' is unsupported",
+                    version
+                )
+            }
+            EnvelopeError::TooLong => write!(f, "the challenge envelope is too long"),
+            EnvelopeError::MissingField(name) => {
+                write!(f, "the challenge envelope has no '{}'", name)
+            }
+            EnvelopeError::InvalidField(field) => {
+                write!(f, "the challenge envelope has an invalid field '{}'", field)
+            }
+        }
+    }
+}
+
+impl Envelope {
+    pub fn parse(data: &[u8]) -> Result<Envelope, EnvelopeError> {
+        if data.len() > MAX_ENVELOPE_LEN {
+            return Err(EnvelopeError::TooLong);
+        }
+        let data = std::str::from_utf8(data).map_err(|_| EnvelopeError::NotAnEnvelope)?;
+        let mut words = data.split(' ');
+        let version = words
+            .next()
+            .and_then(|word| word.strip_prefix(MAGIC))
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or(EnvelopeError::NotAnEnvelope)?;
+        if version != VERSION.to_string() {
+            return Err(EnvelopeError::UnsupportedVersion(version.to_owned()));
+        }
+
+        let mut envelope = Envelope::default();
+        let mut nonce = None;
+        let mut issued = None;
+        let mut user = None;
+        for word in words {
+            let invalid = || EnvelopeError::InvalidField(word.to_owned());
+            let (name, value) = word.split_once('=').ok_or_else(invalid)?;
+            let value = decode(value).ok_or_else(invalid)?;
+            let slot = match name {
+                "nonce" => &mut nonce,
+                "issued" => &mut issued,
+                "user" => &mut user,
+                "service" => &mut envelope.service,
+                "distro" => &mut envelope.distro,
+                "tty" => &mut envelope.tty,
+                "rhost" => &mut envelope.rhost,
+                _ => return Err(invalid()),
+            };
+            if slot.replace(value).is_some() {
+                return Err(invalid());
+            }
+        }
+        envelope.nonce = nonce.ok_or(EnvelopeError::MissingField("nonce"))?;
+        envelope.user = user.ok_or(EnvelopeError::MissingField("user"))?;
+        let issued = issued.ok_or(EnvelopeError::MissingField("issued"))?;
+        envelope.issued = issued
+            .parse()
+            .map_err(|_| EnvelopeError::InvalidField(format!("issued={}", issued)))?;
+
+        // Only the canonical form is accepted, so that an envelope has a single encoding
+        if envelope.to_string() != data {
+            return Err(EnvelopeError::InvalidField(data.to_owned()));
+        }
+        Ok(envelope)
+    }
+
+    /// Returns what the envelope tells about the application that asks for Windows Hello
+    pub fn prompt_context(&self) -> PromptContext<'_> {
+        PromptContext {
+            service: self.service.as_deref(),
+            distro: self.distro.as_deref(),
+            user: &self.user,
+            tty: self.tty.as_deref(),
+            rhost: self.rhost.as_deref(),
+        }
+    }
+
+    /// Describes the request for the user, like "sudo in Ubuntu-22.04 as alice on pts/3"
+    pub fn describe(&self) -> String {
+        self.prompt_context().to_message()
+    }
+}
+
+impl fmt::Display for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} nonce={} issued={} user={}",
+            MAGIC,
+            VERSION,
+            encode(&self.nonce),
+            self.issued,
+            encode(&self.user)
+        )?;
+        let optional = [
+            ("service", &self.service),
+            ("distro", &self.distro),
+            ("tty", &self.tty),
+            ("rhost", &self.rhost),
+        ];
+        for (name, value) in optional.iter() {
+            if let Some(value) = value {
+                write!(f, " {}={}", name, encode(value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Percent-encodes the bytes that would break the line into words
+fn encode(value: &str) -> String {
+    let mut encoded = Vec::with_capacity(value.len());
+    for &byte in value.as_bytes() {
+        if byte <= b' ' || byte == 0x7f || byte == b'%' || byte == b'=' {
+            encoded.extend_from_slice(format!("%{:02X}", byte).as_bytes());
+        } else {
+            encoded.push(byte);
+        }
+    }
+    String::from_utf8(encoded).expect("only ASCII bytes are encoded")
+}
+
+fn decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            nonce: "5f0c6a2e-7a8b-4c1d-9e2f-0a1b2c3d4e5f".to_owned(),
+            issued: 1_700_000_000,
+            user: "alice".to_owned(),
+            service: Some("sudo".to_owned()),
+            distro: Some("Ubuntu-22.04".to_owned()),
+            tty: Some("/dev/pts/3".to_owned()),
+            rhost: None,
+        }
+    }
+
+    #[test]
+    fn round_trips_envelopes() {
+        let envelope = envelope();
+        let line = envelope.to_string();
+        assert_eq!(
+            line,
+            "wsl-hello-challenge/1 nonce=5f0c6a2e-7a8b-4c1d-9e2f-0a1b2c3d4e5f issued=1700000000 \
+             user=alice service=sudo distro=Ubuntu-22.04 tty=/dev/pts/3"
+        );
+        assert_eq!(Envelope::parse(line.as_bytes()), Ok(envelope.clone()));
+
+        let envelope = Envelope {
+            user: "alice smith".to_owned(),
+            tty: Some("pts/1\nx=%y é".to_owned()),
+            ..envelope
+        };
+        let line = envelope.to_string();
+        assert!(line.contains(" user=alice%20smith "));
+        assert!(line.ends_with(" tty=pts/1%0Ax%3D%25y%20é"));
+        assert_eq!(Envelope::parse(line.as_bytes()), Ok(envelope));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        let line = envelope().to_string();
+        assert_eq!(
+            Envelope::parse(b"pam_wsl_hello:alice:1234"),
+            Err(EnvelopeError::NotAnEnvelope)
+        );
+        assert_eq!(
+            Envelope::parse(line.replacen("/1 ", "/2 ", 1).as_bytes()),
+            Err(EnvelopeError::UnsupportedVersion("2".to_owned()))
+        );
+        assert_eq!(
+            Envelope::parse(line.replace(" user=alice", "").as_bytes()),
+            Err(EnvelopeError::MissingField("user"))
+        );
+        for invalid in &[
+            format!("{} user=bob", line),
+            format!("{} command=rm", line),
+            format!("{}\n", line),
+            line.replace("tty=/dev/pts/3", "tty=%zz"),
+            line.replace("user=alice", "user=%61lice"),
+            line.replace("issued=1700000000", "issued=soon"),
+        ] {
+            assert!(Envelope::parse(invalid.as_bytes()).is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            Envelope::parse(format!("{} {}", line, "x".repeat(MAX_ENVELOPE_LEN)).as_bytes()),
+            Err(EnvelopeError::TooLong)
+        );
+    }
+
+    #[test]
+    fn describes_the_requesting_application() {
+        assert_eq!(
+            envelope().describe(),
+            "sudo in Ubuntu-22.04 as alice on pts/3"
+        );
+    }
+}
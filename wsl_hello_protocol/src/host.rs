@@ -0,0 +1,434 @@
+use crate::Envelope;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How old an envelope may be when the bridge checks it, in seconds
+pub const MAX_ENVELOPE_AGE_SECONDS: u64 = 600;
+
+/// How far the clock of the host may be ahead of the bridge, in seconds
+const MAX_CLOCK_SKEW_SECONDS: u64 = 60;
+
+/// The longest name of a host, which is the name of its WSL distribution
+const MAX_HOST_NAME_LEN: usize = 64;
+
+/// The first line of a list of registered hosts, which names its format and version
+const HOST_LIST_HEADER: &str = "wsl-hello-hosts/1";
+
+/// Checks RSASSA-PKCS1-v1_5 SHA-256 signatures, which is how hosts sign their envelopes. The bridge
+/// implements it with Windows' cryptography so that this crate has no dependencies.
+pub trait SignatureVerifier {
+    /// Whether `signature` is a signature of `data` by `public_key`, a DER SubjectPublicKeyInfo
+    fn verify(&self, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostError {
+    /// No host is registered, so no request can be verified
+    NotRegistered,
+    /// The list of registered hosts is not signed by the key that registers them
+    UntrustedList,
+    InvalidList,
+    /// The request carries no host signature
+    Unsigned,
+    /// The envelope does not name the distribution it comes from
+    NoHost,
+    UnknownHost(String),
+    BadSignature,
+    /// The envelope was issued too long ago, or in the future
+    Stale {
+        issued: u64,
+        now: u64,
+    },
+    InvalidHostName(String),
+    InvalidKey,
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostError::NotRegistered => write!(f, "no distro is registered with the bridge"),
+            HostError::UntrustedList => write!(
+                f,
+                "the list of registered distros is not signed by the Windows Hello key of the user"
+            ),
+            HostError::InvalidList => write!(f, "the list of registered distros is malformed"),
+            HostError::Unsigned => write!(f, "the request is not signed by its host"),
+            HostError::NoHost => write!(f, "the challenge envelope names no distro"),
+            HostError::UnknownHost(host) => write!(f, "the host '{}' is not registered", host),
+            HostError::BadSignature => write!(f, "the host signature is invalid"),
+            HostError::Stale { issued, now } => write!(
+                f,
+                "the challenge envelope was issued at {}, which is not close to {}",
+                issued, now
+            ),
+            HostError::InvalidHostName(host) => write!(f, "'{}' is not a valid host name", host),
+            HostError::InvalidKey => write!(f, "the host key is not a PEM public key"),
+        }
+    }
+}
+
+/// The public keys of the WSL distributions that are registered with the bridge, by their names.
+///
+/// The bridge signs only envelopes that a registered host has signed recently, so that no other
+/// program can have Windows Hello sign for the PAM module, and nothing while no host is registered.
+#[derive(Debug, Clone, Default)]
+pub struct HostKeys {
+    keys: BTreeMap<String, Vec<u8>>,
+}
+
+impl HostKeys {
+    pub fn new() -> HostKeys {
+        HostKeys::default()
+    }
+
+    /// Registers the `-----BEGIN PUBLIC KEY-----` PEM `pem` as the key of `host`
+    pub fn insert_pem(&mut self, host: &str, pem: &str) -> Result<(), HostError> {
+        if !is_valid_host_name(host) {
+            return Err(HostError::InvalidHostName(host.to_owned()));
+        }
+        let key = parse_public_key_pem(pem).ok_or(HostError::InvalidKey)?;
+        self.keys.insert(host.to_owned(), key);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Formats the hosts as the list that the bridge keeps: a header line, then a line with the
+    /// name and the base64 DER key of each host. The same hosts always make the same list.
+    pub fn to_list(&self) -> String {
+        let mut list = format!("{}\n", HOST_LIST_HEADER);
+        for (host, key) in &self.keys {
+            list.push_str(&format!("{} {}\n", host, encode_base64(key)));
+        }
+        list
+    }
+
+    /// Parses `list`, made by `to_list`, once `signature` shows that `list_key` signed it. The bridge
+    /// signs the list with a Windows Hello key of the user whenever a host is registered, so that
+    /// programs that can write the list cannot add hosts to it.
+    pub fn from_signed_list(
+        list: &[u8],
+        signature: &[u8],
+        list_key: &[u8],
+        verifier: &impl SignatureVerifier,
+    ) -> Result<HostKeys, HostError> {
+        if !verifier.verify(list_key, list, signature) {
+            return Err(HostError::UntrustedList);
+        }
+        let list = std::str::from_utf8(list).map_err(|_| HostError::InvalidList)?;
+        let mut lines = list
+            .strip_suffix('\n')
+            .ok_or(HostError::InvalidList)?
+            .split('\n');
+        if lines.next() != Some(HOST_LIST_HEADER) {
+            return Err(HostError::InvalidList);
+        }
+        let mut hosts = HostKeys::new();
+        for line in lines {
+            let (host, key) = line.split_once(' ').ok_or(HostError::InvalidList)?;
+            let key = decode_base64(key)
+                .filter(|key| !key.is_empty())
+                .ok_or(HostError::InvalidList)?;
+            if !is_valid_host_name(host) || hosts.keys.insert(host.to_owned(), key).is_some() {
+                return Err(HostError::InvalidList);
+            }
+        }
+        Ok(hosts)
+    }
+
+    /// Checks that `envelope`, parsed from `data`, is recent and that the key of the distro it
+    /// names made `signature`, the base64 host signature of the request
+    pub fn verify(
+        &self,
+        envelope: &Envelope,
+        data: &[u8],
+        signature: Option<&str>,
+        now: u64,
+        verifier: &impl SignatureVerifier,
+    ) -> Result<(), HostError> {
+        if self.is_empty() {
+            return Err(HostError::NotRegistered);
+        }
+        let signature = signature.ok_or(HostError::Unsigned)?;
+        let host = envelope.distro.as_deref().ok_or(HostError::NoHost)?;
+        let key = self
+            .keys
+            .get(host)
+            .ok_or_else(|| HostError::UnknownHost(host.to_owned()))?;
+        let signature = decode_base64(signature).ok_or(HostError::BadSignature)?;
+        if !verifier.verify(key, data, &signature) {
+            return Err(HostError::BadSignature);
+        }
+        // Checked after the signature, so that an unsigned time is never reported
+        let issued = envelope.issued;
+        // Saturating, so that a signed but absurd time cannot wrap around the check
+        if issued.saturating_add(MAX_ENVELOPE_AGE_SECONDS) < now
+            || now.saturating_add(MAX_CLOCK_SKEW_SECONDS) < issued
+        {
+            return Err(HostError::Stale { issued, now });
+        }
+        Ok(())
+    }
+}
+
+/// Whether `host` can name a registered host, which WSL distribution names always can. It is used
+/// as a file name by the bridge.
+pub fn is_valid_host_name(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= MAX_HOST_NAME_LEN
+        && !host.starts_with('.')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Returns the DER of a `-----BEGIN PUBLIC KEY-----` PEM
+fn parse_public_key_pem(pem: &str) -> Option<Vec<u8>> {
+    let body = pem
+        .trim()
+        .strip_prefix("-----BEGIN PUBLIC KEY-----")?
+        .strip_suffix("-----END PUBLIC KEY-----")?;
+    let body: String = body.split_whitespace().collect();
+    decode_base64(&body).filter(|der| !der.is_empty())
+}
+
+fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let mut bytes = [0; 3];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let padding = text.iter().rev().take_while(|&&c| c == b'=').count();
+    if padding > 2 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(text.len() / 4 * 3);
+    let mut bits = 0u32;
+    for (i, &c) in text[..text.len() - padding].iter().enumerate() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(value);
+        if i % 4 == 3 {
+            decoded.extend_from_slice(&bits.to_be_bytes()[1..]);
+            bits = 0;
+        }
+    }
+    match padding {
+        1 => decoded.extend_from_slice(&(bits << 6).to_be_bytes()[1..3]),
+        2 => decoded.push((bits << 12).to_be_bytes()[1]),
+        _ => {}
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::base64;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::sign::{Signer, Verifier};
+
+    const NOW: u64 = 1_700_000_000;
+
+    struct OpenSslVerifier;
+
+    impl SignatureVerifier for OpenSslVerifier {
+        fn verify(&self, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+            let key = PKey::public_key_from_der(public_key).unwrap();
+            let mut verifier = Verifier::new(MessageDigest::sha256(), &key).unwrap();
+            verifier.update(data).unwrap();
+            verifier.verify(signature).unwrap_or(false)
+        }
+    }
+
+    fn host_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    fn sign(key: &PKey<Private>, data: &[u8]) -> String {
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(data).unwrap();
+        base64::encode_block(&signer.sign_to_vec().unwrap())
+    }
+
+    fn envelope(distro: Option<&str>, issued: u64) -> (Envelope, Vec<u8>) {
+        let envelope = Envelope {
+            nonce: "5f0c6a2e-7a8b-4c1d-9e2f-0a1b2c3d4e5f".to_owned(),
+            issued,
+            user: "alice".to_owned(),
+            distro: distro.map(str::to_owned),
+            ..Envelope::default()
+        };
+        let data = envelope.to_string().into_bytes();
+        (envelope, data)
+    }
+
+    #[test]
+    fn verifies_requests_of_registered_hosts() {
+        let key = host_key();
+        let other_key = host_key();
+        let mut hosts = HostKeys::new();
+        assert!(hosts.is_empty());
+        let (request, data) = envelope(Some("Ubuntu-22.04"), NOW);
+        assert_eq!(
+            hosts.verify(
+                &request,
+                &data,
+                Some(&sign(&key, &data)),
+                NOW,
+                &OpenSslVerifier
+            ),
+            Err(HostError::NotRegistered)
+        );
+        let pem = String::from_utf8(key.public_key_to_pem().unwrap()).unwrap();
+        hosts.insert_pem("Ubuntu-22.04", &pem).unwrap();
+        assert!(!hosts.is_empty());
+
+        let verify = |envelope: &(Envelope, Vec<u8>), signature: Option<&str>| {
+            hosts.verify(&envelope.0, &envelope.1, signature, NOW, &OpenSslVerifier)
+        };
+        let request = envelope(Some("Ubuntu-22.04"), NOW - 5);
+        assert_eq!(verify(&request, Some(&sign(&key, &request.1))), Ok(()));
+        assert_eq!(verify(&request, None), Err(HostError::Unsigned));
+        assert_eq!(
+            verify(&request, Some(&sign(&other_key, &request.1))),
+            Err(HostError::BadSignature)
+        );
+        assert_eq!(verify(&request, Some("!!!!")), Err(HostError::BadSignature));
+
+        let forged = envelope(Some("Ubuntu-22.04"), NOW);
+        assert_eq!(
+            verify(&forged, Some(&sign(&key, &request.1))),
+            Err(HostError::BadSignature)
+        );
+        let unknown = envelope(Some("Debian"), NOW);
+        assert_eq!(
+            verify(&unknown, Some(&sign(&key, &unknown.1))),
+            Err(HostError::UnknownHost("Debian".to_owned()))
+        );
+        let anonymous = envelope(None, NOW);
+        assert_eq!(
+            verify(&anonymous, Some(&sign(&key, &anonymous.1))),
+            Err(HostError::NoHost)
+        );
+        for &issued in &[NOW - MAX_ENVELOPE_AGE_SECONDS - 1, NOW + 3600, u64::MAX] {
+            let stale = envelope(Some("Ubuntu-22.04"), issued);
+            assert_eq!(
+                verify(&stale, Some(&sign(&key, &stale.1))),
+                Err(HostError::Stale { issued, now: NOW })
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_registrations() {
+        let pem = String::from_utf8(host_key().public_key_to_pem().unwrap()).unwrap();
+        let mut hosts = HostKeys::new();
+        let long = "x".repeat(65);
+        for &host in &["", "..", "../hosts", "Ubuntu 22.04", "a\\b", &long] {
+            assert_eq!(
+                hosts.insert_pem(host, &pem),
+                Err(HostError::InvalidHostName(host.to_owned()))
+            );
+        }
+        for pem in &[
+            "",
+            "-----BEGIN PUBLIC KEY-----\n-----END PUBLIC KEY-----",
+            "AAAA",
+        ] {
+            assert_eq!(hosts.insert_pem("Ubuntu", pem), Err(HostError::InvalidKey));
+        }
+        assert!(hosts.is_empty());
+    }
+
+    #[test]
+    fn loads_only_lists_signed_by_the_list_key() {
+        let list_key = host_key();
+        let list_public_key = list_key.public_key_to_der().unwrap();
+        let mut hosts = HostKeys::new();
+        for &host in &["Ubuntu-22.04", "Debian"] {
+            let pem = String::from_utf8(host_key().public_key_to_pem().unwrap()).unwrap();
+            hosts.insert_pem(host, &pem).unwrap();
+        }
+        let list = hosts.to_list();
+        assert!(list.starts_with("wsl-hello-hosts/1\nDebian "));
+        let signature = |data: &[u8]| base64::decode_block(&sign(&list_key, data)).unwrap();
+
+        let load = |list: &[u8], signature: &[u8]| {
+            HostKeys::from_signed_list(list, signature, &list_public_key, &OpenSslVerifier)
+        };
+        let loaded = load(list.as_bytes(), &signature(list.as_bytes())).unwrap();
+        assert_eq!(loaded.to_list(), list);
+
+        let other_key = host_key();
+        let extended = format!(
+            "{}Evil {}\n",
+            list,
+            base64::encode_block(&other_key.public_key_to_der().unwrap())
+        );
+        assert_eq!(
+            load(extended.as_bytes(), &signature(list.as_bytes())).unwrap_err(),
+            HostError::UntrustedList
+        );
+        assert_eq!(
+            load(list.as_bytes(), b"").unwrap_err(),
+            HostError::UntrustedList
+        );
+        for invalid in &[
+            "",
+            "wsl-hello-hosts/1",
+            "wsl-hello-hosts/2\n",
+            "wsl-hello-hosts/1\nUbuntu\n",
+            "wsl-hello-hosts/1\n../hosts AAAA\n",
+            "wsl-hello-hosts/1\nUbuntu AAAA\nUbuntu AAAA\n",
+        ] {
+            assert_eq!(
+                load(invalid.as_bytes(), &signature(invalid.as_bytes())).unwrap_err(),
+                HostError::InvalidList,
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_and_decodes_base64() {
+        for data in &[&b""[..], b"a", b"ab", b"abc", b"\xff\x00\x80wsl hello"] {
+            assert_eq!(encode_base64(data), base64::encode_block(data));
+            assert_eq!(
+                decode_base64(&base64::encode_block(data)).as_deref(),
+                Some(*data)
+            );
+        }
+        for invalid in &["a", "ab=c", "a===", "ab\ncd"] {
+            assert_eq!(decode_base64(invalid), None, "{}", invalid);
+        }
+    }
+}
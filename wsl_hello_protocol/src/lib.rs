@@ -0,0 +1,9 @@
+//! The protocol between the PAM module and `WindowsHelloBridge.exe` that both sides implement
+
+pub mod envelope;
+pub mod host;
+pub mod prompt_message;
+
+pub use envelope::{Envelope, EnvelopeError};
+pub use host::{HostError, HostKeys, SignatureVerifier};
+pub use prompt_message::PromptContext;
@@ -0,0 +1,75 @@
+/// The longest message that is passed to the bridge, in characters
+const MAX_MESSAGE_CHARS: usize = 200;
+
+/// What is asking for Windows Hello, as the Windows user is shown before the dialog
+#[derive(Debug, Default)]
+pub struct PromptContext<'a> {
+    pub service: Option<&'a str>,
+    pub distro: Option<&'a str>,
+    pub user: &'a str,
+    pub tty: Option<&'a str>,
+    pub rhost: Option<&'a str>,
+}
+
+impl<'a> PromptContext<'a> {
+    /// Composes a message like "sudo in Ubuntu-22.04 as alice on pts/3". The values come from the
+    /// application, so control characters are replaced and the message is cut to a sane length,
+    /// which also keeps it on one line of the bridge protocol.
+    pub fn to_message(&self) -> String {
+        let present = |value: Option<&'a str>| value.filter(|value| !value.is_empty());
+        let mut message = present(self.service).unwrap_or("Authentication").to_owned();
+        if let Some(distro) = present(self.distro) {
+            message.push_str(&format!(" in {}", distro));
+        }
+        message.push_str(&format!(" as {}", self.user));
+        if let Some(tty) = present(self.tty) {
+            message.push_str(&format!(" on {}", tty.trim_start_matches("/dev/")));
+        }
+        if let Some(rhost) = present(self.rhost) {
+            message.push_str(&format!(" from {}", rhost));
+        }
+        message
+            .chars()
+            .map(|c| if c.is_control() { '?' } else { c })
+            .take(MAX_MESSAGE_CHARS)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_requesting_application() {
+        let context = PromptContext {
+            service: Some("sudo"),
+            distro: Some("Ubuntu-22.04"),
+            user: "alice",
+            tty: Some("/dev/pts/3"),
+            rhost: None,
+        };
+        assert_eq!(
+            context.to_message(),
+            "sudo in Ubuntu-22.04 as alice on pts/3"
+        );
+
+        let context = PromptContext {
+            service: Some(""),
+            user: "alice",
+            tty: Some("pts/1\nmessage: forged"),
+            rhost: Some("example.com"),
+            ..PromptContext::default()
+        };
+        assert_eq!(
+            context.to_message(),
+            "Authentication as alice on pts/1?message: forged from example.com"
+        );
+        let long = "x".repeat(500);
+        let context = PromptContext {
+            service: Some(&long),
+            ..PromptContext::default()
+        };
+        assert_eq!(context.to_message().chars().count(), MAX_MESSAGE_CHARS);
+    }
+}